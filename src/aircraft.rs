@@ -0,0 +1,240 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io;
+use std::io::Read;
+use std::path::Path;
+
+// Which datarefs move each control surface. These differ between aircraft, so
+// they can be overridden with a small TOML file like:
+//
+//     # Comments are allowed
+//     rudder = "sim/flightmodel/controls/vstab1_rud1def"
+//     left_aileron = "sim/flightmodel/controls/wing1l_ail1def"
+//     right_aileron = "sim/flightmodel/controls/wing1r_ail1def"
+//     elevator1 = "sim/flightmodel/controls/hstab1_elv1def"
+//     elevator2 = "sim/flightmodel/controls/hstab2_elv1def"
+//
+//     # Other ailerons on each wing, moved along with the ones above, each
+//     # optionally scaled
+//     left_aileron_extra = "sim/flightmodel/controls/wing1l_ail2def, sim/flightmodel/controls/wing2l_ail1def * 0.5"
+//     right_aileron_extra = "sim/flightmodel/controls/wing1r_ail2def, sim/flightmodel/controls/wing2r_ail1def * 0.5"
+//
+// Every key but the `_extra` ones must be present. Only this flat
+// `key = "string"` subset of TOML is understood.
+#[derive(Clone, Debug)]
+pub struct ControlDatarefs {
+    pub rudder: String,
+    pub left_aileron: String,
+    pub right_aileron: String,
+    pub elevator1: String, // left half of the elevator
+    pub elevator2: String, // right half
+    // Any more ailerons on each wing, e.g. inboard ones
+    pub left_aileron_extra: Vec<LinkedSurface>,
+    pub right_aileron_extra: Vec<LinkedSurface>,
+}
+
+// A control surface that's moved along with one of the main ones, by `scale`
+// times as much
+#[derive(Clone, Debug)]
+pub struct LinkedSurface {
+    pub dataref: String,
+    pub scale: f32,
+}
+
+const KEYS: [&str; 5] = ["rudder", "left_aileron", "right_aileron", "elevator1", "elevator2"];
+// These may be left out
+const EXTRA_KEYS: [&str; 2] = ["left_aileron_extra", "right_aileron_extra"];
+
+impl ControlDatarefs {
+    // These are based on the Cessna Skyhawk, and used by the built-in profiles
+    // (see `Profile`). For other planes you may need to change which datarefs
+    // are used to move the control surfaces!
+    //
+    // Also while we're on the subject. A name like hstab1_elv1def means:
+    //  * The control surfaces is attached to the horizontal (h) stabilizer (stab)
+    //  * The control surface moves when the elevator (elv) command is sent from the yoke.
+    pub fn cessna() -> Self {
+        ControlDatarefs {
+            rudder: "sim/flightmodel/controls/vstab1_rud1def".to_string(),
+            left_aileron: "sim/flightmodel/controls/wing1l_ail1def".to_string(),
+            right_aileron: "sim/flightmodel/controls/wing1r_ail1def".to_string(),
+            elevator1: "sim/flightmodel/controls/hstab1_elv1def".to_string(),
+            elevator2: "sim/flightmodel/controls/hstab2_elv1def".to_string(),
+            left_aileron_extra: Vec::new(),
+            right_aileron_extra: Vec::new(),
+        }
+    }
+
+    // Reads the mapping from `path`, or gives `defaults` (the profile's) if
+    // there's no such file. Any other problem with the file is an error, since
+    // flying with the wrong control surfaces is worse than not flying at all.
+    pub fn load(path: &Path, defaults: ControlDatarefs) -> Result<Self, String> {
+        let mut contents = String::new();
+        match File::open(path) {
+            Ok(mut file) => {
+                file.read_to_string(&mut contents)
+                    .map_err(|e| format!("couldn't read {}: {}", path.display(), e))?;
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(defaults),
+            Err(e) => return Err(format!("couldn't open {}: {}", path.display(), e)),
+        }
+
+        Self::parse(&contents).map_err(|e| format!("{}: {}", path.display(), e))
+    }
+
+    pub fn parse(contents: &str) -> Result<Self, String> {
+        let mut values: HashMap<&str, String> = HashMap::new();
+        let mut extras: HashMap<&str, Vec<LinkedSurface>> = HashMap::new();
+
+        for (num, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let (key, value) = parse_line(line)
+                .map_err(|e| format!("line {}: {}", num + 1, e))?;
+
+            if let Some(known) = EXTRA_KEYS.iter().find(|known| **known == key) {
+                let surfaces = parse_linked_surfaces(&value)
+                    .map_err(|e| format!("line {}: {}", num + 1, e))?;
+                if extras.insert(*known, surfaces).is_some() {
+                    return Err(format!("line {}: {} given twice", num + 1, known));
+                }
+                continue;
+            }
+
+            let key = match KEYS.iter().find(|known| **known == key) {
+                Some(known) => *known,
+                None => return Err(format!("line {}: unknown key {:?}", num + 1, key)),
+            };
+            if !is_valid_dataref(&value) {
+                return Err(format!("line {}: malformed dataref path {:?}", num + 1, value));
+            }
+            if values.insert(key, value).is_some() {
+                return Err(format!("line {}: {} given twice", num + 1, key));
+            }
+        }
+
+        let mut take = |key: &str| values.remove(key).ok_or(format!("missing key {:?}", key));
+
+        Ok(ControlDatarefs {
+            rudder: take("rudder")?,
+            left_aileron: take("left_aileron")?,
+            right_aileron: take("right_aileron")?,
+            elevator1: take("elevator1")?,
+            elevator2: take("elevator2")?,
+            left_aileron_extra: extras.remove("left_aileron_extra").unwrap_or_default(),
+            right_aileron_extra: extras.remove("right_aileron_extra").unwrap_or_default(),
+        })
+    }
+}
+
+// Everything we assume about the aircraft being flown, picked by name with
+// FFSIM_PROFILE. The datarefs file and the individual environment variables
+// (FFSIM_MAX_DEFLECTION etc.) still override whatever the profile says.
+#[derive(Clone, Debug)]
+pub struct Profile {
+    pub name: &'static str,
+    pub controls: ControlDatarefs,
+    pub max_deflection_deg: f32,
+    pub max_steering_deg: f32,
+    // Has to match `NUM_ENGINES`, which is fixed at build time since it
+    // changes the layout of `ControlData`
+    pub engines: usize,
+    // Roll, pitch and yaw of the IMU board relative to the aircraft, degrees
+    pub imu_mounting_deg: [f32; 3],
+}
+
+pub const DEFAULT_PROFILE: &str = "cessna172";
+
+// Names accepted by `Profile::builtin`, for error messages
+pub const BUILTIN_PROFILES: [&str; 2] = ["cessna172", "baron58"];
+
+impl Profile {
+    pub fn builtin(name: &str) -> Option<Self> {
+        match name {
+            "cessna172" => Some(Self::cessna172()),
+            "baron58" => Some(Self::baron58()),
+            _ => None,
+        }
+    }
+
+    // X-Plane's default Cessna Skyhawk, which the plugin was developed against
+    pub fn cessna172() -> Self {
+        Profile {
+            name: "cessna172",
+            controls: ControlDatarefs::cessna(),
+            max_deflection_deg: 15.0,
+            max_steering_deg: 30.0,
+            engines: 1,
+            imu_mounting_deg: [0.0; 3],
+        }
+    }
+
+    // X-Plane's default Beechcraft Baron. Plane Maker names the surfaces the
+    // same way as on the Skyhawk; it's the engine count that differs, so this
+    // needs a build with NUM_ENGINES = 2.
+    pub fn baron58() -> Self {
+        Profile {
+            name: "baron58",
+            controls: ControlDatarefs::cessna(),
+            max_deflection_deg: 15.0,
+            max_steering_deg: 30.0,
+            engines: 2,
+            imu_mounting_deg: [0.0; 3],
+        }
+    }
+}
+
+// Splits `key = "value"  # optional comment`
+fn parse_line(line: &str) -> Result<(&str, String), String> {
+    let eq = line.find('=').ok_or("expected `key = \"value\"`".to_string())?;
+    let key = line[.. eq].trim();
+    let rest = line[eq + 1 ..].trim();
+
+    if !rest.starts_with('"') {
+        return Err(format!("value for {} should be a quoted string", key));
+    }
+    let close = rest[1 ..].find('"').ok_or(format!("unterminated string for {}", key))? + 1;
+    let trailing = rest[close + 1 ..].trim();
+    if !trailing.is_empty() && !trailing.starts_with('#') {
+        return Err(format!("unexpected {:?} after value for {}", trailing, key));
+    }
+
+    Ok((key, rest[1 .. close].to_string()))
+}
+
+// Splits a comma-separated list of `dataref` or `dataref * scale`
+fn parse_linked_surfaces(value: &str) -> Result<Vec<LinkedSurface>, String> {
+    let mut surfaces = Vec::new();
+
+    for item in value.split(',').map(str::trim).filter(|item| !item.is_empty()) {
+        let (dataref, scale) = match item.find('*') {
+            Some(star) => {
+                let scale = item[star + 1 ..].trim();
+                let scale = scale.parse::<f32>().ok().filter(|scale| scale.is_finite())
+                    .ok_or(format!("bad scale {:?} for {}", scale, item[.. star].trim()))?;
+                (item[.. star].trim(), scale)
+            }
+            None => (item, 1.0),
+        };
+        if !is_valid_dataref(dataref) {
+            return Err(format!("malformed dataref path {:?}", dataref));
+        }
+        surfaces.push(LinkedSurface {
+            dataref: dataref.to_string(),
+            scale,
+        });
+    }
+
+    Ok(surfaces)
+}
+
+// Datarefs look like "sim/flightmodel/controls/wing1l_ail1def": slash-separated,
+// non-empty components made up of letters, digits and a little punctuation.
+fn is_valid_dataref(path: &str) -> bool {
+    !path.is_empty() && path.split('/').all(|part| {
+        !part.is_empty() && part.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-' || c == '.')
+    })
+}
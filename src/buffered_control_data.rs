@@ -1,20 +1,39 @@
 use super::ControlData;
+use super::NUM_ENGINES;
+use super::config::Config;
 
-use std::time::{SystemTime, Duration, UNIX_EPOCH};
-use std::mem::transmute;
+use std::time::Instant;
+
+use super::wire::decode_timestamp;
+
+// Largest value of the 11-bit fields in ControlData
+const RAW_MAX: u16 = (1 << 11) - 1;
 
 #[derive(Copy, Clone, Debug)]
 pub struct BufferedControlData {
     pub rudder: f32,
     pub left_aileron: f32,
     pub right_aileron: f32,
-    pub elevator: f32,
+    pub left_elevator: f32,
+    pub right_elevator: f32,
+
+    // [0, 1], retracted to fully extended
+    pub flaps: f32,
+    pub spoilers: f32,
 
-    pub throttle: f32,
+    pub brake: f32,     // [0, 1]
+    pub nosewheel: f32, // degrees, positive to the right
 
-    // Timestamp of creation of the flight data from which the controller generated
-    // these control inputs.
-    pub time: SystemTime,
+    // [0, 1] from idle to full, or down to -1 (full reverse) if configured
+    pub throttle: [f32; NUM_ENGINES],
+
+    // Timestamp (see clock.rs) of creation of the flight data from which the
+    // controller generated these control inputs.
+    pub time: u64,
+
+    // When we received this from the controller (None if we never did); used
+    // by the watchdog in the flight loop.
+    pub received: Option<Instant>,
 }
 
 impl BufferedControlData {
@@ -23,36 +42,234 @@ impl BufferedControlData {
             rudder: 0.0,
             left_aileron: 0.0,
             right_aileron: 0.0,
-            elevator: 0.0,
-            throttle: 0.0,
-            // Since this data is fictitious, we use the epoch to signal that we should ignore
-            // this instance when measuring latency.
-            time: UNIX_EPOCH,
+            left_elevator: 0.0,
+            right_elevator: 0.0,
+            flaps: 0.0,
+            spoilers: 0.0,
+            brake: 0.0,
+            nosewheel: 0.0,
+            throttle: [0.0; NUM_ENGINES],
+            // Since this data is fictitious, we use the "none" timestamp to signal
+            // that we should ignore this instance when measuring latency.
+            time: 0,
+            received: None,
         }
     }
 
-    pub fn from_external(cd: ControlData) -> Self {
-        assert!(cd.verify());
+    pub fn from_external(cd: ControlData, config: &Config) -> Self {
+        assert!(cd.verify(config.wire_endian));
 
-        let max_deflection_deg: f32 = 15f32; // relative to zero in either direction
-        // see comments in ControlData struct
         let control_surface_conversion = |input: u16| -> f32 {
-            (input as f32) * ((2f32 * max_deflection_deg) / ((1 << 11) - 1) as f32)
-                - max_deflection_deg
+            deflection_conversion(input, config.max_deflection_deg)
         };
 
-        let creation_time = UNIX_EPOCH
-            + unsafe { transmute::<[u8; 16], Duration>(cd.time) };
+        let creation_time = decode_timestamp(&cd.time);
+
+        // flaps and spoilers are just [0, 1] so we divide by the full range
+        let ratio_conversion = |input: u16| -> f32 {
+            (input.min(RAW_MAX) as f32) / (RAW_MAX as f32)
+        };
+        // With a reverse region the bottom of the range runs from full reverse
+        // (-1) at 0 up to idle at the threshold, and the rest from idle to full
+        // forward (1)
+        let reverse = config.throttle_reverse;
+        let throttle_conversion = |input: u16| -> f32 {
+            let ratio = ratio_conversion(input);
+            if reverse <= 0f32 {
+                ratio
+            } else if ratio < reverse {
+                (ratio - reverse) / reverse
+            } else {
+                (ratio - reverse) / (1f32 - reverse)
+            }
+        };
+        let mut throttle = [0f32; NUM_ENGINES];
+        for i in 0 .. NUM_ENGINES {
+            throttle[i] = throttle_conversion(cd.throttle[i]);
+        }
 
         BufferedControlData {
             rudder: control_surface_conversion(cd.rudder),
             left_aileron: control_surface_conversion(cd.left_aileron),
             right_aileron: control_surface_conversion(cd.right_aileron),
-            elevator: control_surface_conversion(cd.elevator),
+            left_elevator: control_surface_conversion(cd.left_elevator),
+            right_elevator: control_surface_conversion(cd.right_elevator),
+            flaps: ratio_conversion(cd.flaps),
+            spoilers: ratio_conversion(cd.spoilers),
+            brake: ratio_conversion(cd.brake),
+            nosewheel: deflection_conversion(cd.nosewheel, config.max_steering_deg),
 
-            // throttle output is just [0, 1] so we divide it by the full range
-            throttle: (cd.throttle as f32) / (((1 << 11) - 1) as f32),
+            throttle,
             time: creation_time,
+            received: Some(Instant::now()),
+        }
+    }
+}
+
+// see comments in ControlData struct. `max` is relative to zero in either
+// direction: 0 is -max, 2047 is +max and neutral is halfway between 1023 and
+// 1024 (see the tests below for the default of 15 degrees). Anything with one
+// of the 5 unused bits set is treated as 2047.
+fn deflection_conversion(input: u16, max: f32) -> f32 {
+    let deflection = (input.min(RAW_MAX) as f32) * ((2f32 * max) / RAW_MAX as f32) - max;
+    debug_assert!(deflection.abs() <= max * 1.0001, "{} is outside ±{}", deflection, max);
+    deflection
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::control_data::CONTROL_DATA_SIZE;
+    use super::super::crc::crc32;
+    use super::super::wire::{WireEndian, WireWriter, encode_timestamp};
+
+    // What the flight loop gets from `cd` after `edit`, as the controller
+    // would have sent it (i.e. with the checksum made right again)
+    fn received<F: FnOnce(&mut ControlData)>(config: &Config, edit: F) -> BufferedControlData {
+        let endian = config.wire_endian;
+        let mut cd = ControlData::neutral(0, encode_timestamp(1), endian);
+        edit(&mut cd);
+
+        let mut bytes = cd.to_bytes(endian);
+        let crc = crc32(&bytes[4 .. CONTROL_DATA_SIZE - 4]);
+        WireWriter::new(&mut bytes[CONTROL_DATA_SIZE - 4 ..], endian).u32(crc);
+        BufferedControlData::from_external(ControlData::from_bytes(&bytes, endian), config)
+    }
+
+    fn assert_deg(raw: u16, max: f32, expected: f32) {
+        let got = deflection_conversion(raw, max);
+        assert!((got - expected).abs() < 0.001, "raw {}: expected {}, got {}", raw, expected, got);
+    }
+
+    #[test]
+    fn deflection_at_the_default_limit() {
+        //     raw      0     512    1023    1024    1535    2047
+        //     deg  -15.0  -7.496  -0.007  +0.007  +7.496   +15.0
+        let table = [(0, -15.0), (512, -7.496), (1023, -0.007), (1024, 0.007),
+                     (1535, 7.496), (2047, 15.0)];
+        for &(raw, deg) in table.iter() {
+            assert_deg(raw, 15.0, deg);
         }
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn neutral_is_between_1023_and_1024() {
+        let below = deflection_conversion(1023, 15.0);
+        let above = deflection_conversion(1024, 15.0);
+        assert!(below < 0.0 && above > 0.0);
+        assert!((below + above).abs() < 1e-4);
+    }
+
+    #[test]
+    fn deflection_scales_with_the_limit() {
+        assert_deg(0, 25.0, -25.0);
+        assert_deg(2047, 25.0, 25.0);
+        assert_deg(1535, 25.0, 7.496 * 25.0 / 15.0);
+        assert_deg(2047, 5.0, 5.0);
+    }
+
+    #[test]
+    fn unused_bits_count_as_full_deflection() {
+        assert_deg(0xffff, 15.0, 15.0);
+        assert_deg(2048, 15.0, 15.0);
+    }
+
+    #[test]
+    fn surfaces_use_the_configured_limit() {
+        let mut config = Config::for_test();
+        config.max_deflection_deg = 20.0;
+        let data = received(&config, |cd| {
+            cd.rudder = 0;
+            cd.left_aileron = 2047;
+            cd.right_aileron = 0;
+            cd.left_elevator = 2047;
+            cd.right_elevator = 1024;
+        });
+
+        assert_eq!(data.rudder, -20.0);
+        assert_eq!(data.left_aileron, 20.0);
+        assert_eq!(data.right_aileron, -20.0);
+        assert_eq!(data.left_elevator, 20.0);
+        assert!(data.right_elevator > 0.0 && data.right_elevator < 0.01);
+    }
+
+    #[test]
+    fn timestamp_comes_back_as_sent() {
+        for &endian in &[WireEndian::Little, WireEndian::Big] {
+            let mut config = Config::for_test();
+            config.wire_endian = endian;
+            let data = received(&config, |cd| cd.time = encode_timestamp(0x0123_4567_89ab_cdef));
+            assert_eq!(data.time, 0x0123_4567_89ab_cdef);
+        }
+    }
+
+    #[test]
+    fn flaps_and_spoilers_are_fractions_of_the_range() {
+        let config = Config::for_test();
+        let data = received(&config, |cd| {
+            cd.flaps = 2047;
+            cd.spoilers = 0;
+        });
+        assert_eq!(data.flaps, 1.0);
+        assert_eq!(data.spoilers, 0.0);
+
+        let data = received(&config, |cd| {
+            cd.flaps = 1024;
+            cd.spoilers = 0xffff;
+        });
+        assert!((data.flaps - 0.5).abs() < 0.001);
+        assert_eq!(data.spoilers, 1.0);
+    }
+
+    #[test]
+    fn brake_is_a_fraction_and_steering_uses_its_own_limit() {
+        let mut config = Config::for_test();
+        config.max_deflection_deg = 15.0;
+        config.max_steering_deg = 60.0;
+
+        let data = received(&config, |cd| {
+            cd.brake = 2047;
+            cd.nosewheel = 0;
+        });
+        assert_eq!(data.brake, 1.0);
+        assert_eq!(data.nosewheel, -60.0);
+
+        let data = received(&config, |cd| {
+            cd.brake = 0;
+            cd.nosewheel = 2047;
+        });
+        assert_eq!(data.brake, 0.0);
+        assert_eq!(data.nosewheel, 60.0);
+
+        // Neutral controls mean brakes off and (near enough) straight ahead
+        let data = received(&config, |_| ());
+        assert_eq!(data.brake, 0.0);
+        assert!(data.nosewheel.abs() < 0.05);
+    }
+
+    #[test]
+    fn throttle_without_a_reverse_region() {
+        let config = Config::for_test();
+        let data = received(&config, |cd| cd.throttle = [0; NUM_ENGINES]);
+        assert_eq!(data.throttle, [0.0; NUM_ENGINES]);
+        let data = received(&config, |cd| cd.throttle = [2047; NUM_ENGINES]);
+        assert_eq!(data.throttle, [1.0; NUM_ENGINES]);
+    }
+
+    #[test]
+    fn throttle_with_a_reverse_region() {
+        let mut config = Config::for_test();
+        config.throttle_reverse = 0.25;
+        let throttle = |raw: u16| received(&config, |cd| cd.throttle = [raw; NUM_ENGINES]).throttle[0];
+
+        assert_eq!(throttle(0), -1.0);
+        assert_eq!(throttle(2047), 1.0);
+        // Idle at the threshold, from either side
+        let idle = (0.25 * 2047.0) as u16;
+        assert!(throttle(idle).abs() < 0.01, "{}", throttle(idle));
+        assert!(throttle(idle) <= 0.0 && throttle(idle + 1) >= 0.0);
+        // Halfway into each region
+        assert!((throttle(idle / 2) + 0.5).abs() < 0.01);
+        assert!((throttle(idle + (2047 - idle) / 2) - 0.5).abs() < 0.01);
+    }
+}
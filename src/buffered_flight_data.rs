@@ -1,3 +1,4 @@
+use std::cell::Cell;
 use std::time::SystemTime;
 
 // See FFSim struct for comments about these values
@@ -18,17 +19,35 @@ pub struct BufferedFlightData {
 
     pub latitude: f64,
     pub longitude: f64,
+    pub elevation: f64,
+    pub vertical_speed: f32,
+    pub groundspeed: f32,
+    pub track: f32,
+    pub wind_speed: f32,
+    pub wind_direction: f32,
 
     pub indicated_airspeed: f32,
+    pub true_airspeed: f32,
+    pub alpha: f32,
+    pub beta: f32,
     pub barometer_inhg: f32,
 
     pub ambient_temp: f32,
-    pub air_density: f32,
+    pub air_density: f32,       // at sea level
+    pub local_air_density: f32, // where the aircraft is
 
-    // Time of creation; used to measure round trip latency.
-    // SystemTime can change under us; hopefully running the latency
-    // experiment multiple times should amortize the effects of this.
-    pub time: SystemTime,
+    // Where the sim actually has the control surfaces, in degrees, so the
+    // controller can check its commands took. None if the aircraft hasn't got one.
+    pub actual_rudder: Option<f32>,
+    pub actual_left_aileron: Option<f32>,
+    pub actual_right_aileron: Option<f32>,
+    pub actual_left_elevator: Option<f32>,
+    pub actual_right_elevator: Option<f32>,
+
+    // Time of creation from `clock::now`; used to measure round trip latency.
+    pub time: u64,
+    // ... and by the wall clock, for the GPS
+    pub utc: SystemTime,
 }
 
 impl BufferedFlightData {
@@ -46,11 +65,228 @@ impl BufferedFlightData {
             plane_orientation_quaternion: [0.0; 4],
             latitude: 0.0,
             longitude: 0.0,
+            elevation: 0.0,
+            vertical_speed: 0.0,
+            groundspeed: 0.0,
+            track: 0.0,
+            wind_speed: 0.0,
+            wind_direction: 0.0,
             indicated_airspeed: 0.0,
+            true_airspeed: 0.0,
+            alpha: 0.0,
+            beta: 0.0,
             ambient_temp: 0.0,
             barometer_inhg: 0.0,
             air_density: 0.0,
-            time: SystemTime::now(),
+            local_air_density: 0.0,
+            actual_rudder: None,
+            actual_left_aileron: None,
+            actual_right_aileron: None,
+            actual_left_elevator: None,
+            actual_right_elevator: None,
+            time: 0,
+            utc: SystemTime::now(),
+        }
+    }
+
+    // The sim can briefly report garbage (e.g. NaN during a reposition), which
+    // would turn into meaningless integers on the wire. This gives a copy of
+    // `self` with every NaN, infinite or physically implausible reading replaced
+    // by the same field from `previous`, i.e. the last frame that passed.
+    pub fn sanitized(&self, previous: &BufferedFlightData) -> Self {
+        self.sanitize(previous, &Replaced::default())
+    }
+
+    // As `sanitized`, for the first frame, when there's no previous one to fall
+    // back on. None if any reading is NaN or infinite, so the caller can wait
+    // for a frame that's all numbers rather than send zeros in their place.
+    // Implausible readings are still zeroed, but with a warning.
+    pub fn first_sanitized(&self) -> Option<Self> {
+        let replaced = Replaced::default();
+        let clean = self.sanitize(&BufferedFlightData::new(), &replaced);
+        if replaced.non_finite.get() > 0 {
+            return None;
+        }
+        if replaced.implausible.get() > 0 {
+            log_warn!("{} implausible readings in the first frame of flight data, sending 0 for them",
+                      replaced.implausible.get());
         }
+        Some(clean)
     }
-}
\ No newline at end of file
+
+    fn sanitize(&self, previous: &BufferedFlightData, replaced: &Replaced) -> Self {
+        let finite = |val: f32, prev: f32| {
+            if val.is_finite() { val } else { replaced.count(false); prev }
+        };
+        let in_range = |val: f32, prev: f32, min: f32, max: f32| {
+            if val >= min && val <= max { val } else { replaced.count(val.is_finite()); prev }
+        };
+        let finite64 = |val: f64, prev: f64, limit: f64| {
+            if val.abs() <= limit { val } else { replaced.count(val.is_finite()); prev }
+        };
+
+        BufferedFlightData {
+            roll_rate: finite(self.roll_rate, previous.roll_rate),
+            pitch_rate: finite(self.pitch_rate, previous.pitch_rate),
+            yaw_rate: finite(self.yaw_rate, previous.yaw_rate),
+            true_theta: finite(self.true_theta, previous.true_theta),
+            true_phi: finite(self.true_phi, previous.true_phi),
+            mag_psi: finite(self.mag_psi, previous.mag_psi),
+            local_ax: finite(self.local_ax, previous.local_ax),
+            local_ay: finite(self.local_ay, previous.local_ay),
+            local_az: finite(self.local_az, previous.local_az),
+            plane_orientation_quaternion:
+                if self.plane_orientation_quaternion.iter().all(|x| x.is_finite()) {
+                    self.plane_orientation_quaternion
+                } else {
+                    replaced.count(false);
+                    previous.plane_orientation_quaternion
+                },
+            latitude: finite64(self.latitude, previous.latitude, 90.0),
+            longitude: finite64(self.longitude, previous.longitude, 180.0),
+            elevation: finite64(self.elevation, previous.elevation, 1e6),
+            vertical_speed: in_range(self.vertical_speed, previous.vertical_speed, -500.0, 500.0),
+            groundspeed: in_range(self.groundspeed, previous.groundspeed, 0.0, 1000.0),
+            track: finite(self.track, previous.track),
+            wind_speed: in_range(self.wind_speed, previous.wind_speed, 0.0, 500.0),
+            wind_direction: finite(self.wind_direction, previous.wind_direction),
+            indicated_airspeed: in_range(self.indicated_airspeed, previous.indicated_airspeed, 0.0, 2000.0),
+            true_airspeed: in_range(self.true_airspeed, previous.true_airspeed, 0.0, 1000.0),
+            alpha: in_range(self.alpha, previous.alpha, -180.0, 180.0),
+            beta: in_range(self.beta, previous.beta, -180.0, 180.0),
+            // roughly 250-1100 hPa, the same as the real barometer's range
+            barometer_inhg: in_range(self.barometer_inhg, previous.barometer_inhg, 7.5, 32.5),
+            ambient_temp: in_range(self.ambient_temp, previous.ambient_temp, -100.0, 100.0),
+            air_density: in_range(self.air_density, previous.air_density, 0.0, 2.0),
+            local_air_density: in_range(self.local_air_density, previous.local_air_density, 0.0, 2.0),
+            actual_rudder: self.actual_rudder.map(|val| finite(val, previous.actual_rudder.unwrap_or(0.0))),
+            actual_left_aileron: self.actual_left_aileron
+                .map(|val| finite(val, previous.actual_left_aileron.unwrap_or(0.0))),
+            actual_right_aileron: self.actual_right_aileron
+                .map(|val| finite(val, previous.actual_right_aileron.unwrap_or(0.0))),
+            actual_left_elevator: self.actual_left_elevator
+                .map(|val| finite(val, previous.actual_left_elevator.unwrap_or(0.0))),
+            actual_right_elevator: self.actual_right_elevator
+                .map(|val| finite(val, previous.actual_right_elevator.unwrap_or(0.0))),
+            time: self.time,
+            utc: self.utc,
+        }
+    }
+}
+
+// How many readings `BufferedFlightData::sanitize` replaced, and why
+#[derive(Default)]
+struct Replaced {
+    non_finite: Cell<usize>,
+    implausible: Cell<usize>,
+}
+
+impl Replaced {
+    fn count(&self, finite: bool) {
+        let counter = if finite { &self.implausible } else { &self.non_finite };
+        counter.set(counter.get() + 1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::f32::{INFINITY, NAN};
+
+    fn previous() -> BufferedFlightData {
+        let mut prev = BufferedFlightData::new();
+        prev.roll_rate = 1.0;
+        prev.plane_orientation_quaternion = [1.0, 0.0, 0.0, 0.0];
+        prev.latitude = -35.0;
+        prev.groundspeed = 50.0;
+        prev.barometer_inhg = 29.92;
+        prev.ambient_temp = 15.0;
+        prev.actual_rudder = Some(2.0);
+        prev
+    }
+
+    #[test]
+    fn good_readings_pass_through() {
+        let mut bfd = BufferedFlightData::new();
+        bfd.roll_rate = -3.0;
+        bfd.plane_orientation_quaternion = [0.0, 1.0, 0.0, 0.0];
+        bfd.latitude = 51.5;
+        bfd.groundspeed = 0.0;
+        bfd.barometer_inhg = 30.1;
+        bfd.ambient_temp = -56.5;
+        bfd.actual_rudder = Some(-4.0);
+        bfd.time = 7;
+
+        let clean = bfd.sanitized(&previous());
+        assert_eq!(clean.roll_rate, -3.0);
+        assert_eq!(clean.plane_orientation_quaternion, [0.0, 1.0, 0.0, 0.0]);
+        assert_eq!(clean.latitude, 51.5);
+        assert_eq!(clean.groundspeed, 0.0);
+        assert_eq!(clean.barometer_inhg, 30.1);
+        assert_eq!(clean.ambient_temp, -56.5);
+        assert_eq!(clean.actual_rudder, Some(-4.0));
+        assert_eq!(clean.time, 7);
+    }
+
+    #[test]
+    fn garbage_is_replaced_by_the_previous_reading() {
+        let mut bfd = previous();
+        bfd.roll_rate = NAN;
+        bfd.plane_orientation_quaternion = [1.0, NAN, 0.0, 0.0];
+        bfd.latitude = ::std::f64::INFINITY;
+        bfd.groundspeed = -1.0;
+        bfd.barometer_inhg = 0.0;
+        bfd.ambient_temp = INFINITY;
+        bfd.actual_rudder = Some(NAN);
+
+        let clean = bfd.sanitized(&previous());
+        assert_eq!(clean.roll_rate, 1.0);
+        assert_eq!(clean.plane_orientation_quaternion, [1.0, 0.0, 0.0, 0.0]);
+        assert_eq!(clean.latitude, -35.0);
+        assert_eq!(clean.groundspeed, 50.0);
+        assert_eq!(clean.barometer_inhg, 29.92);
+        assert_eq!(clean.ambient_temp, 15.0);
+        assert_eq!(clean.actual_rudder, Some(2.0));
+    }
+
+    #[test]
+    fn implausible_positions_are_rejected() {
+        let mut bfd = previous();
+        bfd.latitude = 91.0;
+        bfd.longitude = -181.0;
+        let clean = bfd.sanitized(&previous());
+        assert_eq!(clean.latitude, -35.0);
+        assert_eq!(clean.longitude, 0.0);
+    }
+
+    #[test]
+    fn missing_surfaces_stay_missing() {
+        let mut bfd = previous();
+        bfd.actual_rudder = None;
+        assert_eq!(bfd.sanitized(&previous()).actual_rudder, None);
+
+        // and a bad reading with nothing before it falls back to neutral
+        bfd.actual_rudder = Some(NAN);
+        assert_eq!(bfd.sanitized(&BufferedFlightData::new()).actual_rudder, Some(0.0));
+    }
+
+    #[test]
+    fn first_frame_waits_for_numbers() {
+        let mut bfd = previous();
+        assert!(bfd.first_sanitized().is_some());
+
+        // With nothing to fall back on, NaN would go out as 0
+        bfd.roll_rate = NAN;
+        assert!(bfd.first_sanitized().is_none());
+        bfd.roll_rate = 1.0;
+        bfd.plane_orientation_quaternion = [INFINITY, 0.0, 0.0, 0.0];
+        assert!(bfd.first_sanitized().is_none());
+
+        // Numbers, if implausible ones, are zeroed and sent
+        bfd.plane_orientation_quaternion = [1.0, 0.0, 0.0, 0.0];
+        bfd.groundspeed = -5.0;
+        let clean = bfd.first_sanitized().unwrap();
+        assert_eq!(clean.groundspeed, 0.0);
+        assert_eq!(clean.roll_rate, 1.0);
+    }
+}
@@ -0,0 +1,175 @@
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+// Per-axis trims applied to the IMU readings before they're converted to sensor
+// units, to match the bias and scale corrections the FPGA's sensor fusion expects
+// of the real chips. Read from the file named by FFSIM_CALIBRATION, e.g.
+//
+//     # offset, then scale; units are those of the sim: deg/s, m/s^2, gauss
+//     roll_rate = [0.25, 1.0]
+//     accel_z = [0.0, 1.02]
+//
+// Axes that aren't mentioned are left alone.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct AxisCalibration {
+    pub offset: f32,
+    pub scale: f32,
+}
+
+impl AxisCalibration {
+    pub fn identity() -> Self {
+        AxisCalibration {
+            offset: 0.0,
+            scale: 1.0,
+        }
+    }
+
+    pub fn apply(&self, value: f32) -> f32 {
+        (value - self.offset) * self.scale
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Calibration {
+    pub roll_rate: AxisCalibration,
+    pub pitch_rate: AxisCalibration,
+    pub yaw_rate: AxisCalibration,
+    // x, y, z
+    pub accel: [AxisCalibration; 3],
+    pub mag: [AxisCalibration; 3],
+}
+
+impl Default for Calibration {
+    fn default() -> Self {
+        Calibration {
+            roll_rate: AxisCalibration::identity(),
+            pitch_rate: AxisCalibration::identity(),
+            yaw_rate: AxisCalibration::identity(),
+            accel: [AxisCalibration::identity(); 3],
+            mag: [AxisCalibration::identity(); 3],
+        }
+    }
+}
+
+impl Calibration {
+    // Unlike the datarefs file, a calibration file has to be asked for, so a
+    // missing one is an error too.
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let mut contents = String::new();
+        File::open(path)
+            .and_then(|mut file| file.read_to_string(&mut contents))
+            .map_err(|e| format!("couldn't read {}: {}", path.display(), e))?;
+
+        Self::parse(&contents).map_err(|e| format!("{}: {}", path.display(), e))
+    }
+
+    pub fn parse(contents: &str) -> Result<Self, String> {
+        let mut ret = Calibration::default();
+        let mut seen: Vec<String> = Vec::new();
+
+        for (num, line) in contents.lines().enumerate() {
+            // (no strings in here, so any '#' starts a comment)
+            let line = line.split('#').next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            let (key, axis) = parse_line(line)
+                .map_err(|e| format!("line {}: {}", num + 1, e))?;
+
+            let slot = match key {
+                "roll_rate" => &mut ret.roll_rate,
+                "pitch_rate" => &mut ret.pitch_rate,
+                "yaw_rate" => &mut ret.yaw_rate,
+                "accel_x" => &mut ret.accel[0],
+                "accel_y" => &mut ret.accel[1],
+                "accel_z" => &mut ret.accel[2],
+                "mag_x" => &mut ret.mag[0],
+                "mag_y" => &mut ret.mag[1],
+                "mag_z" => &mut ret.mag[2],
+                _ => return Err(format!("line {}: unknown axis {:?}", num + 1, key)),
+            };
+            if seen.iter().any(|other| other == key) {
+                return Err(format!("line {}: {} given twice", num + 1, key));
+            }
+            seen.push(key.to_string());
+            *slot = axis;
+        }
+
+        Ok(ret)
+    }
+}
+
+// Splits `axis = [offset, scale]`
+fn parse_line(line: &str) -> Result<(&str, AxisCalibration), String> {
+    let eq = line.find('=').ok_or("expected `axis = [offset, scale]`".to_string())?;
+    let key = line[.. eq].trim();
+    let rest = line[eq + 1 ..].trim();
+
+    if !rest.starts_with('[') || !rest.ends_with(']') {
+        return Err(format!("value for {} should be [offset, scale]", key));
+    }
+    let numbers: Vec<&str> = rest[1 .. rest.len() - 1].split(',').map(|num| num.trim()).collect();
+    if numbers.len() != 2 {
+        return Err(format!("value for {} should be [offset, scale]", key));
+    }
+
+    let parse = |num: &str| -> Result<f32, String> {
+        match num.parse::<f32>() {
+            Ok(val) if val.is_finite() => Ok(val),
+            _ => Err(format!("bad number {:?} for {}", num, key)),
+        }
+    };
+
+    Ok((key, AxisCalibration {
+        offset: parse(numbers[0])?,
+        scale: parse(numbers[1])?,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn offset_comes_off_before_scaling() {
+        let axis = AxisCalibration { offset: 0.5, scale: 2.0 };
+        assert_eq!(axis.apply(1.5), 2.0);
+        assert_eq!(AxisCalibration::identity().apply(-3.25), -3.25);
+    }
+
+    #[test]
+    fn parse_the_example() {
+        let cal = Calibration::parse("\
+            # offset, then scale; units are those of the sim: deg/s, m/s^2, gauss\n\
+            roll_rate = [0.25, 1.0]\n\
+            \n\
+            accel_z = [0.0, 1.02]   # a little under-reading\n").unwrap();
+
+        assert_eq!(cal.roll_rate, AxisCalibration { offset: 0.25, scale: 1.0 });
+        assert_eq!(cal.accel[2], AxisCalibration { offset: 0.0, scale: 1.02 });
+        // The rest are left alone
+        assert_eq!(cal.pitch_rate, AxisCalibration::identity());
+        assert_eq!(cal.mag, [AxisCalibration::identity(); 3]);
+    }
+
+    #[test]
+    fn empty_file_changes_nothing() {
+        assert_eq!(Calibration::parse("").unwrap(), Calibration::default());
+        assert_eq!(Calibration::parse("# nothing yet\n").unwrap(), Calibration::default());
+    }
+
+    #[test]
+    fn mistakes_say_which_line() {
+        let err = |contents: &str| Calibration::parse(contents).unwrap_err();
+
+        assert_eq!(err("mag_x = [0, 1]\nmag_w = [0, 1]"), "line 2: unknown axis \"mag_w\"");
+        assert_eq!(err("yaw_rate = [0, 1]\nyaw_rate = [1, 1]"), "line 2: yaw_rate given twice");
+        assert!(err("roll_rate [0, 1]").starts_with("line 1: expected"));
+        assert!(err("roll_rate = 0, 1").contains("should be [offset, scale]"));
+        assert!(err("roll_rate = [0, 1, 2]").contains("should be [offset, scale]"));
+        assert!(err("roll_rate = [0, inf]").contains("bad number \"inf\""));
+        assert!(err("roll_rate = [zero, 1]").contains("bad number \"zero\""));
+    }
+}
@@ -0,0 +1,61 @@
+// Monotonic timestamps for measuring latency: nanoseconds since the clock was
+// first read. Unlike SystemTime these can't jump when NTP adjusts the wall
+// clock. The controller just echoes them back to us, so the arbitrary origin
+// doesn't matter to anyone else.
+//
+// 0 is never a valid timestamp; it stands for "none" (e.g. control data that
+// was never written).
+
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use super::latency::as_nanos;
+
+// Set on first use
+static ORIGIN: Mutex<Option<Instant>> = Mutex::new(None);
+
+fn origin() -> Instant {
+    // (Nothing panics while holding the lock, and the Instant would be fine if it did)
+    let mut origin = match ORIGIN.lock() {
+        Ok(guard) => guard,
+        Err(poisoned) => poisoned.into_inner(),
+    };
+    *origin.get_or_insert_with(Instant::now)
+}
+
+pub fn now() -> u64 {
+    // (the very first reading could otherwise be 0)
+    as_nanos(origin().elapsed()).max(1)
+}
+
+// How long ago `timestamp` was taken. None for the "none" timestamp, or one
+// from the future, which can only be garbage.
+pub fn since(timestamp: u64) -> Option<Duration> {
+    if timestamp == 0 {
+        return None;
+    }
+    now().checked_sub(timestamp).map(Duration::from_nanos)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn timestamps_are_never_zero_and_never_go_backwards() {
+        let first = now();
+        assert!(first > 0);
+        let second = now();
+        assert!(second >= first);
+    }
+
+    #[test]
+    fn since_skips_missing_and_future_timestamps() {
+        assert_eq!(since(0), None);
+        // From the future: garbage
+        assert_eq!(since(now() + 1_000_000_000), None);
+
+        let stamp = now();
+        assert!(since(stamp).is_some());
+    }
+}
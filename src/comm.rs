@@ -1,170 +1,371 @@
 use std::io;
 use std::io::{Read, Write};
-use std::time::Duration;
-use std::thread;
-use std::mem::transmute;
-use std::sync::atomic::Ordering;
-use std::sync::{Arc, Mutex};
-use serial;
-use serial::SerialPort;
-
-use super::STOP_THREADS;
-use super::flight_data::FLIGHT_DATA_SIZE;
-use super::control_data::CONTROL_DATA_SIZE;
+use std::time::{Duration, Instant};
+use std::sync::{Arc, Condvar, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use super::config::Config;
+use super::link;
+use super::link::{LinkHandle, SharedLink};
+use super::replay::Replay;
+use super::telemetry_log::TelemetryLog;
+use super::flight_data::{Saturation, FLIGHT_DATA_SIZE};
+use super::control_data::{CONTROL_DATA_SIZE, FrameError};
+use super::control_queue::ControlSender;
+use super::wire::{WireEndian, SYNC, sequence_gap};
 use super::BufferedFlightData;
 use super::ControlData;
 use super::BufferedControlData;
 
 use super::FlightData;
-use triple_buffer::{Input, Output};
-
-fn ser_connect() -> io::Result<serial::SystemPort> {
-    // FIXME: We probably want to be a bit more flexible
-    let mut ser = serial::open(if cfg!(target_os = "windows") {
-        "COM5"
-    } else {
-        "/dev/ttyUSB0"
-    })?;
-
-    // Loosely based on the example in
-    // https://github.com/dcuddeback/serial-rs/tree/master/serial
-    ser.reconfigure(&|settings| {
-        settings.set_baud_rate(serial::BaudOther(4_000_000))?;
-        settings.set_char_size(serial::Bits8);
-        settings.set_parity(serial::ParityNone);
-        settings.set_stop_bits(serial::Stop1);
-        settings.set_flow_control(serial::FlowNone);
-        Ok(())
-    })?;
-
-    //ser.set_timeout(Duration::from_millis(100))?;
-
-    Ok(ser)
+use triple_buffer::Output;
+
+// Tells the comm threads to stop. Unlike a plain flag this also wakes them up
+// if they're sleeping, so they exit promptly. Each `start()` makes a new one,
+// so threads left over from a previous load can't be revived by accident.
+pub struct StopSignal {
+    stopped: Mutex<bool>,
+    cvar: Condvar,
+}
+
+impl StopSignal {
+    pub fn new() -> Self {
+        StopSignal {
+            stopped: Mutex::new(false),
+            cvar: Condvar::new(),
+        }
+    }
+
+    pub fn stop(&self) {
+        *self.stopped.lock().unwrap() = true;
+        self.cvar.notify_all();
+    }
+
+    pub fn is_stopped(&self) -> bool {
+        *self.stopped.lock().unwrap()
+    }
+
+    // Sleeps for `dur`, or until `stop` is called if that's sooner. Returns
+    // whether we've been told to stop.
+    pub fn sleep(&self, dur: Duration) -> bool {
+        let deadline = Instant::now() + dur;
+        let mut stopped = self.stopped.lock().unwrap();
+        while !*stopped {
+            let now = Instant::now();
+            if now >= deadline {
+                break;
+            }
+            // (spurious wakeups just take us around the loop again)
+            stopped = self.cvar.wait_timeout(stopped, deadline - now).unwrap().0;
+        }
+        *stopped
+    }
+}
+
+// Counts of the frames we've had from the controller, to give an idea of the
+// link's quality. Kept up by the receive thread and published as datarefs
+// (ffsim/stats/...) from the flight loop. They only ever go up, for as long as
+// the plugin is loaded.
+pub struct LinkStats {
+    pub frames: AtomicUsize,        // everything that looked like a frame
+    pub valid_frames: AtomicUsize,  // ... and passed verification
+    pub bad_headers: AtomicUsize,
+    pub crc_errors: AtomicUsize,
+}
+
+impl LinkStats {
+    pub fn new() -> Self {
+        LinkStats {
+            frames: AtomicUsize::new(0),
+            valid_frames: AtomicUsize::new(0),
+            bad_headers: AtomicUsize::new(0),
+            crc_errors: AtomicUsize::new(0),
+        }
+    }
+}
+
+// Connection attempts start this far apart ...
+const MIN_RETRY_DELAY_MS: u64 = 20;
+// ... and back off exponentially up to this
+const MAX_RETRY_DELAY_MS: u64 = 2000;
+
+// Delay between attempts to (re)connect the link
+struct Backoff {
+    delay_ms: u64,
+}
+
+impl Backoff {
+    fn new() -> Self {
+        Backoff {
+            delay_ms: MIN_RETRY_DELAY_MS,
+        }
+    }
+
+    // Call after a successful connection
+    fn reset(&mut self) {
+        self.delay_ms = MIN_RETRY_DELAY_MS;
+    }
+
+    // How long to wait before the next attempt. Each call doubles the next delay.
+    fn next_delay(&mut self) -> Duration {
+        let delay = Duration::from_millis(self.delay_ms);
+        self.delay_ms = (self.delay_ms * 2).min(MAX_RETRY_DELAY_MS);
+        delay
+    }
 }
 
-pub fn send_flight_data_thread(data_in_: Output<BufferedFlightData>, ser_: Arc<Mutex<Option<serial::SystemPort>>>) {
+// `serial_port` is the port to (re)connect to; it can be changed from the menu.
+// With a `replay`, its frames are sent instead of the sim's.
+pub fn send_flight_data_thread(data_in_: Output<BufferedFlightData>, replay: Option<Replay>,
+                               link_: Arc<SharedLink>, serial_port: Arc<Mutex<String>>,
+                               paused: Arc<AtomicBool>, stop: Arc<StopSignal>, config: Config) {
     let mut data_in = data_in_;
-    let mut ser: Option<serial::SystemPort>;
+    let mut replay = replay;
+    let mut handle = LinkHandle::new();
+    let mut backoff = Backoff::new();
+    // Last flight data that passed sanity checks (None until the first does);
+    // see `BufferedFlightData::sanitized`
+    let mut last_valid: Option<BufferedFlightData> = None;
+    // (finishes writing when it's dropped, i.e. when this thread exits)
+    let mut telemetry_log = config.telemetry_dir.as_ref()
+        .map(|dir| TelemetryLog::start(dir, config.telemetry_max_mb.saturating_mul(1024 * 1024),
+                                       config.log_gzip));
+    // Whether the last connection attempt worked (None before the first one), so
+    // we only log when this changes rather than on every failed attempt.
+    let mut connected: Option<bool> = None;
+    // Of the next frame we send
+    let mut sequence: u16 = 0;
+    // Which sensor readings are pinned, see `FlightData::new`
+    let mut saturation = Saturation::new();
+    // So we only say once that we're waiting for the first frame
+    let mut waiting_logged = false;
 
     loop {
-        if STOP_THREADS.load(Ordering::SeqCst) {
+        if stop.is_stopped() {
             break;
         }
 
-        let guard = ser_.lock().unwrap();
-        ser = guard.clone();
-        drop(guard);
+        let mut delay = config.send_interval();
 
-        let new_ser = match ser {
-            Some(mut port) => {
-                let data = FlightData::new(*data_in.read());
-                let bytes: [u8; FLIGHT_DATA_SIZE] = unsafe { transmute(data) };
-                match port.write_all(&bytes[..]) {
-                    Ok(_) => Some(port),
-                    Err(e) => {
-                        println!("[FFSim] Lost serial connection: send, with error {:?}", e);
-                        port.close();
-                        None
+        let result = match handle.get(&link_) {
+            // Keep the link (and our timing) but send nothing
+            Some(_) if paused.load(Ordering::SeqCst) => Some(Ok(())),
+            Some(link) => {
+                let next = match replay {
+                    Some(ref mut replay) => Some(replay.next_frame()),
+                    None => match last_valid {
+                        Some(ref previous) => Some(data_in.read().sanitized(previous)),
+                        None => data_in.read().first_sanitized(),
                     },
+                };
+                match next {
+                    Some(frame) => {
+                        last_valid = Some(frame);
+                        if let Some(ref mut telemetry_log) = telemetry_log {
+                            telemetry_log.record(&frame);
+                        }
+                        let data = FlightData::new(frame, sequence, &config, &mut saturation);
+                        sequence = sequence.wrapping_add(1);
+                        let bytes: [u8; FLIGHT_DATA_SIZE] = data.to_bytes(config.wire_endian);
+                        Some(link.write_all(&bytes[..]))
+                    }
+                    // The sim hasn't given us a frame of numbers yet
+                    None => {
+                        if !waiting_logged {
+                            log_warn!("Not sending flight data until the sim's readings are all numbers");
+                            waiting_logged = true;
+                        }
+                        Some(Ok(()))
+                    }
                 }
             }
+            None => None,
+        };
+
+        match result {
+            Some(Ok(_)) => (),
+            Some(Err(e)) => {
+                log_warn!("Lost connection: send, with error {:?}", e);
+                connected = Some(false);
+                handle.failed(&link_);
+            }
             None => {
-                match ser_connect() {
-                    Ok(port) => {
-                        println!("[FFSim] Got serial connection");
-                        Some(port)
+                // If the link isn't up (yet) we just try again next time around
+                let port_name = serial_port.lock().unwrap().clone();
+                match link::connect(&config, &port_name) {
+                    Ok(link) => {
+                        connected = Some(true);
+                        backoff.reset();
+                        link_.set(link);
                     }
                     Err(e) => {
-                        println!("[FFSim] Serial connection failed: send, with error {:?}", e);
-                        None
+                        if connected != Some(false) {
+                            log_warn!("Connection failed: send, with error {:?}", e);
+                        }
+                        connected = Some(false);
+                        link_.connect_failed();
+                        delay = backoff.next_delay();
                     },
                 }
             }
-        };
-        ser = new_ser;
-
-        let mut guard = ser_.lock().unwrap();
-        *guard = ser.clone();
-        drop(guard);
+        }
 
-        thread::sleep(Duration::from_millis(20)); // 50Hz
+        if stop.sleep(delay) {
+            break;
+        }
     }
 }
 
-pub fn recv_control_data_thread(data_out_: Input<BufferedControlData>, ser_: Arc<Mutex<Option<serial::SystemPort>>>) {
+// Room for a few frames, so if several have piled up on the link since the
+// last read they're all picked up at once
+const RECV_BUF_SIZE: usize = 4 * CONTROL_DATA_SIZE;
+
+pub fn recv_control_data_thread(data_out_: ControlSender, link_: Arc<SharedLink>,
+                                stats: Arc<LinkStats>, stop: Arc<StopSignal>, config: Config) {
     let mut data_out = data_out_;
-    let mut ser: Option<serial::SystemPort>;
+    let mut handle = LinkHandle::new();
 
-    let mut buf: [u8; CONTROL_DATA_SIZE] = [0; CONTROL_DATA_SIZE];
+    let mut buf: [u8; RECV_BUF_SIZE] = [0; RECV_BUF_SIZE];
     let mut cursor: usize = 0;
 
+    // Whether the last read worked, so we only log when the link goes down
+    // rather than every time a read fails
+    let mut connected = false;
+    // Sequence number the next frame from the controller should have, once
+    // we've seen one
+    let mut expected_sequence: Option<u16> = None;
+
     loop {
-        if STOP_THREADS.load(Ordering::SeqCst) {
+        if stop.is_stopped() {
             break;
         }
 
-        let guard = ser_.lock().unwrap();
-        ser = guard.clone();
-        drop(guard);
-
-        match ser {
-            Some(mut port) => {
-                match port.read_exact(&mut buf[cursor..]) {
-                    Ok(_) => {
-                        // case 1: "SYNC" is at the start of the buffer, so we can
-                        //         interpret the whole thing as a ControlData struct
-                        if buf[..4] == *"SYNC".as_bytes() {
-                            let cd: ControlData = unsafe { transmute(buf) };
-                            if cd.verify() {
-                                // Actually pass the control data on to the flightsim
-                                data_out.write(BufferedControlData::from_external(cd));
-                            } else {
-                                println!("[FFSim] Bad checksum");
+        // Take whatever has arrived so far; a frame may well span several
+        // reads, and one read may hold several frames
+        let result = match handle.get(&link_) {
+            Some(link) => match link.read(&mut buf[cursor..]) {
+                // This is how a TCP connection tells us the other end hung up
+                Ok(0) => Some(Err(io::Error::new(io::ErrorKind::UnexpectedEof, "link closed"))),
+                other => Some(other),
+            },
+            None => None,
+        };
+
+        match result {
+            Some(Ok(len)) => {
+                connected = true;
+                cursor = drain_frames(&mut buf[..], cursor + len, config.wire_endian, |cd| {
+                    stats.frames.fetch_add(1, Ordering::Relaxed);
+                    match cd.check(config.wire_endian) {
+                        Ok(_) => {
+                            stats.valid_frames.fetch_add(1, Ordering::Relaxed);
+
+                            let gap = expected_sequence
+                                .map(|expected| sequence_gap(expected, cd.sequence));
+                            match gap {
+                                Some(Some(0)) | None => (),
+                                Some(Some(gap)) =>
+                                    log_warn!("Missed {} frames from the controller", gap),
+                                Some(None) =>
+                                    log_info!("Controller's frame count went backwards, was it restarted?"),
                             }
-                            // In either case, we want to have an entirely fresh
-                            // buffer the next time
-                            cursor = 0;
-                        }
+                            expected_sequence = Some(cd.sequence.wrapping_add(1));
 
-                        // case 2: "SYNC" is a substring. Discard all bytes before the substring,
-                        //         and move the rest up to make room for more input
-                        else if let Some(pos) = buf.windows(4).position(|window|
-                            *window == *"SYNC".as_bytes()) {
-                            shift(&mut buf[..], pos);
-                            cursor = CONTROL_DATA_SIZE - pos;
+                            // Actually pass the control data on to the flightsim
+                            data_out.write(BufferedControlData::from_external(cd, &config));
+                            true
                         }
-
-                        // case 3: "SYN" is at the end of the buf. The next input byte may well be
-                        //         'C', so discard everything before "SYN" and move it to the front
-                        else if buf[CONTROL_DATA_SIZE - 3..] == *"SYN".as_bytes() {
-                            shift(&mut buf[..], CONTROL_DATA_SIZE - 3);
-                            cursor = 3;
+                        // (`check` has already said what was wrong)
+                        Err(FrameError::BadHeader) => {
+                            stats.bad_headers.fetch_add(1, Ordering::Relaxed);
+                            false
                         }
-                        // The rest of the cases are fairly self-explanatory
-                        else if buf[CONTROL_DATA_SIZE - 2..] == *"SY".as_bytes() {
-                            shift(&mut buf[..], CONTROL_DATA_SIZE - 2);
-                            cursor = 2;
-                        } else if buf[CONTROL_DATA_SIZE - 1..] == *"S".as_bytes() {
-                            shift(&mut buf[..], CONTROL_DATA_SIZE - 1);
-                            cursor = 1;
-                        } else {
-                            cursor = 0;
+                        Err(FrameError::BadChecksum) => {
+                            stats.crc_errors.fetch_add(1, Ordering::Relaxed);
+                            false
                         }
                     }
+                });
+            }
 
-                    Err(e) => {
-                        println!("[FFSim] Lost serial connection: receive, with error {:?}", e);
-                        cursor = 0; // unlikely that transmission will resume from the same point
+            // Nothing arrived in time. Keep what we have of the current
+            // frame, the rest of it may yet turn up.
+            // (TCP read timeouts show up as WouldBlock on some platforms)
+            Some(Err(ref e)) if e.kind() == io::ErrorKind::TimedOut
+                || e.kind() == io::ErrorKind::WouldBlock
+                || e.kind() == io::ErrorKind::Interrupted => (),
 
-                        let mut guard = ser_.lock().unwrap();
-                        port.close();
-                        *guard = None;
-                        drop(guard);
-                    }
+            Some(Err(e)) => {
+                if connected {
+                    log_warn!("Lost connection: receive, with error {:?}", e);
                 }
+                connected = false;
+                cursor = 0; // unlikely that transmission will resume from the same point
+                expected_sequence = None;
+                handle.failed(&link_);
             }
             None => {
-                thread::sleep(Duration::from_millis(200));
+                // wait for the send thread to connect
+                if stop.sleep(Duration::from_millis(200)) {
+                    break;
+                }
+            }
+        }
+    }
+}
+
+// Passes each complete frame in the first `len` bytes of `buf` to `on_frame`,
+// decoded with `endian` and in order, which says whether it was valid.
+// Whatever is left over that might be the start of the next frame is moved to
+// the front of the buffer; returns how many bytes of it are kept, i.e. where
+// the next read should start filling from.
+//
+// "SYNC" can turn up inside a frame as well as at its start: not in the
+// controls themselves (the high byte of each is at most 0x07, and every 4-byte
+// window over them includes one), but in the sequence number, the timestamp,
+// the checksum or across the boundary between them. Once we're in step with
+// the controller that doesn't matter, since we only look for "SYNC" where a
+// frame should start. But if we've lost framing we may lock onto one of these
+// instead, so when a frame fails verification we only skip its "SYNC" and
+// search again from there. Whatever real frame was overlapping it is then
+// found in full, and the false match costs nothing but a checksum error.
+fn drain_frames<F>(buf: &mut [u8], len: usize, endian: WireEndian, mut on_frame: F) -> usize
+    where F: FnMut(ControlData) -> bool {
+    let mut len = len;
+
+    loop {
+        match buf[..len].windows(4).position(|window| *window == SYNC) {
+            Some(pos) => {
+                // Discard anything before "SYNC"; we've lost framing
+                shift(&mut buf[..len], pos);
+                len -= pos;
+
+                if len < CONTROL_DATA_SIZE {
+                    // wait for the rest of the frame
+                    return len;
+                }
+
+                let mut frame = [0; CONTROL_DATA_SIZE];
+                frame.copy_from_slice(&buf[..CONTROL_DATA_SIZE]);
+                // ("SYNC" can't overlap itself, so skipping all of it is safe)
+                let used = if on_frame(ControlData::from_bytes(&frame, endian)) {
+                    CONTROL_DATA_SIZE
+                } else {
+                    SYNC.len()
+                };
+
+                shift(&mut buf[..len], used);
+                len -= used;
+            }
+
+            // No "SYNC" anywhere, but the end of the buffer could be the
+            // start of one ("SYN", "SY" or "S"), in which case keep that
+            None => {
+                let keep = (1 .. 4).rev()
+                    .find(|&n| n <= len && buf[len - n .. len] == SYNC[..n])
+                    .unwrap_or(0);
+                shift(&mut buf[..len], len - keep);
+                return keep;
             }
         }
     }
@@ -177,3 +378,155 @@ fn shift<T: Copy>(arr: &mut [T], start_pos: usize) {
     }
 }
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::wire::TIMESTAMP_SIZE;
+
+    fn frame(sequence: u16) -> [u8; CONTROL_DATA_SIZE] {
+        ControlData::neutral(sequence, [0; TIMESTAMP_SIZE], WireEndian::Little)
+            .to_bytes(WireEndian::Little)
+    }
+
+    // Runs `drain_frames` over `buf[..len]`, giving back the cursor and the
+    // sequence numbers of the valid frames it found
+    fn drain(buf: &mut [u8], len: usize) -> (usize, Vec<u16>) {
+        let mut sequences = Vec::new();
+        let cursor = drain_frames(buf, len, WireEndian::Little, |cd| {
+            if cd.verify(WireEndian::Little) {
+                sequences.push(cd.sequence);
+                true
+            } else {
+                false
+            }
+        });
+        (cursor, sequences)
+    }
+
+    #[test]
+    fn frame_split_across_reads() {
+        let whole = frame(7);
+        let mut buf = [0u8; RECV_BUF_SIZE];
+
+        buf[.. 10].copy_from_slice(&whole[.. 10]);
+        let (cursor, sequences) = drain(&mut buf, 10);
+        assert_eq!(cursor, 10);
+        assert_eq!(&buf[.. 10], &whole[.. 10]);
+        assert!(sequences.is_empty());
+
+        buf[cursor .. CONTROL_DATA_SIZE].copy_from_slice(&whole[10 ..]);
+        let (cursor, sequences) = drain(&mut buf, CONTROL_DATA_SIZE);
+        assert_eq!(cursor, 0);
+        assert_eq!(sequences, vec![7]);
+    }
+
+    #[test]
+    fn frames_back_to_back_come_out_in_order() {
+        let mut buf = [0u8; RECV_BUF_SIZE];
+        buf[.. CONTROL_DATA_SIZE].copy_from_slice(&frame(10));
+        buf[CONTROL_DATA_SIZE .. 2 * CONTROL_DATA_SIZE].copy_from_slice(&frame(11));
+
+        let (cursor, sequences) = drain(&mut buf, 2 * CONTROL_DATA_SIZE);
+        assert_eq!(cursor, 0);
+        assert_eq!(sequences, vec![10, 11]);
+    }
+
+    #[test]
+    fn big_endian_frames_need_a_big_endian_reader() {
+        let big = ControlData::neutral(0x0102, [0; TIMESTAMP_SIZE], WireEndian::Big)
+            .to_bytes(WireEndian::Big);
+
+        for &(endian, expected) in &[(WireEndian::Big, &[0x0102][..]), (WireEndian::Little, &[][..])] {
+            let mut buf = [0u8; RECV_BUF_SIZE];
+            buf[.. CONTROL_DATA_SIZE].copy_from_slice(&big);
+            let mut sequences = Vec::new();
+            drain_frames(&mut buf, CONTROL_DATA_SIZE, endian, |cd| {
+                let valid = cd.verify(endian);
+                if valid {
+                    sequences.push(cd.sequence);
+                }
+                valid
+            });
+            assert_eq!(&sequences[..], expected, "{:?}", endian);
+        }
+    }
+
+    #[test]
+    fn false_sync_in_a_payload_costs_only_a_checksum_error() {
+        // The tail of a frame whose timestamp happens to contain "SYNC", as if
+        // we'd started reading partway through it, then a real frame
+        let decoy = ControlData::neutral(1, *b"abSYNCcd", WireEndian::Little)
+            .to_bytes(WireEndian::Little);
+        let tail = &decoy[CONTROL_DATA_SIZE - 4 - TIMESTAMP_SIZE ..];
+        let mut buf = [0u8; RECV_BUF_SIZE];
+        buf[.. tail.len()].copy_from_slice(tail);
+        buf[tail.len() .. tail.len() + CONTROL_DATA_SIZE].copy_from_slice(&frame(2));
+
+        let mut rejected = 0;
+        let mut sequences = Vec::new();
+        let cursor = drain_frames(&mut buf, tail.len() + CONTROL_DATA_SIZE, WireEndian::Little, |cd| {
+            match cd.check(WireEndian::Little) {
+                Ok(_) => sequences.push(cd.sequence),
+                Err(e) => {
+                    assert_eq!(e, FrameError::BadChecksum);
+                    rejected += 1;
+                }
+            }
+            cd.verify(WireEndian::Little)
+        });
+
+        assert_eq!(rejected, 1);
+        assert_eq!(sequences, vec![2]);
+        assert_eq!(cursor, 0);
+    }
+
+    #[test]
+    fn garbage_before_sync_is_discarded() {
+        let garbage = b"\x00\xffSYxN";
+        let mut buf = [0u8; RECV_BUF_SIZE];
+        buf[.. garbage.len()].copy_from_slice(garbage);
+        buf[garbage.len() .. garbage.len() + CONTROL_DATA_SIZE].copy_from_slice(&frame(3));
+
+        let (cursor, sequences) = drain(&mut buf, garbage.len() + CONTROL_DATA_SIZE);
+        assert_eq!(cursor, 0);
+        assert_eq!(sequences, vec![3]);
+    }
+
+    #[test]
+    fn start_of_sync_at_end_is_kept() {
+        for n in 1 .. SYNC.len() {
+            let mut buf = [0u8; RECV_BUF_SIZE];
+            buf[.. 5].copy_from_slice(b"noise");
+            buf[5 .. 5 + n].copy_from_slice(&SYNC[.. n]);
+
+            let (cursor, sequences) = drain(&mut buf, 5 + n);
+            assert_eq!(cursor, n);
+            assert_eq!(&buf[.. n], &SYNC[.. n]);
+            assert!(sequences.is_empty());
+        }
+    }
+
+    #[test]
+    fn nothing_like_sync_is_dropped() {
+        let mut buf = [0u8; RECV_BUF_SIZE];
+        buf[.. 8].copy_from_slice(b"garbage!");
+
+        let (cursor, sequences) = drain(&mut buf, 8);
+        assert_eq!(cursor, 0);
+        assert!(sequences.is_empty());
+    }
+
+    #[test]
+    fn sync_at_an_offset_is_moved_to_the_front() {
+        let whole = frame(1);
+        let mut buf = [0u8; RECV_BUF_SIZE];
+        buf[.. 3].copy_from_slice(b"abc");
+        buf[3 .. 23].copy_from_slice(&whole[.. 20]);
+
+        let (cursor, sequences) = drain(&mut buf, 23);
+        assert_eq!(cursor, 20);
+        assert_eq!(&buf[.. 20], &whole[.. 20]);
+        assert!(sequences.is_empty());
+    }
+}
@@ -0,0 +1,572 @@
+use std::env;
+use std::fmt::Display;
+use std::str::FromStr;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use super::aircraft::{Profile, DEFAULT_PROFILE};
+use super::calibration::Calibration;
+use super::imu;
+use super::imu::ImuScales;
+use super::log::Level;
+use super::log_file;
+use super::nmea;
+use super::wire::WireEndian;
+use super::quaternion::Quaternion;
+
+pub const DEFAULT_BAUD_RATE: usize = 4_000_000;
+pub const DEFAULT_SEND_RATE_HZ: u32 = 50;
+pub const DEFAULT_WATCHDOG_MS: u64 = 500;
+// Longest FFSIM_CONTROL_QUEUE, well over a second's worth at any rate
+pub const MAX_CONTROL_QUEUE: usize = 10_000;
+pub const DEFAULT_READ_TIMEOUT_MS: u64 = 100;
+pub const DEFAULT_TELEMETRY_MAX_MB: u64 = 100;
+pub const DEFAULT_MAG_FIELD_GAUSS: f32 = 0.45;
+pub const DEFAULT_LATENCY_BUCKET_US: u64 = 100;
+// Initially the latency is very erratic
+pub const DEFAULT_LATENCY_WARMUP: usize = 100;
+
+// Kinds of NMEA sentence we can put in the `gps` field of `FlightData`
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum NmeaSentence {
+    Gll, // latitude/longitude
+    Gga, // fix data, including altitude
+    Rmc, // recommended minimum, including ground speed and track
+}
+
+// Degrees added to what the controller commands for each control surface, so
+// that a neutral command can hold an airframe level that needs some trim.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct Trims {
+    pub rudder: f32,         // FFSIM_TRIM_RUDDER
+    pub left_aileron: f32,   // FFSIM_TRIM_LEFT_AILERON
+    pub right_aileron: f32,  // FFSIM_TRIM_RIGHT_AILERON
+    pub left_elevator: f32,  // FFSIM_TRIM_LEFT_ELEVATOR
+    pub right_elevator: f32, // FFSIM_TRIM_RIGHT_ELEVATOR
+}
+
+// Control axes whose commands are flipped before they reach the sim, for
+// airframes whose datarefs move the other way to the Skyhawk's. None by default.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct Inversions {
+    pub rudder: bool,    // FFSIM_INVERT_RUDDER
+    pub ailerons: bool,  // FFSIM_INVERT_AILERONS
+    pub elevators: bool, // FFSIM_INVERT_ELEVATORS
+}
+
+// Runtime configuration. Read once from the environment when the plugin
+// starts, so changing a variable requires reloading the plugin.
+#[derive(Clone, Debug)]
+pub struct Config {
+    // Name of the serial port the FPGA is attached to (FFSIM_SERIAL_PORT)
+    pub serial_port: String,
+    // Baud rate of the serial link (FFSIM_BAUD)
+    pub baud_rate: usize,
+    // If set, talk to the controller over TCP at this address (e.g.
+    // "192.168.1.10:5000") instead of the serial port (FFSIM_TCP_ADDR)
+    pub tcp_addr: Option<String>,
+    // Byte order of the multi-byte fields in both frames, to match how the
+    // FPGA was built (FFSIM_WIRE_ENDIAN: little, the default, or big)
+    pub wire_endian: WireEndian,
+    // Don't talk to a controller at all, answer our own flight data with neutral
+    // controls instead (FFSIM_LOOPBACK=1). For testing without hardware.
+    pub loopback: bool,
+    // How often flight data is sent to the controller, 1-1000Hz (FFSIM_SEND_RATE_HZ)
+    pub send_rate_hz: u32,
+    // NMEA sentences to send, in order (FFSIM_NMEA, e.g. "gll,gga,rmc")
+    pub nmea_sentences: Vec<NmeaSentence>,
+    // Talker ID they start with (FFSIM_NMEA_TALKER), "GP" by default
+    pub nmea_talker: String,
+    // Control surface deflection (degrees, either side of zero) that the full
+    // 11-bit range of the controller maps onto (FFSIM_MAX_DEFLECTION). This
+    // and the steering limit default to the aircraft profile's.
+    pub max_deflection_deg: f32,
+    // Likewise for nosewheel steering (FFSIM_MAX_STEERING)
+    pub max_steering_deg: f32,
+    // Trim for each control surface, in degrees; none by default. The trimmed
+    // deflection is still kept within `max_deflection_deg`.
+    pub trims: Trims,
+    // Axes to flip the controller's commands on, before trim is added
+    pub inversions: Inversions,
+    // Fastest any of the rudder, ailerons and elevators may move, in degrees
+    // per second (FFSIM_SLEW_RATE). 0, the default, means no limit.
+    pub slew_rate_deg_s: f32,
+    // Fraction of the throttle's range, from the bottom, that asks for reverse
+    // thrust instead (FFSIM_THROTTLE_REVERSE, e.g. 0.25). 0, the default, means
+    // the whole range is forward thrust, from idle to full.
+    pub throttle_reverse: f32,
+    // Run the flight loop (reading the sim, applying the controls) every this
+    // many milliseconds rather than every frame (FFSIM_FLIGHT_LOOP_MS). 0, the
+    // default, means every frame.
+    pub flight_loop_ms: u64,
+    // If nothing valid arrives from the controller for this long, the controls
+    // are returned to neutral and the throttle to idle (FFSIM_WATCHDOG_MS)
+    pub watchdog_ms: u64,
+    // If not 0, pass control data to the flight loop through a queue of this
+    // many (FFSIM_CONTROL_QUEUE) rather than keeping only the latest; see
+    // control_queue.rs
+    pub control_queue_len: usize,
+    // How long a read from the controller may block before we check whether
+    // we've been told to stop (FFSIM_READ_TIMEOUT_MS)
+    pub read_timeout_ms: u64,
+    // Directory to write latency results into (FFSIM_LATENCY_PATH). If unset
+    // they go in X-Plane's working directory.
+    pub latency_dir: Option<PathBuf>,
+    // Width of the buckets in the latency histogram, microseconds (FFSIM_LATENCY_BUCKET_US)
+    pub latency_bucket_us: u64,
+    // Round trips thrown away at the start of each latency experiment, before
+    // any are recorded (FFSIM_LATENCY_WARMUP). They come on top of the samples
+    // recorded, so a run lasts this many round trips longer.
+    pub latency_warmup: usize,
+    // Streaming mode (FFSIM_LATENCY_STREAM=1): rather than a fixed number of
+    // samples, the experiment runs until restarted and writes each sample as
+    // it's measured. See `latency::LatencyStream`.
+    pub latency_stream: bool,
+    // If set, every frame of telemetry is also logged as CSV to a file in this
+    // directory (FFSIM_TELEMETRY_LOG), see telemetry_log.rs
+    pub telemetry_dir: Option<PathBuf>,
+    // Size at which the telemetry log is rotated (FFSIM_TELEMETRY_MAX_MB)
+    pub telemetry_max_mb: u64,
+    // Gzip the telemetry log and the latency samples (FFSIM_LOG_GZIP=1). Only
+    // builds with the `gzip` feature can; see log_file.rs
+    pub log_gzip: bool,
+    // If set, the controller is sent the telemetry recorded in this file
+    // instead of the sim's (FFSIM_REPLAY), see replay.rs
+    pub replay_file: Option<PathBuf>,
+    // File mapping control surfaces to datarefs, see aircraft.rs (FFSIM_DATAREFS)
+    pub datarefs_file: PathBuf,
+    // Passive mode (FFSIM_PASSIVE=1): telemetry is still sent, but we never take
+    // control of the aircraft, so the user can fly it as normal.
+    pub passive: bool,
+    // Which controls the controller gets when it's in control: the control
+    // surfaces (FFSIM_OVERRIDE_SURFACES) and the throttle
+    // (FFSIM_OVERRIDE_THROTTLE). Both by default; set either to 0 to leave
+    // it with the user.
+    pub override_surfaces: bool,
+    pub override_throttle: bool,
+    // Work out the airspeed pressure from the true airspeed and the density of
+    // the air around the aircraft (FFSIM_LOCAL_AIR_DENSITY=1), rather than from
+    // the indicated airspeed and sea level density. See `FlightData::new`.
+    pub local_air_density: bool,
+    // Least severe messages to log (FFSIM_LOG_LEVEL: error, warn, info or debug).
+    // Debug includes hex dumps of bad frames, so it's a lot of output.
+    pub log_level: Level,
+    // File to append log messages to as well as stdout (FFSIM_LOG_FILE)
+    pub log_file: Option<PathBuf>,
+    // Sensitivities matching the full-scale ranges the FPGA sets its IMU to
+    // (FFSIM_GYRO_RANGE_DPS, FFSIM_ACCEL_RANGE_G, FFSIM_MAG_RANGE_GAUSS)
+    pub imu: ImuScales,
+    // How the IMU board is turned relative to the aircraft, from
+    // FFSIM_IMU_MOUNTING="roll,pitch,yaw" in degrees. The profile's by default.
+    pub imu_mounting: Quaternion,
+    // Strength of the Earth's magnetic field as seen by the magnetometer
+    // (FFSIM_MAG_FIELD_GAUSS), and declination to add to what the sim models,
+    // degrees east positive (FFSIM_MAG_DECLINATION)
+    pub mag_field_gauss: f32,
+    pub mag_declination_deg: f32,
+    // Reference pressure in hPa to report the barometer reading relative to,
+    // as the lps25hb does with its REF_P register set (FFSIM_BARO_REFERENCE),
+    // e.g. the QNH or QFE. 0, the default, gives the absolute pressure.
+    pub baro_reference_hpa: f32,
+    // How far each frame moves the orientation we send towards the sim's, from
+    // 1 (no smoothing, the default) down towards 0 (FFSIM_QUATERNION_SMOOTHING)
+    pub quaternion_smoothing: f32,
+    // File of per-axis IMU offsets and scales, see calibration.rs (FFSIM_CALIBRATION)
+    pub calibration_file: Option<PathBuf>,
+    // What was loaded from `calibration_file` when the plugin started; no
+    // correction at all if there isn't one
+    pub calibration: Calibration,
+    // Aircraft profile asked for with FFSIM_PROFILE (see aircraft.rs), and the
+    // profile itself, or None if there's no profile by that name. The limits
+    // and IMU mounting above default to the profile's.
+    pub profile_name: String,
+    pub profile: Option<Profile>,
+}
+
+impl Config {
+    pub fn from_env() -> Self {
+        let serial_port = match env::var("FFSIM_SERIAL_PORT") {
+            Ok(port) => port,
+            Err(_) => default_serial_port().to_string(),
+        };
+
+        let profile_name = env::var("FFSIM_PROFILE").map(|name| name.trim().to_lowercase())
+            .unwrap_or_else(|_| DEFAULT_PROFILE.to_string());
+        let profile = Profile::builtin(&profile_name);
+        // (if the name is wrong the plugin won't start, so these don't matter much)
+        let defaults = profile.clone().unwrap_or_else(Profile::cessna172);
+
+        Config {
+            serial_port,
+            baud_rate: env_or("FFSIM_BAUD", DEFAULT_BAUD_RATE, |baud| *baud > 0),
+            tcp_addr: env::var("FFSIM_TCP_ADDR").ok().filter(|addr| !addr.trim().is_empty()),
+            wire_endian: env_or("FFSIM_WIRE_ENDIAN", WireEndian::default(), |_| true),
+            loopback: env_flag("FFSIM_LOOPBACK"),
+            latency_stream: env_flag("FFSIM_LATENCY_STREAM"),
+            send_rate_hz: env_or("FFSIM_SEND_RATE_HZ", DEFAULT_SEND_RATE_HZ,
+                                 |hz| *hz >= 1 && *hz <= 1000),
+            nmea_sentences: nmea_sentences_from_env(),
+            nmea_talker: env_or("FFSIM_NMEA_TALKER", nmea::DEFAULT_TALKER.to_string(),
+                                |talker| nmea::is_valid_talker(talker)),
+            max_deflection_deg: env_or("FFSIM_MAX_DEFLECTION", defaults.max_deflection_deg,
+                                       |deg| deg.is_finite() && *deg > 0.0),
+            max_steering_deg: env_or("FFSIM_MAX_STEERING", defaults.max_steering_deg,
+                                     |deg| deg.is_finite() && *deg > 0.0),
+            trims: trims_from_env(),
+            inversions: Inversions {
+                rudder: env_flag("FFSIM_INVERT_RUDDER"),
+                ailerons: env_flag("FFSIM_INVERT_AILERONS"),
+                elevators: env_flag("FFSIM_INVERT_ELEVATORS"),
+            },
+            slew_rate_deg_s: env_or("FFSIM_SLEW_RATE", 0.0, |rate| rate.is_finite() && *rate >= 0.0),
+            throttle_reverse: env_or("FFSIM_THROTTLE_REVERSE", 0.0,
+                                     |frac| *frac >= 0.0 && *frac < 1.0),
+            flight_loop_ms: env_or("FFSIM_FLIGHT_LOOP_MS", 0, |_| true),
+            watchdog_ms: env_or("FFSIM_WATCHDOG_MS", DEFAULT_WATCHDOG_MS, |ms| *ms > 0),
+            control_queue_len: env_or("FFSIM_CONTROL_QUEUE", 0, |len| *len <= MAX_CONTROL_QUEUE),
+            read_timeout_ms: env_or("FFSIM_READ_TIMEOUT_MS", DEFAULT_READ_TIMEOUT_MS, |ms| *ms > 0),
+            latency_dir: env::var_os("FFSIM_LATENCY_PATH").map(PathBuf::from),
+            latency_bucket_us: env_or("FFSIM_LATENCY_BUCKET_US", DEFAULT_LATENCY_BUCKET_US, |us| *us > 0),
+            // (usize can't be negative, and 0 just records from the first round trip)
+            latency_warmup: env_or("FFSIM_LATENCY_WARMUP", DEFAULT_LATENCY_WARMUP, |_| true),
+            telemetry_dir: env::var_os("FFSIM_TELEMETRY_LOG").map(PathBuf::from),
+            telemetry_max_mb: env_or("FFSIM_TELEMETRY_MAX_MB", DEFAULT_TELEMETRY_MAX_MB, |mb| *mb > 0),
+            log_gzip: log_gzip_from_env(),
+            replay_file: env::var_os("FFSIM_REPLAY").map(PathBuf::from),
+            datarefs_file: env::var_os("FFSIM_DATAREFS").map(PathBuf::from)
+                .unwrap_or_else(|| PathBuf::from("ffsim.toml")),
+            passive: env_flag("FFSIM_PASSIVE"),
+            override_surfaces: env_flag_or("FFSIM_OVERRIDE_SURFACES", true),
+            override_throttle: env_flag_or("FFSIM_OVERRIDE_THROTTLE", true),
+            local_air_density: env_flag("FFSIM_LOCAL_AIR_DENSITY"),
+            log_level: env_or("FFSIM_LOG_LEVEL", Level::Info, |_| true),
+            log_file: env::var_os("FFSIM_LOG_FILE").map(PathBuf::from),
+            imu: imu_scales_from_env(),
+            imu_mounting: imu_mounting_from_env(defaults.imu_mounting_deg),
+            mag_field_gauss: env_or("FFSIM_MAG_FIELD_GAUSS", DEFAULT_MAG_FIELD_GAUSS,
+                                    |gauss| gauss.is_finite() && *gauss > 0.0),
+            mag_declination_deg: env_or("FFSIM_MAG_DECLINATION", 0.0,
+                                        |deg| deg.is_finite() && deg.abs() <= 180.0),
+            baro_reference_hpa: env_or("FFSIM_BARO_REFERENCE", 0.0,
+                                       |hpa| is_baro_reference(*hpa)),
+            quaternion_smoothing: env_or("FFSIM_QUATERNION_SMOOTHING", 1.0,
+                                         |factor| *factor > 0.0 && *factor <= 1.0),
+            calibration_file: env::var_os("FFSIM_CALIBRATION").map(PathBuf::from),
+            calibration: Calibration::default(),
+            profile_name,
+            profile,
+        }
+    }
+
+    // The defaults, as `from_env` gives with none of our variables set, but
+    // without looking: tests run in parallel and some of them set variables
+    #[cfg(test)]
+    pub fn for_test() -> Self {
+        let profile = Profile::builtin(DEFAULT_PROFILE);
+        let defaults = profile.clone().unwrap_or_else(Profile::cessna172);
+        let rad = |deg: f32| deg.to_radians();
+        let mounting = defaults.imu_mounting_deg;
+
+        Config {
+            serial_port: default_serial_port().to_string(),
+            baud_rate: DEFAULT_BAUD_RATE,
+            tcp_addr: None,
+            wire_endian: WireEndian::default(),
+            loopback: false,
+            latency_stream: false,
+            send_rate_hz: DEFAULT_SEND_RATE_HZ,
+            nmea_sentences: vec![NmeaSentence::Gll],
+            nmea_talker: nmea::DEFAULT_TALKER.to_string(),
+            max_deflection_deg: defaults.max_deflection_deg,
+            max_steering_deg: defaults.max_steering_deg,
+            trims: Trims::default(),
+            inversions: Inversions::default(),
+            slew_rate_deg_s: 0.0,
+            throttle_reverse: 0.0,
+            flight_loop_ms: 0,
+            watchdog_ms: DEFAULT_WATCHDOG_MS,
+            control_queue_len: 0,
+            read_timeout_ms: DEFAULT_READ_TIMEOUT_MS,
+            latency_dir: None,
+            latency_bucket_us: DEFAULT_LATENCY_BUCKET_US,
+            latency_warmup: DEFAULT_LATENCY_WARMUP,
+            telemetry_dir: None,
+            telemetry_max_mb: DEFAULT_TELEMETRY_MAX_MB,
+            log_gzip: false,
+            replay_file: None,
+            datarefs_file: PathBuf::from("ffsim.toml"),
+            passive: false,
+            override_surfaces: true,
+            override_throttle: true,
+            local_air_density: false,
+            log_level: Level::Info,
+            log_file: None,
+            imu: ImuScales::default(),
+            imu_mounting: Quaternion::from_euler(rad(mounting[0]), rad(mounting[1]), rad(mounting[2])),
+            mag_field_gauss: DEFAULT_MAG_FIELD_GAUSS,
+            mag_declination_deg: 0.0,
+            baro_reference_hpa: 0.0,
+            quaternion_smoothing: 1.0,
+            calibration_file: None,
+            calibration: Calibration::default(),
+            profile_name: DEFAULT_PROFILE.to_string(),
+            profile,
+        }
+    }
+
+    // False if we're talking over TCP, or not talking to anything (loopback)
+    pub fn uses_serial_port(&self) -> bool {
+        !self.loopback && self.tcp_addr.is_none()
+    }
+
+    pub fn watchdog_timeout(&self) -> Duration {
+        Duration::from_millis(self.watchdog_ms)
+    }
+
+    pub fn read_timeout(&self) -> Duration {
+        Duration::from_millis(self.read_timeout_ms)
+    }
+
+    // How long the flight loop should wait between runs; None for every frame
+    pub fn flight_loop_interval(&self) -> Option<Duration> {
+        match self.flight_loop_ms {
+            0 => None,
+            ms => Some(Duration::from_millis(ms)),
+        }
+    }
+
+    // Time between flight data packets
+    pub fn send_interval(&self) -> Duration {
+        Duration::new(0, 1_000_000_000 / self.send_rate_hz)
+    }
+}
+
+// Parses the environment variable `name`, falling back to `default` if it is unset.
+// If it is set but garbled (or rejected by `valid`) we also fall back, but warn
+// about it since that's probably not what the user intended.
+fn env_or<T, F>(name: &str, default: T, valid: F) -> T
+    where T: FromStr + Display, F: Fn(&T) -> bool {
+    match env::var(name) {
+        Ok(raw) => match raw.trim().parse::<T>() {
+            Ok(val) => {
+                if valid(&val) {
+                    return val;
+                }
+            }
+            Err(_) => (),
+        },
+        Err(_) => return default,
+    };
+
+    log_warn!("Ignoring invalid value for {}, using {}", name, default);
+    default
+}
+
+// True if `name` is set to something like "1", "true" or "yes"
+fn env_flag(name: &str) -> bool {
+    env_flag_or(name, false)
+}
+
+// Likewise, but `default` if it isn't set
+fn env_flag_or(name: &str, default: bool) -> bool {
+    match env::var(name) {
+        Ok(raw) => match raw.trim().to_lowercase().as_str() {
+            "1" | "true" | "yes" | "on" => true,
+            "0" | "false" | "no" | "off" => false,
+            "" => default,
+            _ => {
+                log_warn!("Ignoring invalid value for {}, using {}", name, default);
+                default
+            }
+        },
+        Err(_) => default,
+    }
+}
+
+// Trims are in degrees of X-Plane's deflection, added after any inversion (see
+// `Inversions`), so flipping an axis doesn't flip its trim
+fn trims_from_env() -> Trims {
+    let trim = |name| env_or(name, 0.0, |deg: &f32| deg.is_finite() && deg.abs() <= 90.0);
+
+    Trims {
+        rudder: trim("FFSIM_TRIM_RUDDER"),
+        left_aileron: trim("FFSIM_TRIM_LEFT_AILERON"),
+        right_aileron: trim("FFSIM_TRIM_RIGHT_AILERON"),
+        left_elevator: trim("FFSIM_TRIM_LEFT_ELEVATOR"),
+        right_elevator: trim("FFSIM_TRIM_RIGHT_ELEVATOR"),
+    }
+}
+
+fn imu_scales_from_env() -> ImuScales {
+    let gyro = env_or("FFSIM_GYRO_RANGE_DPS", imu::DEFAULT_GYRO_RANGE_DPS,
+                      |dps| ImuScales::is_gyro_range(*dps));
+    let accel = env_or("FFSIM_ACCEL_RANGE_G", imu::DEFAULT_ACCEL_RANGE_G,
+                       |g| ImuScales::is_accel_range(*g));
+    let mag = env_or("FFSIM_MAG_RANGE_GAUSS", imu::DEFAULT_MAG_RANGE_GAUSS,
+                     |gauss| ImuScales::is_mag_range(*gauss));
+
+    // Every range was checked above (or is a default), so this can't fail
+    ImuScales::from_ranges(gyro, accel, mag).unwrap_or_default()
+}
+
+fn log_gzip_from_env() -> bool {
+    let gzip = env_flag("FFSIM_LOG_GZIP");
+    if gzip && !log_file::GZIP_AVAILABLE {
+        log_warn!("Ignoring FFSIM_LOG_GZIP, this build doesn't have the gzip feature");
+        return false;
+    }
+    gzip
+}
+
+// `default` is roll, pitch and yaw in degrees
+fn imu_mounting_from_env(default: [f32; 3]) -> Quaternion {
+    let parsed = env::var("FFSIM_IMU_MOUNTING").ok().map(|raw| parse_angles(&raw));
+
+    let angles = match parsed {
+        Some(Some(angles)) => angles,
+        Some(None) => {
+            log_warn!("Ignoring invalid value for FFSIM_IMU_MOUNTING, using {},{},{}",
+                      default[0], default[1], default[2]);
+            default
+        }
+        None => default,
+    };
+
+    let rad = |deg: f32| deg.to_radians();
+    Quaternion::from_euler(rad(angles[0]), rad(angles[1]), rad(angles[2]))
+}
+
+// "roll,pitch,yaw", three finite numbers and nothing else
+fn parse_angles(raw: &str) -> Option<[f32; 3]> {
+    let angles: Vec<f32> = raw.split(',')
+        .filter_map(|angle| angle.trim().parse::<f32>().ok())
+        .filter(|angle| angle.is_finite())
+        .collect();
+    if angles.len() == 3 && raw.split(',').count() == 3 {
+        Some([angles[0], angles[1], angles[2]])
+    } else {
+        None
+    }
+}
+
+// 0 (none) or a pressure within the lps25hb's 1260 hPa range
+fn is_baro_reference(hpa: f32) -> bool {
+    hpa.is_finite() && hpa >= 0.0 && hpa <= 1260.0
+}
+
+fn nmea_sentences_from_env() -> Vec<NmeaSentence> {
+    let default = vec![NmeaSentence::Gll];
+
+    let raw = match env::var("FFSIM_NMEA") {
+        Ok(raw) => raw,
+        Err(_) => return default,
+    };
+
+    let mut sentences = Vec::new();
+    for name in raw.split(',') {
+        let sentence = match name.trim().to_lowercase().as_str() {
+            "gll" => NmeaSentence::Gll,
+            "gga" => NmeaSentence::Gga,
+            "rmc" => NmeaSentence::Rmc,
+            _ => {
+                log_warn!("Ignoring invalid value for FFSIM_NMEA, using {:?}", default);
+                return default;
+            }
+        };
+        // each sentence only gets one slot in the buffer
+        if !sentences.contains(&sentence) {
+            sentences.push(sentence);
+        }
+    }
+    sentences
+}
+
+fn default_serial_port() -> &'static str {
+    if cfg!(target_os = "windows") {
+        "COM5"
+    } else {
+        "/dev/ttyUSB0"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mounting_angles() {
+        assert_eq!(parse_angles("0,0,0"), Some([0.0, 0.0, 0.0]));
+        assert_eq!(parse_angles(" 180, -2.5 ,90 "), Some([180.0, -2.5, 90.0]));
+
+        assert_eq!(parse_angles(""), None);
+        assert_eq!(parse_angles("1,2"), None);
+        assert_eq!(parse_angles("1,2,3,4"), None);
+        assert_eq!(parse_angles("1,x,3"), None);
+        assert_eq!(parse_angles("1,inf,3"), None);
+        assert_eq!(parse_angles("1,NaN,3"), None);
+    }
+
+    // Each of these tests uses variables of its own, since tests run in
+    // parallel and share the environment
+
+    #[test]
+    fn numbers_from_the_environment() {
+        let warmup = |raw: Option<&str>| {
+            match raw {
+                Some(raw) => env::set_var("FFSIM_TEST_WARMUP", raw),
+                None => env::remove_var("FFSIM_TEST_WARMUP"),
+            }
+            env_or("FFSIM_TEST_WARMUP", DEFAULT_LATENCY_WARMUP, |_| true)
+        };
+
+        assert_eq!(warmup(None), DEFAULT_LATENCY_WARMUP);
+        assert_eq!(warmup(Some("25")), 25);
+        assert_eq!(warmup(Some(" 0 ")), 0);
+        // Garbled or negative: the default, not 0
+        assert_eq!(warmup(Some("-3")), DEFAULT_LATENCY_WARMUP);
+        assert_eq!(warmup(Some("lots")), DEFAULT_LATENCY_WARMUP);
+        assert_eq!(warmup(Some("")), DEFAULT_LATENCY_WARMUP);
+    }
+
+    #[test]
+    fn rejected_values_fall_back_to_the_default() {
+        env::set_var("FFSIM_TEST_RATE", "5000");
+        assert_eq!(env_or("FFSIM_TEST_RATE", 50u32, |hz| *hz >= 1 && *hz <= 1000), 50);
+        env::set_var("FFSIM_TEST_RATE", "1000");
+        assert_eq!(env_or("FFSIM_TEST_RATE", 50u32, |hz| *hz >= 1 && *hz <= 1000), 1000);
+    }
+
+    #[test]
+    fn flags_from_the_environment() {
+        let flag = |raw: Option<&str>, default: bool| {
+            match raw {
+                Some(raw) => env::set_var("FFSIM_TEST_OVERRIDE", raw),
+                None => env::remove_var("FFSIM_TEST_OVERRIDE"),
+            }
+            env_flag_or("FFSIM_TEST_OVERRIDE", default)
+        };
+
+        for &raw in &["1", "true", "Yes", " ON "] {
+            assert!(flag(Some(raw), false), "{:?}", raw);
+        }
+        for &raw in &["0", "false", "NO", "off"] {
+            assert!(!flag(Some(raw), true), "{:?}", raw);
+        }
+        // Unset, empty or garbled: the default, whichever way round that is
+        for &default in &[false, true] {
+            assert_eq!(flag(None, default), default);
+            assert_eq!(flag(Some(""), default), default);
+            assert_eq!(flag(Some("maybe"), default), default);
+        }
+    }
+
+    #[test]
+    fn baro_references() {
+        for &hpa in &[0.0, 260.0, 1013.25, 1260.0] {
+            assert!(is_baro_reference(hpa), "{}", hpa);
+        }
+        for &hpa in &[-1.0, 1260.5, ::std::f32::NAN, ::std::f32::INFINITY] {
+            assert!(!is_baro_reference(hpa), "{}", hpa);
+        }
+    }
+}
@@ -1,50 +1,300 @@
-use std::mem::transmute;
+use super::NUM_ENGINES;
+use super::crc::crc32;
+use super::log;
+use super::wire::{WireEndian, WireReader, WireWriter, SYNC, TIMESTAMP_SIZE, hex_dump};
 
-// XXX: Fields assumed to be little endian
+// Sent over the wire field by field, in order, in the configured `WireEndian`
+// (see `from_bytes`). Only the timestamp is always little endian, see
+// `wire::encode_timestamp`.
 #[repr(C)]
 #[derive(Copy, Clone)]
 pub struct ControlData {
-    // "SYNC" in ASCII. Won't appear in the body of the struct
-    // since the 5 leading bits of each field shall be zero.
+    // `wire::SYNC`, i.e. "SYNC" in ASCII. Won't appear in the controls since
+    // the 5 leading bits of each of those shall be zero, though it can in the
+    // sequence number, timestamp or checksum. See `comm::drain_frames` for why
+    // that's alright.
     sync: [u8; 4],
 
-    // 11 bits each; unsigned so at 0 control surface is down.
+    // Goes up by one with each frame the controller sends, wrapping around,
+    // so we can tell when frames have gone missing. See `wire::sequence_gap`.
+    pub sequence: u16,
+
+    // 11 bits each, unsigned, spanning the full deflection: 0 is the most
+    // negative deflection the sim's dataref is given, 2047 the most positive,
+    // and neutral is halfway between 1023 and 1024. The sign is passed straight
+    // through, so which way is "positive" is whatever the dataref means by it.
+    // See `BufferedControlData::from_external`.
     pub rudder: u16,
     pub left_aileron: u16,
     pub right_aileron: u16,
-    pub elevator: u16,
+    // The two halves of the elevator can be moved independently (e.g. for roll
+    // augmentation); a controller that doesn't care just sends the same value twice.
+    pub left_elevator: u16,
+    pub right_elevator: u16,
+
+    // 11 bit unsigned like the throttle, from retracted (0) to fully extended
+    pub flaps: u16,
+    pub spoilers: u16, // i.e. speedbrakes
 
-    // also 11 bit unsigned
-    pub throttle: u16,
-    _pad: u16, // better to be explicit
+    // Wheel brakes (both sides together), 11 bit unsigned from released to full
+    pub brake: u16,
+    // Nosewheel steering, 11 bit unsigned with the middle of the range straight ahead
+    pub nosewheel: u16,
+
+    // also 11 bit unsigned, one per engine
+    pub throttle: [u16; NUM_ENGINES],
+    // better to be explicit; pads the throttles out to a multiple of 4 bytes
+    _pad: [u16; NUM_ENGINES % 2],
 
     // Timestamp of the flight data from which the controller generated
-    // these control inputs.
-    pub time: [u8; 16],
+    // these control inputs, in the format of `wire::encode_timestamp`.
+    pub time: [u8; TIMESTAMP_SIZE],
 
-    // Sum of bytes between sync and checksum, modulo 4 bytes, all bits flipped (1's complement)
+    // CRC-32 (IEEE 802.3, see crc.rs) of the bytes between sync and checksum
     checksum: u32,
 }
 
-pub const CONTROL_DATA_SIZE: usize = 36;
+pub const CONTROL_DATA_SIZE: usize = 36 + 2 * (NUM_ENGINES + NUM_ENGINES % 2);
+
+// Why a frame failed verification
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum FrameError {
+    BadHeader,
+    BadChecksum,
+}
 
 impl ControlData {
-    pub fn verify(&self) -> bool {
-        let raw_bytes: [u8; CONTROL_DATA_SIZE] = unsafe { transmute(*self) };
-        if raw_bytes[.. 4] != *"SYNC".as_bytes() {
-            println!("[FFSim] ControlData: bad header! expected [53, 59, 4e, 43], got [{:x}, {:x}, {:x}, {:x}]",
-                     raw_bytes[0], raw_bytes[1], raw_bytes[2], raw_bytes[3]);
-            return false;
+    // Surfaces and steering centred, everything else at zero, in reply to the
+    // flight data stamped `time`. For when there's no FPGA; see loopback.rs.
+    pub fn neutral(sequence: u16, time: [u8; TIMESTAMP_SIZE], endian: WireEndian) -> Self {
+        let centre: u16 = 1 << 10; // (within a rounding error)
+
+        let mut ret = ControlData {
+            sync: SYNC,
+            sequence,
+            rudder: centre,
+            left_aileron: centre,
+            right_aileron: centre,
+            left_elevator: centre,
+            right_elevator: centre,
+            flaps: 0,
+            spoilers: 0,
+            brake: 0,
+            nosewheel: centre,
+            throttle: [0; NUM_ENGINES],
+            _pad: [0; NUM_ENGINES % 2],
+            time,
+            checksum: 0,
+        };
+        ret.checksum = crc32(&ret.to_bytes(endian)[4 .. CONTROL_DATA_SIZE - 4]);
+        ret
+    }
+
+    pub fn from_bytes(buf: &[u8; CONTROL_DATA_SIZE], endian: WireEndian) -> Self {
+        let mut input = WireReader::new(&buf[..], endian);
+
+        let mut sync = [0u8; 4];
+        input.bytes(&mut sync);
+
+        let sequence = input.u16();
+        let rudder = input.u16();
+        let left_aileron = input.u16();
+        let right_aileron = input.u16();
+        let left_elevator = input.u16();
+        let right_elevator = input.u16();
+        let flaps = input.u16();
+        let spoilers = input.u16();
+        let brake = input.u16();
+        let nosewheel = input.u16();
+
+        let mut throttle = [0u16; NUM_ENGINES];
+        for val in throttle.iter_mut() {
+            *val = input.u16();
+        }
+        // Kept as-is (rather than zeroed) since it's covered by the checksum
+        let mut _pad = [0u16; NUM_ENGINES % 2];
+        for val in _pad.iter_mut() {
+            *val = input.u16();
+        }
+
+        let mut time = [0u8; TIMESTAMP_SIZE];
+        input.bytes(&mut time);
+
+        let checksum = input.u32();
+
+        debug_assert_eq!(input.position(), CONTROL_DATA_SIZE);
+
+        ControlData {
+            sync,
+            sequence,
+            rudder,
+            left_aileron,
+            right_aileron,
+            left_elevator,
+            right_elevator,
+            flaps,
+            spoilers,
+            brake,
+            nosewheel,
+            throttle,
+            _pad,
+            time,
+            checksum,
+        }
+    }
+
+    pub fn to_bytes(&self, endian: WireEndian) -> [u8; CONTROL_DATA_SIZE] {
+        let mut buf = [0u8; CONTROL_DATA_SIZE];
+        {
+            let mut out = WireWriter::new(&mut buf, endian);
+
+            out.bytes(&self.sync);
+
+            out.u16(self.sequence);
+            out.u16(self.rudder);
+            out.u16(self.left_aileron);
+            out.u16(self.right_aileron);
+            out.u16(self.left_elevator);
+            out.u16(self.right_elevator);
+            out.u16(self.flaps);
+            out.u16(self.spoilers);
+            out.u16(self.brake);
+            out.u16(self.nosewheel);
+
+            for val in self.throttle.iter().chain(self._pad.iter()) {
+                out.u16(*val);
+            }
+
+            out.bytes(&self.time);
+            out.u32(self.checksum);
+
+            debug_assert_eq!(out.position(), CONTROL_DATA_SIZE);
+        }
+        buf
+    }
+
+    // `endian` must be what the frame was read with, since the checksum is
+    // over the bytes as they were on the wire
+    pub fn verify(&self, endian: WireEndian) -> bool {
+        self.check(endian).is_ok()
+    }
+
+    // Like `verify`, but says what was wrong
+    pub fn check(&self, endian: WireEndian) -> Result<(), FrameError> {
+        let raw_bytes = self.to_bytes(endian);
+        if raw_bytes[.. 4] != SYNC {
+            log_warn!("ControlData: bad header! expected [53, 59, 4e, 43], got [{:x}, {:x}, {:x}, {:x}]",
+                      raw_bytes[0], raw_bytes[1], raw_bytes[2], raw_bytes[3]);
+            dump_frame(&raw_bytes);
+            return Err(FrameError::BadHeader);
         }
 
-        let expected: u32 = !(raw_bytes[4 .. CONTROL_DATA_SIZE - 4].iter()
-            .fold(0u32, |sum, val| sum.wrapping_add(*val as u32)));
+        let expected: u32 = crc32(&raw_bytes[4 .. CONTROL_DATA_SIZE - 4]);
         if expected != self.checksum {
-            println!("[FFSim] ControlData: bad checksum! expected {}, got {}",
-                     expected, self.checksum);
-            return false;
+            log_warn!("ControlData: bad checksum! expected {}, got {}",
+                      expected, self.checksum);
+            dump_frame(&raw_bytes);
+            return Err(FrameError::BadChecksum);
         }
 
-        return true;
+        Ok(())
+    }
+}
+
+// The whole frame is a lot of output at our packet rates, so only at debug level
+fn dump_frame(raw_bytes: &[u8; CONTROL_DATA_SIZE]) {
+    // (checked up front so we don't format the dump just to throw it away)
+    if log::enabled(log::Level::Debug) {
+        log_debug!("ControlData: frame was [{}]", hex_dump(&raw_bytes[..]));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Every field different, so a swapped or misplaced one shows up
+    fn distinct(endian: WireEndian) -> ControlData {
+        let mut data = ControlData::neutral(0xBEEF, [1, 2, 3, 4, 5, 6, 7, 8], endian);
+        data.rudder = 1;
+        data.left_aileron = 2;
+        data.right_aileron = 3;
+        data.left_elevator = 4;
+        data.right_elevator = 5;
+        data.flaps = 6;
+        data.spoilers = 7;
+        data.brake = 8;
+        data.nosewheel = 9;
+        for (i, val) in data.throttle.iter_mut().enumerate() {
+            *val = 2047 - i as u16;
+        }
+        data.checksum = crc32(&data.to_bytes(endian)[4 .. CONTROL_DATA_SIZE - 4]);
+        data
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn round_trip_in_both_endians() {
+        for &endian in &[WireEndian::Little, WireEndian::Big] {
+            let sent = distinct(endian);
+            let bytes = sent.to_bytes(endian);
+            let received = ControlData::from_bytes(&bytes, endian);
+
+            assert_eq!(received.check(endian), Ok(()));
+            assert_eq!(received.sequence, 0xBEEF);
+            assert_eq!([received.rudder, received.left_aileron, received.right_aileron,
+                        received.left_elevator, received.right_elevator, received.flaps,
+                        received.spoilers, received.brake, received.nosewheel],
+                       [1, 2, 3, 4, 5, 6, 7, 8, 9]);
+            assert_eq!(received.throttle, sent.throttle);
+            assert_eq!(received.time, [1, 2, 3, 4, 5, 6, 7, 8]);
+            assert_eq!(received.checksum, sent.checksum);
+            assert_eq!(&received.to_bytes(endian)[..], &bytes[..]);
+        }
+    }
+
+    #[test]
+    fn endianness_is_on_the_wire() {
+        let little = distinct(WireEndian::Little).to_bytes(WireEndian::Little);
+        let big = distinct(WireEndian::Big).to_bytes(WireEndian::Big);
+        assert_eq!(little[.. 4], SYNC);
+        assert_eq!(big[.. 4], SYNC);
+        assert_eq!(little[4 .. 6], [0xEF, 0xBE]);
+        assert_eq!(big[4 .. 6], [0xBE, 0xEF]);
+
+        // Read with the wrong endianness it's garbage, and the checksum says so
+        let misread = ControlData::from_bytes(&little, WireEndian::Big);
+        assert_eq!(misread.check(WireEndian::Big), Err(FrameError::BadChecksum));
+    }
+
+    #[test]
+    fn single_bit_flip_is_rejected() {
+        let endian = WireEndian::Little;
+        let bytes = ControlData::neutral(42, [1, 2, 3, 4, 5, 6, 7, 8], endian).to_bytes(endian);
+        assert_eq!(ControlData::from_bytes(&bytes, endian).check(endian), Ok(()));
+
+        // Anywhere after the header, including the checksum itself
+        for bit in 8 * SYNC.len() .. 8 * CONTROL_DATA_SIZE {
+            let mut flipped = bytes;
+            flipped[bit / 8] ^= 1 << (bit % 8);
+            assert_eq!(ControlData::from_bytes(&flipped, endian).check(endian),
+                       Err(FrameError::BadChecksum), "bit {}", bit);
+        }
+    }
+
+    #[test]
+    fn bad_header_is_rejected() {
+        let endian = WireEndian::Little;
+        let mut bytes = ControlData::neutral(0, [0; TIMESTAMP_SIZE], endian).to_bytes(endian);
+        bytes[0] = b'X';
+        assert_eq!(ControlData::from_bytes(&bytes, endian).check(endian), Err(FrameError::BadHeader));
+    }
+
+    // Catches a field added without updating CONTROL_DATA_SIZE, or wrong padding
+    #[test]
+    fn wire_size() {
+        let endian = WireEndian::Little;
+        let bytes = ControlData::neutral(0, [0; TIMESTAMP_SIZE], endian).to_bytes(endian);
+        assert_eq!(bytes.len(), CONTROL_DATA_SIZE);
+        assert_eq!(::std::mem::size_of::<ControlData>(), CONTROL_DATA_SIZE);
+    }
+}
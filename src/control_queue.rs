@@ -0,0 +1,86 @@
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender, TrySendError};
+use triple_buffer::{TripleBuffer, Input, Output};
+
+use super::BufferedControlData;
+
+// How control data gets from the receive thread to the flight loop.
+//
+// By default that's a triple buffer, which only ever holds the latest
+// commands: if the controller sends faster than the flight loop runs, the ones
+// in between are simply overwritten. With FFSIM_CONTROL_QUEUE set they go
+// through a bounded queue instead, which the flight loop empties every time it
+// runs. It still applies only the last, but every one of them is seen (and
+// logged at debug level), in the order they arrived.
+pub enum ControlSender {
+    Latest(Input<BufferedControlData>),
+    Queue {
+        queue: SyncSender<BufferedControlData>,
+        dropped: u64,
+    },
+}
+
+pub enum ControlReceiver {
+    Latest(Output<BufferedControlData>),
+    Queue {
+        queue: Receiver<BufferedControlData>,
+        // Given back until something newer arrives
+        last: BufferedControlData,
+    },
+}
+
+// A triple buffer if `queue_len` is 0, otherwise a queue of that many
+pub fn control_channel(queue_len: usize) -> (ControlSender, ControlReceiver) {
+    if queue_len == 0 {
+        let (input, output) = TripleBuffer::new(BufferedControlData::new()).split();
+        return (ControlSender::Latest(input), ControlReceiver::Latest(output));
+    }
+
+    let (queue, receiver) = sync_channel(queue_len);
+    (ControlSender::Queue { queue, dropped: 0 },
+     ControlReceiver::Queue { queue: receiver, last: BufferedControlData::new() })
+}
+
+impl ControlSender {
+    // Never blocks. If the queue is full the flight loop has stopped emptying
+    // it (X-Plane is paused, say), so `data` is dropped.
+    pub fn write(&mut self, data: BufferedControlData) {
+        match *self {
+            ControlSender::Latest(ref mut input) => input.write(data),
+            ControlSender::Queue { ref queue, ref mut dropped } => match queue.try_send(data) {
+                Ok(_) => {
+                    if *dropped > 0 {
+                        log_warn!("Control queue has room again, dropped {} commands", *dropped);
+                        *dropped = 0;
+                    }
+                }
+                Err(TrySendError::Full(_)) => {
+                    if *dropped == 0 {
+                        log_warn!("Control queue is full, dropping commands");
+                    }
+                    *dropped += 1;
+                }
+                // The plugin is stopping
+                Err(TrySendError::Disconnected(_)) => (),
+            },
+        }
+    }
+}
+
+impl ControlReceiver {
+    // The latest control data
+    pub fn read(&mut self) -> BufferedControlData {
+        match *self {
+            ControlReceiver::Latest(ref mut output) => *output.read(),
+            ControlReceiver::Queue { ref queue, ref mut last } => {
+                let mut pending = queue.try_iter().peekable();
+                while let Some(data) = pending.next() {
+                    if pending.peek().is_some() {
+                        log_debug!("Superseded by a later command in the same frame: {:?}", data);
+                    }
+                    *last = data;
+                }
+                *last
+            }
+        }
+    }
+}
@@ -0,0 +1,43 @@
+// CRC-32 as used by Ethernet, zlib etc. (IEEE 802.3, reflected polynomial
+// 0xEDB88320, initial value and final XOR of all ones). Check value for the
+// ASCII string "123456789" is 0xCBF43926.
+//
+// The frames we checksum are only a few dozen bytes long, so we compute it
+// bit-by-bit rather than bothering with a lookup table.
+pub fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc: u32 = !0;
+    for byte in bytes {
+        crc ^= *byte as u32;
+        for _ in 0 .. 8 {
+            let mask = (!(crc & 1)).wrapping_add(1); // all ones iff the low bit is set
+            crc = (crc >> 1) ^ (0xEDB8_8320 & mask);
+        }
+    }
+    !crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn check_value() {
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+    }
+
+    #[test]
+    fn empty_input() {
+        assert_eq!(crc32(b""), 0);
+    }
+
+    #[test]
+    fn every_single_bit_flip_changes_it() {
+        let data = *b"SYNC and some payload";
+        let expected = crc32(&data);
+        for bit in 0 .. data.len() * 8 {
+            let mut flipped = data;
+            flipped[bit / 8] ^= 1 << (bit % 8);
+            assert_ne!(crc32(&flipped), expected, "bit {}", bit);
+        }
+    }
+}
@@ -0,0 +1,60 @@
+use std::error::Error;
+use std::ffi::NulError;
+use std::fmt;
+
+use xplm::command::CommandCreateError;
+use xplm::data::borrowed::FindError;
+use xplm::data::owned::CreateError;
+
+// Anything that can go wrong in `FFSim::start()`
+#[derive(Debug)]
+pub enum StartError {
+    // A dataref we need doesn't exist (or isn't writeable)
+    Find(FindError),
+    // Couldn't publish one of our own datarefs
+    Create(CreateError),
+    // Couldn't register one of our commands
+    Command(CommandCreateError),
+    // Something wrong with a configuration file
+    Config(String),
+    // Couldn't build our menu
+    Menu(NulError),
+}
+
+impl fmt::Display for StartError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            StartError::Find(ref e) => write!(f, "couldn't find dataref: {}", e),
+            StartError::Create(ref e) => write!(f, "couldn't create dataref: {}", e),
+            StartError::Command(ref e) => write!(f, "couldn't create command: {}", e),
+            StartError::Config(ref e) => write!(f, "bad configuration: {}", e),
+            StartError::Menu(ref e) => write!(f, "couldn't create menu: {}", e),
+        }
+    }
+}
+
+impl Error for StartError {}
+
+impl From<FindError> for StartError {
+    fn from(e: FindError) -> Self {
+        StartError::Find(e)
+    }
+}
+
+impl From<CreateError> for StartError {
+    fn from(e: CreateError) -> Self {
+        StartError::Create(e)
+    }
+}
+
+impl From<CommandCreateError> for StartError {
+    fn from(e: CommandCreateError) -> Self {
+        StartError::Command(e)
+    }
+}
+
+impl From<NulError> for StartError {
+    fn from(e: NulError) -> Self {
+        StartError::Menu(e)
+    }
+}
@@ -1,19 +1,36 @@
 use std::f32::consts::PI;
-use std::ops::BitXor;
-use std::mem::transmute;
-use std::time::UNIX_EPOCH;
+use std::fmt;
 
 use super::BufferedFlightData;
 use super::Quaternion;
-
-// XXX: Fields assumed to be little endian
+use super::config::Config;
+use super::crc::crc32;
+use super::nmea;
+use super::nmea::NMEA_BUFFER_SIZE;
+use super::wire::{WireEndian, WireWriter, SYNC, PROTOCOL_VERSION, TIMESTAMP_SIZE, decode_timestamp, encode_timestamp};
+
+// Sent over the wire field by field, in order, in the configured `WireEndian`
+// (see `to_bytes`). Only the timestamp is always little endian, see
+// `wire::encode_timestamp`.
+// The explicit padding keeps the in-memory layout identical to the wire layout.
 #[repr(C)]
 #[derive(Copy, Clone)]
 pub struct FlightData {
-    // "SYNC" in ASCII. Not _guaranteed_ be exclusive with some
-    // subset of the fields below, but pretty unlikely.
+    // `wire::SYNC`, so the FPGA can frame the telemetry stream the same way we
+    // frame ControlData. Unlike ControlData we can't keep it out of the fields
+    // below (they use all their bits), so a receiver that locks onto a "SYNC"
+    // inside the payload must rely on the checksum to reject the frame and
+    // search again from the next byte.
     sync: [u8; 4],
 
+    // `wire::PROTOCOL_VERSION`, then a reserved (zero) byte to keep the
+    // sequence number aligned
+    version: u8,
+    _reserved: u8,
+    // Goes up by one with each frame sent, wrapping around, so the FPGA can
+    // tell when frames have gone missing. See `wire::sequence_gap`.
+    sequence: u16,
+
     // These are sent to allow debugging of the flight controller.
     // They SHOULD NOT be used to test a production system, since
     // they don't correspond to any physical measurement our actual
@@ -30,17 +47,17 @@ pub struct FlightData {
     // lsm6dsm: Outputs are in 2's complement, 16 bits
     // Units: X milli-dps / least-significant-bit,
     //        depending on Full Scale representation.
-    //        (See data sheet, we're using PM 2000 dps)
+    //        (See data sheet, PM 2000 dps by default, see imu.rs)
     roll_rate: i16,
     pitch_rate: i16,
     yaw_rate: i16,
 
-    // Sim. to above, same datasheet, PM 8G
+    // Sim. to above, same datasheet, PM 8G by default
     lin_acc_x: i16,
     lin_acc_y: i16,
     lin_acc_z: i16,
 
-    // en.DM00075867, sim to above .. PM 4 gauss
+    // en.DM00075867, sim to above .. PM 4 gauss by default
     mag_x: i16,
     mag_y: i16,
     mag_z: i16,
@@ -52,165 +69,773 @@ pub struct FlightData {
     // XXX: Technically this is 2's complement 24-bits,
     // but on default settings (i.e. if you don't mess
     // with the reference pressure) a negative output
-    // should be impossible.
+    // should be impossible. With a reference pressure
+    // (`config.baro_reference_hpa`, like the chip's REF_P
+    // register) it's the difference, which can be negative.
 
     // Sensirion_Differential_Pressure_Sensors_SDP3x_Digital_Datasheet
     // 60 or 240 Pa/LSB for 31 and 32 resp. Probably 32.
     airspeed_pressure: i16,
 
-    // GPS in NMEA
-    gps: [u8; 82],
+    // True airspeed straight from the sim, 0.1 m/s per LSB. Like roll etc.
+    // above this isn't something the aircraft can measure directly, but some
+    // controllers would rather not derive it from `airspeed_pressure` themselves.
+    // (At sea level on a standard day the two agree.)
+    true_airspeed: i16,
+
+    // Angle of attack and sideslip, also straight from the sim (an air data
+    // probe would give us these). 0.01 degrees per LSB.
+    alpha: i16,
+    beta: i16,
+
+    // Altitude above mean sea level in centimetres (signed, since you can fly
+    // below sea level), and vertical speed in 0.01 m/s per LSB, up positive.
+    // Both straight from the sim, so the controller doesn't have to integrate
+    // the accelerometer or convert the barometer reading.
+    altitude: i32,
+    vertical_speed: i16,
+
+    // Wind at the aircraft, for controllers that compensate for it. Speed is
+    // 0.1 m/s per LSB; direction is where the wind is blowing from, in 0.01
+    // degrees magnetic (like `yaw`), 0 to 35999.
+    wind_speed: u16,
+    wind_direction: u16,
+
+    // Normal load factor (Nz), 0.001 g per LSB: -z / g of the same body frame
+    // acceleration as lin_acc_*, so -9.81 m/s^2 along z reads as 1000. Unlike
+    // lin_acc_* this has no calibration applied and no sensor range to hit.
+    load_factor: i16,
+
+    // Where the control surfaces actually are in the sim, read back at the
+    // start of the frame (i.e. after last frame's commands were applied), in
+    // 0.01 degrees per LSB. i16::MIN for a surface the aircraft hasn't got.
+    actual_rudder: i16,
+    actual_left_aileron: i16,
+    actual_right_aileron: i16,
+    actual_left_elevator: i16,
+    actual_right_elevator: i16,
+
+    // GPS in NMEA; the configured sentences back to back, CRLF terminated,
+    // zero-filled after the last one
+    // (NMEA_BUFFER_SIZE is a multiple of 4 plus 2, so `time` and `checksum` stay aligned)
+    gps: [u8; NMEA_BUFFER_SIZE],
 
     // Directly from BufferedFlightData. Controller treats this as a black box.
-    time: [u8; 16],
+    // See `wire::encode_timestamp` for the format.
+    time: [u8; TIMESTAMP_SIZE],
 
-    // Sum of bytes between sync and checksum, modulo 4 bytes, all bits flipped (1's complement)
+    // CRC-32 (IEEE 802.3, see crc.rs) of the bytes between sync and checksum,
+    // i.e. bytes 4 .. FLIGHT_DATA_SIZE - 4 of the frame. Same as ControlData.
     checksum: u32
 }
 
-pub const FLIGHT_DATA_SIZE: usize = 140;
+pub const FLIGHT_DATA_SIZE: usize = 82 + NMEA_BUFFER_SIZE;
 
 impl FlightData {
-    pub fn new(bfd: BufferedFlightData) -> Self {
+    // Hand-worked values for the conversions below, with the default IMU
+    // ranges and no calibration, mounting or smoothing, as checked by the tests
+    // at the bottom of this file. (Float to integer casts truncate towards zero.)
+    //
+    //     input                               field               LSB
+    //     10 deg/s roll rate                  roll_rate           142
+    //     1 g (9.81 m/s^2) along x            lin_acc_x          4102
+    //     field of 0.45 gauss along z         mag_z              3078
+    //     15 deg C                            temp               3840
+    //     1013.25 hPa (29.9212 inHg) static   barometer       4150272
+    //     100 KIAS, sea level (1.225 kg/m^3)  airspeed_pressure     6   (1621 Pa)
+    //     51.4 m/s true airspeed              true_airspeed       514
+    //     2.5 deg angle of attack             alpha               250
+    //     1000.5 m above sea level            altitude         100050
+    //
+    // If a change moves any of these, the FPGA side has to change with it.
+    //
+    // `saturation` remembers which readings are pinned at the ends of their
+    // range from one frame to the next; keep the same one for every frame.
+    pub fn new(bfd: BufferedFlightData, sequence: u16, config: &Config,
+               saturation: &mut Saturation) -> Self {
         /* See comments on `FlightData` for info about conversions */
-        let angular_rate_conversion: f32 = 1000f32 / 70f32;
+        let imu = &config.imu;
+        let angular_rate_conversion: f32 = 1000f32 / imu.gyro_mdps_per_lsb;
 
         let temperature_conversion: f32 = 256f32;
         let temperature_offset: f32 = 0f32; // XXX: configurable via IMU registers, deg C, PM 15
 
         // I would support nuking the U.S. if it means we get rid of imperial units,
-        let inhg_to_hpa: f32 = 338.639f32;
+        let inhg_to_hpa: f32 = 33.8639f32;
         let barometer_conversion: f32 = inhg_to_hpa * 4096f32;
+        // (0 unless configured, which leaves the absolute pressure)
+        let barometer_reference: f32 = config.baro_reference_hpa / inhg_to_hpa;
 
         let knots_to_ms: f32 = 0.5144447f32;
         let kias_to_pa = |kias: f32| -> f32 {
             (bfd.air_density * (kias * knots_to_ms) * (kias * knots_to_ms)) / 2f32
         };
+        // Indicated airspeed is by definition what gives the measured dynamic
+        // pressure at sea level density, so the above is what a pitot tube would
+        // see (up to X-Plane's instrument errors). The same pressure can also be
+        // had from the true airspeed and the density where the aircraft actually
+        // is, which doesn't depend on the airspeed indicator at all.
+        let tas_to_pa = |tas: f32| -> f32 {
+            (bfd.local_air_density * tas * tas) / 2f32
+        };
+        let dynamic_pressure = if config.local_air_density {
+            tas_to_pa(bfd.true_airspeed)
+        } else {
+            kias_to_pa(bfd.indicated_airspeed)
+        };
         let airspeed_pressure_conversion: f32 = 1f32 / 240f32;
+        let true_airspeed_conversion: f32 = 10f32; // m/s -> LSB
+        let air_angle_conversion: f32 = 100f32; // degrees -> LSB
+        let altitude_conversion: f64 = 100f64; // m -> cm
+        let vertical_speed_conversion: f32 = 100f32; // m/s -> LSB
+        let wind_speed_conversion: f32 = knots_to_ms * 10f32; // knots -> LSB
+        let wind_direction_conversion: f32 = 100f32; // degrees -> LSB
+        let wind_direction = {
+            let dir = bfd.wind_direction % 360f32;
+            if dir < 0f32 { dir + 360f32 } else { dir }
+        };
 
         // Polar coordinate angles of B field vector relative to aircraft
         // (negated since theta/psi were aircraft relative to magnetic field)
         // I would also support bombing the engineering building to get rid of angles in degrees
         let mag_theta: f32 = - bfd.true_theta * PI / 180f32;
-        let mag_psi: f32 = - bfd.mag_psi * PI / 180f32;
+        // Extra declination (east positive) on top of whatever the sim models.
+        // Moving magnetic north east by D degrees takes D off the magnetic heading.
+        let mag_psi: f32 = - (bfd.mag_psi - config.mag_declination_deg) * PI / 180f32;
         // standard conversion to cartesian coordinates
         let norm_mag_x: f32 = mag_theta.sin() * mag_psi.cos();
-        let norm_mag_y: f32 = mag_theta.sin() * mag_psi.cos();
+        let norm_mag_y: f32 = mag_theta.sin() * mag_psi.sin();
         let norm_mag_z: f32 = mag_theta.cos();
-        // this is a lie but I don't think we have actual field strength from the sim
-        let mag_field_str: f32 = 0.45f32; // in gauss for ease of conversion
-        let mag_field_str_conversion: f32 = 6842f32;
+        // The sim doesn't tell us the field strength, so it's configurable
+        let mag_field_str: f32 = config.mag_field_gauss; // in gauss for ease of conversion
+        let mag_field_str_conversion: f32 = imu.mag_lsb_per_gauss;
+        let mag = [norm_mag_x * mag_field_str, norm_mag_y * mag_field_str, norm_mag_z * mag_field_str];
 
         // The quaternion is from OpenGL coordinates to the plane's, so
         // we invert (conjugate) it, and then rotate the acceleration
-        // in OpenGL coordinates.
+        // in OpenGL coordinates. The sim's quaternion drifts slightly off
+        // unit length, so normalize it first.
         // (Units remain m/s^2 since the quaternion is only a rotation)
         let lin_acc = Quaternion::new([
             bfd.plane_orientation_quaternion[0],
             bfd.plane_orientation_quaternion[1],
             bfd.plane_orientation_quaternion[2],
             bfd.plane_orientation_quaternion[3],
-        ]).conj().rotate([bfd.local_ax, bfd.local_ay, bfd.local_az]);
+        ]).normalize().conj().rotate([bfd.local_ax, bfd.local_ay, bfd.local_az]);
         let acc_conversion: f32 = (1f32 / 9.8f32)  // m/s^2 -> g
             * 1000f32 // g -> mg
-            * (1f32 / 0.244f32); // mg -> LSB
-
-        let mut sync: [u8; 4] = [0; 4];
-        sync.copy_from_slice("SYNC".as_bytes());
-
-        let time = bfd.time.duration_since(UNIX_EPOCH).unwrap();
+            * (1f32 / imu.accel_mg_per_lsb); // mg -> LSB
+        let load_factor = - lin_acc[2] / 9.81f32;
+        let load_factor_conversion: f32 = 1000f32; // g -> LSB
+        // (saturating, so the largest deflections can't turn into "missing")
+        let actual_surface = |deg: Option<f32>| -> i16 {
+            match deg {
+                Some(deg) => ((deg * air_angle_conversion) as i16).max(i16::min_value() + 1),
+                None => i16::min_value(),
+            }
+        };
 
+        // If the sensors aren't lined up with the aircraft's axes, rotate what
+        // they'd measure into their own frame. (Load factor is the aircraft's,
+        // so it's left out of this.)
+        let mounting = config.imu_mounting.conj();
+        let rates = mounting.rotate([bfd.roll_rate, bfd.pitch_rate, bfd.yaw_rate]);
+        let sensor_acc = mounting.rotate(lin_acc);
+        let mag = mounting.rotate(mag);
+
+        // Per-axis corrections (identity unless configured) go on before any of
+        // the IMU readings are converted, so they're in the sim's units
+        let cal = &config.calibration;
+
+        // N.B. Float to integer casts saturate at the bounds of the integer type
+        //      (and NaN becomes 0), so nothing below can wrap around. `bfd` should
+        //      have been through `BufferedFlightData::sanitized` anyway. The IMU
+        //      readings go through `imu_reading` so that hitting the end of the
+        //      sensor's range gets logged rather than passing silently.
         let mut ret = FlightData {
-            sync,
+            sync: SYNC,
+            version: PROTOCOL_VERSION,
+            _reserved: 0,
+            sequence,
 
             roll: (bfd.true_phi * angular_rate_conversion) as i16,
             pitch: (bfd.true_theta * angular_rate_conversion) as i16,
             yaw: (bfd.mag_psi * angular_rate_conversion) as i16,
             _pad: 0,
 
-            roll_rate: (bfd.roll_rate * angular_rate_conversion) as i16,
-            pitch_rate: (bfd.pitch_rate * angular_rate_conversion) as i16,
-            yaw_rate: (bfd.yaw_rate * angular_rate_conversion) as i16,
-
-            lin_acc_x: (lin_acc[0] * acc_conversion) as i16,
-            lin_acc_y: (lin_acc[1] * acc_conversion) as i16,
-            lin_acc_z: (lin_acc[2] * acc_conversion) as i16,
-
-            mag_x: (norm_mag_x * mag_field_str * mag_field_str_conversion) as i16,
-            mag_y: (norm_mag_y * mag_field_str * mag_field_str_conversion) as i16,
-            mag_z: (norm_mag_z * mag_field_str * mag_field_str_conversion) as i16,
+            roll_rate: imu_reading("roll_rate",
+                                   cal.roll_rate.apply(rates[0]) * angular_rate_conversion,
+                                   saturation),
+            pitch_rate: imu_reading("pitch_rate",
+                                    cal.pitch_rate.apply(rates[1]) * angular_rate_conversion,
+                                    saturation),
+            yaw_rate: imu_reading("yaw_rate",
+                                  cal.yaw_rate.apply(rates[2]) * angular_rate_conversion,
+                                  saturation),
+
+            lin_acc_x: imu_reading("lin_acc_x", cal.accel[0].apply(sensor_acc[0]) * acc_conversion,
+                                   saturation),
+            lin_acc_y: imu_reading("lin_acc_y", cal.accel[1].apply(sensor_acc[1]) * acc_conversion,
+                                   saturation),
+            lin_acc_z: imu_reading("lin_acc_z", cal.accel[2].apply(sensor_acc[2]) * acc_conversion,
+                                   saturation),
+
+            mag_x: imu_reading("mag_x", cal.mag[0].apply(mag[0]) * mag_field_str_conversion,
+                               saturation),
+            mag_y: imu_reading("mag_y", cal.mag[1].apply(mag[1]) * mag_field_str_conversion,
+                               saturation),
+            mag_z: imu_reading("mag_z", cal.mag[2].apply(mag[2]) * mag_field_str_conversion,
+                               saturation),
 
             temp: ((bfd.ambient_temp + temperature_offset) * temperature_conversion) as i16,
-            barometer: (bfd.barometer_inhg * barometer_conversion) as u32,
-            airspeed_pressure: (kias_to_pa(bfd.indicated_airspeed)
-                * airspeed_pressure_conversion) as i16,
-
-            gps: Self::conv_to_nmea(bfd.latitude, bfd.longitude),
-            time: unsafe { transmute(time) },
+            // (via i32 so that a pressure below the reference comes out as
+            // two's complement)
+            barometer: barometer_reading((bfd.barometer_inhg - barometer_reference) * barometer_conversion,
+                                         saturation),
+            airspeed_pressure: (dynamic_pressure * airspeed_pressure_conversion) as i16,
+            true_airspeed: (bfd.true_airspeed * true_airspeed_conversion) as i16,
+            alpha: (bfd.alpha * air_angle_conversion) as i16,
+            beta: (bfd.beta * air_angle_conversion) as i16,
+            altitude: (bfd.elevation * altitude_conversion) as i32,
+            vertical_speed: (bfd.vertical_speed * vertical_speed_conversion) as i16,
+            wind_speed: (bfd.wind_speed * wind_speed_conversion) as u16,
+            // (min() since 359.999 would otherwise round up to a full circle)
+            wind_direction: ((wind_direction * wind_direction_conversion) as u16).min(35999),
+            load_factor: (load_factor * load_factor_conversion) as i16,
+            actual_rudder: actual_surface(bfd.actual_rudder),
+            actual_left_aileron: actual_surface(bfd.actual_left_aileron),
+            actual_right_aileron: actual_surface(bfd.actual_right_aileron),
+            actual_left_elevator: actual_surface(bfd.actual_left_elevator),
+            actual_right_elevator: actual_surface(bfd.actual_right_elevator),
+
+            gps: nmea::build(&config.nmea_sentences, &config.nmea_talker, &bfd),
+            time: encode_timestamp(bfd.time),
             checksum: 0,
         };
 
-        let checksum: u32 = {
-            let raw_bytes: [u8; FLIGHT_DATA_SIZE] = unsafe { transmute(ret) };
-            !(raw_bytes[4 .. FLIGHT_DATA_SIZE - 4].iter()
-                .fold(0u32, |sum, val| sum.wrapping_add(*val as u32)))
-        };
-        ret.checksum = checksum;
+        ret.checksum = crc32(&ret.to_bytes(config.wire_endian)[4 .. FLIGHT_DATA_SIZE - 4]);
 
         ret
     }
 
-    // XXX: There are other NMEA formats we could send,
-    //      but for simplicity we'll just send global
-    //      position data.
-    fn conv_to_nmea(lat: f64, long: f64) -> [u8; 82] {
-        let mut res = String::new();
-
-        // header
-        res.push_str("$");
-        res.push_str("GL");  // GLORY TO THE MOTHERLAND
-        res.push_str("GLL"); // Latitude/Longitude info
-        res.push_str(",");
-
-        // latitude
-        res.push_str(format!("{:.2}", lat.abs()).as_str()); // abs lat to 2dp
-        res.push_str(",");
-        // sign according to ISO-6709 (hopefully)
-        if lat.is_sign_positive() {
-            res.push_str("N");
-        } else {
-            res.push_str("S");
+    pub fn to_bytes(&self, endian: WireEndian) -> [u8; FLIGHT_DATA_SIZE] {
+        let mut buf = [0u8; FLIGHT_DATA_SIZE];
+        {
+            let mut out = WireWriter::new(&mut buf, endian);
+
+            out.bytes(&self.sync);
+            out.bytes(&[self.version, self._reserved]);
+            out.u16(self.sequence);
+
+            out.i16(self.roll);
+            out.i16(self.pitch);
+            out.i16(self.yaw);
+            out.i16(self._pad);
+
+            out.i16(self.roll_rate);
+            out.i16(self.pitch_rate);
+            out.i16(self.yaw_rate);
+
+            out.i16(self.lin_acc_x);
+            out.i16(self.lin_acc_y);
+            out.i16(self.lin_acc_z);
+
+            out.i16(self.mag_x);
+            out.i16(self.mag_y);
+            out.i16(self.mag_z);
+            out.i16(self.temp);
+
+            out.u32(self.barometer);
+            out.i16(self.airspeed_pressure);
+            out.i16(self.true_airspeed);
+            out.i16(self.alpha);
+            out.i16(self.beta);
+            out.i32(self.altitude);
+            out.i16(self.vertical_speed);
+            out.u16(self.wind_speed);
+            out.u16(self.wind_direction);
+            out.i16(self.load_factor);
+            out.i16(self.actual_rudder);
+            out.i16(self.actual_left_aileron);
+            out.i16(self.actual_right_aileron);
+            out.i16(self.actual_left_elevator);
+            out.i16(self.actual_right_elevator);
+
+            out.bytes(&self.gps);
+            out.bytes(&self.time);
+
+            out.u32(self.checksum);
+
+            debug_assert_eq!(out.position(), FLIGHT_DATA_SIZE);
         }
-        res.push_str(",");
+        buf
+    }
+}
 
-        // longitude
-        res.push_str(format!("{:.2}", long.abs()).as_str());
-        res.push_str(",");
-        if long.is_sign_positive() {
-            res.push_str("E");
-        } else {
-            res.push_str("W");
+// Shows the fields as they go on the wire (the raw integers, not the readings
+// they stand for), e.g. for checking what was sent when debugging. The GPS
+// field is shown as the sentences it holds.
+impl fmt::Debug for FlightData {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let gps_len = self.gps.iter().position(|byte| *byte == 0).unwrap_or(self.gps.len());
+        let gps = String::from_utf8_lossy(&self.gps[.. gps_len]);
+
+        f.debug_struct("FlightData")
+            .field("version", &self.version)
+            .field("sequence", &self.sequence)
+            .field("roll", &self.roll)
+            .field("pitch", &self.pitch)
+            .field("yaw", &self.yaw)
+            .field("roll_rate", &self.roll_rate)
+            .field("pitch_rate", &self.pitch_rate)
+            .field("yaw_rate", &self.yaw_rate)
+            .field("lin_acc_x", &self.lin_acc_x)
+            .field("lin_acc_y", &self.lin_acc_y)
+            .field("lin_acc_z", &self.lin_acc_z)
+            .field("mag_x", &self.mag_x)
+            .field("mag_y", &self.mag_y)
+            .field("mag_z", &self.mag_z)
+            .field("temp", &self.temp)
+            .field("barometer", &self.barometer)
+            .field("airspeed_pressure", &self.airspeed_pressure)
+            .field("true_airspeed", &self.true_airspeed)
+            .field("alpha", &self.alpha)
+            .field("beta", &self.beta)
+            .field("altitude", &self.altitude)
+            .field("vertical_speed", &self.vertical_speed)
+            .field("wind_speed", &self.wind_speed)
+            .field("wind_direction", &self.wind_direction)
+            .field("load_factor", &self.load_factor)
+            .field("actual_rudder", &self.actual_rudder)
+            .field("actual_left_aileron", &self.actual_left_aileron)
+            .field("actual_right_aileron", &self.actual_right_aileron)
+            .field("actual_left_elevator", &self.actual_left_elevator)
+            .field("actual_right_elevator", &self.actual_right_elevator)
+            .field("gps", &gps)
+            .field("time", &decode_timestamp(&self.time))
+            .field("checksum", &format_args!("{:08x}", self.checksum))
+            .finish()
+    }
+}
+
+// Which readings were pinned at the end of their range as of the last frame.
+// A sustained manoeuvre can keep one there for many seconds, so we log when a
+// reading gets pinned and when it comes back, not every frame in between.
+pub struct Saturation {
+    pinned: Vec<&'static str>,
+}
+
+impl Saturation {
+    pub fn new() -> Self {
+        Saturation {
+            pinned: Vec::new(),
         }
+    }
+
+    pub fn is_pinned(&self, name: &str) -> bool {
+        self.pinned.contains(&name)
+    }
+
+    // `sent` is what went out instead of `lsb`, or None if `lsb` was in range
+    fn update(&mut self, name: &'static str, lsb: f32, sent: Option<i64>) {
+        match (sent, self.is_pinned(name)) {
+            (Some(sent), false) => {
+                log_warn!("{} out of sensor range ({} LSB), sending {} until it's back",
+                          name, lsb, sent);
+                self.pinned.push(name);
+            }
+            (None, true) => {
+                log_info!("{} back within sensor range", name);
+                self.pinned.retain(|pinned| *pinned != name);
+            }
+            _ => (),
+        }
+    }
+}
 
-        /* We're not bothering with the time of the fix for now
-           since the FPGA and flightsim don't synchronize their
-           clocks anyway. */
+// Converts a gyro, accelerometer or magnetometer reading (already in LSB) to
+// what the sensor would output. Like the real thing it pins at the ends of its
+// range, but we also complain, since the controller can't tell a pinned
+// reading from a real one.
+fn imu_reading(name: &'static str, lsb: f32, saturation: &mut Saturation) -> i16 {
+    let min = i16::min_value() as f32;
+    let max = i16::max_value() as f32;
+    if lsb >= min && lsb <= max {
+        saturation.update(name, lsb, None);
+        return lsb as i16;
+    }
 
-        // checksum
-        res.push_str("*");
-        let check: u8 = res[1 .. res.len()-1] // the $ and * aren't part of the checksum
-            .as_bytes().iter()
-            // checksum is XOR of all elements
-            .fold(0u8, |tot, val| tot.bitxor(*val));
+    let saturated = if lsb > max {
+        i16::max_value()
+    } else if lsb < min {
+        i16::min_value()
+    } else {
+        0 // NaN
+    };
+    saturation.update(name, lsb, Some(saturated as i64));
+    saturated
+}
 
-        res.push_str(format!("{:02X}", check).as_str()); // format as 2 hex digits
+// Likewise for the barometer, whose output is 24 bits of two's complement
+// (it goes negative below the reference pressure, if there is one). That's
+// sign extended to fill the 32-bit field.
+fn barometer_reading(lsb: f32, saturation: &mut Saturation) -> u32 {
+    let min = -(1 << 23) as f32;
+    let max = ((1 << 23) - 1) as f32;
+    if lsb >= min && lsb <= max {
+        saturation.update("barometer", lsb, None);
+        return lsb as i32 as u32;
+    }
 
-        // CRLF indicates end of string
-        res.push_str("\r\n");
+    let saturated = if lsb > max {
+        max as i32
+    } else if lsb < min {
+        min as i32
+    } else {
+        0 // NaN
+    };
+    saturation.update("barometer", lsb, Some(saturated as i64));
+    saturated as u32
+}
 
-        let mut ret: [u8; 82] = [0u8; 82];
-        let res_slice = res.as_bytes();
-        for i in 0 .. res_slice.len() { ret[i] = res_slice[i]; }
-        ret
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::imu::ImuScales;
+    use super::super::wire::{WireReader, sequence_gap};
+
+    // Level and at rest on a standard day: 15 deg C, 1013.25 hPa and sea
+    // level density
+    fn standard_day() -> BufferedFlightData {
+        let mut bfd = BufferedFlightData::new();
+        bfd.plane_orientation_quaternion = [1.0, 0.0, 0.0, 0.0];
+        bfd.ambient_temp = 15.0;
+        bfd.barometer_inhg = 1013.25 / 33.8639;
+        bfd.air_density = 1.225;
+        bfd.local_air_density = 1.225;
+        bfd
+    }
+
+    // Within one LSB, since the float arithmetic needn't match the hand-worked
+    // values to the last bit
+    fn assert_near(field: &str, got: i64, expected: i64) {
+        assert!((got - expected).abs() <= 1, "{}: expected {}, got {}", field, expected, got);
+    }
+
+    #[test]
+    fn conversions_match_reference_values() {
+        let config = Config::for_test();
+        let mut bfd = standard_day();
+        bfd.roll_rate = 10.0;
+        bfd.local_ax = 9.81;
+        bfd.indicated_airspeed = 100.0;
+        bfd.true_airspeed = 51.4;
+        bfd.alpha = 2.5;
+        bfd.elevation = 1000.5;
+
+        let data = FlightData::new(bfd, 0, &config, &mut Saturation::new());
+
+        assert_near("roll_rate", data.roll_rate as i64, 142);
+        assert_near("pitch_rate", data.pitch_rate as i64, 0);
+        assert_near("lin_acc_x", data.lin_acc_x as i64, 4102);
+        assert_near("lin_acc_y", data.lin_acc_y as i64, 0);
+        assert_near("mag_z", data.mag_z as i64, 3078);
+        assert_near("temp", data.temp as i64, 3840);
+        assert_near("barometer", data.barometer as i64, 4150272);
+        assert_near("airspeed_pressure", data.airspeed_pressure as i64, 6);
+        assert_near("true_airspeed", data.true_airspeed as i64, 514);
+        assert_near("alpha", data.alpha as i64, 250);
+        assert_near("altitude", data.altitude as i64, 100050);
+    }
+
+    #[test]
+    fn no_airspeed_means_no_airspeed_pressure() {
+        let config = Config::for_test();
+        let data = FlightData::new(standard_day(), 0, &config, &mut Saturation::new());
+
+        assert_eq!(data.airspeed_pressure, 0);
+        assert_eq!(data.true_airspeed, 0);
+        assert_eq!(data.roll_rate, 0);
+    }
+
+    // The sequence number as the FPGA reads it off the wire
+    fn sent_sequence(data: &FlightData, endian: WireEndian) -> u16 {
+        let bytes = data.to_bytes(endian);
+        let mut input = WireReader::new(&bytes[6 ..], endian);
+        input.u16()
+    }
+
+    #[test]
+    fn sequence_round_trips_and_wraps() {
+        let config = Config::for_test();
+        let mut saturation = Saturation::new();
+
+        for &endian in &[WireEndian::Little, WireEndian::Big] {
+            for &sequence in &[0, 1, 0x1234, 0x8000, 0xFFFF] {
+                let data = FlightData::new(standard_day(), sequence, &config, &mut saturation);
+                assert_eq!(sent_sequence(&data, endian), sequence);
+            }
+        }
+
+        // Counting the way the send thread does, 0xFFFF is followed by 0, and
+        // the receiving end sees no frames missing
+        let last = FlightData::new(standard_day(), 0xFFFF, &config, &mut saturation);
+        let next = FlightData::new(standard_day(), 0xFFFFu16.wrapping_add(1), &config, &mut saturation);
+        let (last, next) = (sent_sequence(&last, WireEndian::Little),
+                            sent_sequence(&next, WireEndian::Little));
+        assert_eq!(next, 0);
+        assert_eq!(sequence_gap(last.wrapping_add(1), next), Some(0));
+        assert_eq!(sequence_gap(last.wrapping_add(1), 2), Some(2));
+    }
+
+    #[test]
+    fn imu_readings_pin_at_the_i16_bounds() {
+        let mut saturation = Saturation::new();
+        assert_eq!(imu_reading("roll_rate", 32767.0, &mut saturation), i16::max_value());
+        assert_eq!(imu_reading("roll_rate", -32768.0, &mut saturation), i16::min_value());
+        assert!(!saturation.is_pinned("roll_rate"));
+
+        assert_eq!(imu_reading("roll_rate", 40000.0, &mut saturation), i16::max_value());
+        assert_eq!(imu_reading("pitch_rate", -1e9, &mut saturation), i16::min_value());
+        assert_eq!(imu_reading("yaw_rate", ::std::f32::NAN, &mut saturation), 0);
+        assert!(saturation.is_pinned("roll_rate"));
+        assert!(saturation.is_pinned("pitch_rate"));
+        assert!(saturation.is_pinned("yaw_rate"));
+
+        // Still pinned: no change. Back in range: no longer pinned.
+        imu_reading("roll_rate", 50000.0, &mut saturation);
+        assert!(saturation.is_pinned("roll_rate"));
+        assert_eq!(imu_reading("roll_rate", 100.0, &mut saturation), 100);
+        assert!(!saturation.is_pinned("roll_rate"));
+        assert!(saturation.is_pinned("pitch_rate"));
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn barometer_pins_at_the_24_bit_bounds() {
+        let mut saturation = Saturation::new();
+        let max = (1 << 23) - 1;
+        let min = -(1 << 23);
+
+        assert_eq!(barometer_reading(max as f32, &mut saturation) as i32, max);
+        assert_eq!(barometer_reading(min as f32, &mut saturation) as i32, min);
+        assert!(!saturation.is_pinned("barometer"));
+
+        assert_eq!(barometer_reading(1e8, &mut saturation) as i32, max);
+        assert!(saturation.is_pinned("barometer"));
+        assert_eq!(barometer_reading(-1e8, &mut saturation) as i32, min);
+        assert_eq!(barometer_reading(::std::f32::NAN, &mut saturation), 0);
+
+        // Negative readings are sign extended
+        assert_eq!(barometer_reading(-1.0, &mut saturation), 0xffff_ffff);
+        assert!(!saturation.is_pinned("barometer"));
+    }
+
+    fn barometer_at(hpa: f32, config: &Config) -> u32 {
+        let mut bfd = standard_day();
+        bfd.barometer_inhg = hpa / 33.8639;
+        FlightData::new(bfd, 0, config, &mut Saturation::new()).barometer
+    }
+
+    #[test]
+    fn absolute_barometer_readings() {
+        let config = Config::for_test();
+        assert_near("1013.25 hPa", barometer_at(1013.25, &config) as i64, 4150272);
+
+        // The chip's whole range fits in 24 bits; beyond it, we pin
+        assert_near("260 hPa", barometer_at(260.0, &config) as i64, 260 * 4096);
+        assert_near("1260 hPa", barometer_at(1260.0, &config) as i64, 1260 * 4096);
+        assert_eq!(barometer_at(3000.0, &config), (1 << 23) - 1);
+    }
+
+    #[test]
+    fn barometer_readings_relative_to_a_reference() {
+        let mut config = Config::for_test();
+        config.baro_reference_hpa = 1013.25;
+
+        assert_near("at the reference", barometer_at(1013.25, &config) as i32 as i64, 0);
+        assert_near("above it", barometer_at(1020.0, &config) as i32 as i64, 27648);
+
+        // Below the reference it's negative, sign extended to 32 bits
+        let below = barometer_at(1000.0, &config);
+        assert_eq!(below >> 23, 0x1ff);
+        assert_near("below it", below as i32 as i64, -54272);
+
+        // and pinned at -2^23 rather than wrapping
+        config.baro_reference_hpa = 3000.0;
+        assert_eq!(barometer_at(260.0, &config) as i32, -(1 << 23));
+    }
+
+    #[test]
+    fn checksum_is_crc32_of_everything_after_sync() {
+        for &endian in &[WireEndian::Little, WireEndian::Big] {
+            let mut config = Config::for_test();
+            config.wire_endian = endian;
+            let mut bfd = standard_day();
+            bfd.roll_rate = 3.0;
+            bfd.latitude = -35.3;
+            let bytes = FlightData::new(bfd, 9, &config, &mut Saturation::new()).to_bytes(endian);
+
+            assert_eq!(bytes[.. 4], SYNC);
+            let sent = WireReader::new(&bytes[FLIGHT_DATA_SIZE - 4 ..], endian).u32();
+            assert_eq!(sent, crc32(&bytes[4 .. FLIGHT_DATA_SIZE - 4]), "{:?}", endian);
+        }
+    }
+
+    #[test]
+    fn true_airspeed_is_independent_of_indicated() {
+        let config = Config::for_test();
+        let mut bfd = standard_day();
+        bfd.indicated_airspeed = 100.0;
+        let at_sea_level = FlightData::new(bfd, 0, &config, &mut Saturation::new());
+
+        // Same IAS high up, where the TAS is well above it
+        bfd.true_airspeed = 70.0;
+        let high_up = FlightData::new(bfd, 0, &config, &mut Saturation::new());
+        assert_eq!(high_up.true_airspeed, 700);
+        assert_eq!(high_up.airspeed_pressure, at_sea_level.airspeed_pressure);
+
+        // and it saturates rather than wrapping
+        bfd.true_airspeed = 5000.0;
+        let data = FlightData::new(bfd, 0, &config, &mut Saturation::new());
+        assert_eq!(data.true_airspeed, i16::max_value());
+    }
+
+    #[test]
+    fn readings_follow_the_configured_imu_ranges() {
+        let mut config = Config::for_test();
+        let mut bfd = standard_day();
+        bfd.roll_rate = 10.0;
+        bfd.local_ax = 9.8;
+
+        let wide = FlightData::new(bfd, 0, &config, &mut Saturation::new());
+        config.imu = ImuScales::from_ranges(250, 2, 4).unwrap();
+        let narrow = FlightData::new(bfd, 0, &config, &mut Saturation::new());
+
+        // 8 times as sensitive to rotation, 4 times to acceleration
+        assert_near("roll_rate", narrow.roll_rate as i64, 10 * 1000 * 100 / 875);
+        assert_near("roll_rate", wide.roll_rate as i64, 10 * 1000 / 70);
+        assert_near("lin_acc_x", narrow.lin_acc_x as i64, 1000 * 1000 / 61);
+        assert_near("lin_acc_x", wide.lin_acc_x as i64, 1000 * 1000 / 244);
+    }
+
+    #[test]
+    fn airspeed_pressure_from_local_density() {
+        let mut config = Config::for_test();
+        let mut bfd = standard_day();
+        bfd.indicated_airspeed = 100.0;
+        bfd.true_airspeed = 80.0;
+        bfd.local_air_density = 0.9;
+
+        // 1.225 * (100 kt)^2 / 2 = 1621 Pa, against 0.9 * (80 m/s)^2 / 2 = 2880 Pa
+        let from_ias = FlightData::new(bfd, 0, &config, &mut Saturation::new());
+        config.local_air_density = true;
+        let from_tas = FlightData::new(bfd, 0, &config, &mut Saturation::new());
+        assert_eq!(from_ias.airspeed_pressure, 1621 / 240);
+        assert_eq!(from_tas.airspeed_pressure, 2880 / 240);
+
+        // At sea level on a standard day they agree
+        bfd.true_airspeed = 100.0 * 0.514_444_7;
+        bfd.local_air_density = 1.225;
+        let from_tas = FlightData::new(bfd, 0, &config, &mut Saturation::new());
+        assert_eq!(from_tas.airspeed_pressure, from_ias.airspeed_pressure);
+    }
+
+    #[test]
+    fn wind_speed_and_direction() {
+        let config = Config::for_test();
+        let wind = |knots: f32, degrees: f32| {
+            let mut bfd = standard_day();
+            bfd.wind_speed = knots;
+            bfd.wind_direction = degrees;
+            let data = FlightData::new(bfd, 0, &config, &mut Saturation::new());
+            (data.wind_speed, data.wind_direction)
+        };
+
+        // 20 kt is 10.29 m/s
+        assert_eq!(wind(20.0, 270.0), (102, 27000));
+        assert_eq!(wind(0.0, 0.0), (0, 0));
+        // Directions are brought into [0, 360), and never round up to 360
+        assert_eq!(wind(5.0, -90.0).1, 27000);
+        assert_eq!(wind(5.0, 450.0).1, 9000);
+        assert_eq!(wind(5.0, 359.9999).1, 35999);
+    }
+
+    #[test]
+    fn load_factor_in_milli_g() {
+        let mut config = Config::for_test();
+        let load = |config: &Config, az: f32| {
+            let mut bfd = standard_day();
+            bfd.local_az = az;
+            FlightData::new(bfd, 0, config, &mut Saturation::new()).load_factor
+        };
+
+        assert_eq!(load(&config, 0.0), 0);
+        assert_near("1g", load(&config, -9.81) as i64, 1000);
+        assert_near("2g", load(&config, -19.62) as i64, 2000);
+        assert_near("-1g", load(&config, 9.81) as i64, -1000);
+
+        // It's the aircraft's, so a tilted IMU doesn't change it
+        config.imu_mounting = Quaternion::from_euler(0.0, 30f32.to_radians(), 0.0);
+        assert_near("2g, tilted IMU", load(&config, -19.62) as i64, 2000);
+    }
+
+    #[test]
+    fn magnetometer_strength_and_declination() {
+        let mut config = Config::for_test();
+        let mag = |config: &Config, theta: f32, psi: f32| {
+            let mut bfd = standard_day();
+            bfd.true_theta = theta;
+            bfd.mag_psi = psi;
+            let data = FlightData::new(bfd, 0, config, &mut Saturation::new());
+            [data.mag_x as i64, data.mag_y as i64, data.mag_z as i64]
+        };
+
+        // Field strength scales every axis
+        assert_near("0.45 gauss", mag(&config, 0.0, 0.0)[2], 3078);
+        config.mag_field_gauss = 0.5;
+        assert_near("0.5 gauss", mag(&config, 0.0, 0.0)[2], 3421);
+
+        // D degrees of declination takes D off the magnetic heading, so at 90
+        // it looks the same as heading north with none
+        let undeclined = mag(&config, 30.0, 0.0);
+        config.mag_declination_deg = 90.0;
+        let declined = mag(&config, 30.0, 90.0);
+        for axis in 0 .. 3 {
+            assert_near("declination", declined[axis], undeclined[axis]);
+        }
+        assert_near("mag_x", declined[0], -1710);
+    }
+
+    #[test]
+    fn imu_mounted_at_an_angle() {
+        let mut config = Config::for_test();
+        let mut bfd = standard_day();
+        bfd.roll_rate = 10.0;
+        bfd.local_ax = 9.8;
+
+        // Turned 90 degrees to the right, the board's y axis points back
+        // down the fuselage, so a roll shows up negated on its pitch axis
+        config.imu_mounting = Quaternion::from_euler(0.0, 0.0, 90f32.to_radians());
+        let data = FlightData::new(bfd, 0, &config, &mut Saturation::new());
+        assert_near("roll_rate", data.roll_rate as i64, 0);
+        assert_near("pitch_rate", data.pitch_rate as i64, -142);
+        assert_near("lin_acc_x", data.lin_acc_x as i64, 0);
+        assert_near("lin_acc_y", data.lin_acc_y as i64, -4098);
+    }
+
+    #[test]
+    fn protocol_version_follows_sync() {
+        let config = Config::for_test();
+        let bytes = FlightData::new(standard_day(), 0, &config, &mut Saturation::new())
+            .to_bytes(config.wire_endian);
+        assert_eq!(bytes[.. 6], [b'S', b'Y', b'N', b'C', PROTOCOL_VERSION, 0]);
+    }
+
+    // See the same test in control_data.rs
+    #[test]
+    fn wire_size() {
+        let data = FlightData::new(standard_day(), 0, &Config::for_test(), &mut Saturation::new());
+        assert_eq!(data.to_bytes(WireEndian::Little).len(), FLIGHT_DATA_SIZE);
+        assert_eq!(::std::mem::size_of::<FlightData>(), FLIGHT_DATA_SIZE);
+    }
+}
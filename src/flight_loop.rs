@@ -1,15 +1,46 @@
 use xplm::flight_loop::LoopState;
-use xplm::data::{DataReadWrite, ArrayReadWrite};
-use std::time::SystemTime;
+use xplm::data::{DataRead, DataReadWrite, ArrayRead, ArrayReadWrite, ReadWrite};
+use xplm::data::borrowed::DataRef;
+use std::time::{Duration, SystemTime};
 use std::time::UNIX_EPOCH;
+use clock;
 use std::thread;
-use std::fs::File;
-use std::io::Write;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use FFSim;
+use BufferedControlData;
 use PLUGIN;
-use NUM_LATENCY_MEASUREMENTS;
+use RESTART_LATENCY;
+use TOGGLE_OVERRIDE;
+use TOGGLE_SURFACES;
+use TOGGLE_THROTTLE;
+use TOGGLE_PAUSE_TX;
+use NUM_ENGINES;
+use latency::{LatencyStats, LatencyStream, WriteInProgress, as_nanos, histogram, output_dir,
+              write_histogram, write_latencies};
+use log_file::log_path;
+
+// Set while `flight_loop` is running. X-Plane only ever calls plugins from its
+// main thread, one callback at a time, and that's the only thing that makes the
+// `&mut` to PLUGIN below sound. This makes the assumption explicit: if we're
+// ever entered twice at once we skip the second call rather than alias it.
+static IN_FLIGHT_LOOP: AtomicBool = AtomicBool::new(false);
+
+// Clears IN_FLIGHT_LOOP however `flight_loop` returns
+struct InFlightLoop;
+
+impl Drop for InFlightLoop {
+    fn drop(&mut self) {
+        IN_FLIGHT_LOOP.store(false, Ordering::SeqCst);
+    }
+}
+
+pub fn flight_loop(loop_state: &mut LoopState) {
+    if IN_FLIGHT_LOOP.swap(true, Ordering::SeqCst) {
+        log_error!("Flight loop re-entered, skipping this call");
+        return;
+    }
+    let _in_flight_loop = InFlightLoop;
 
-pub fn flight_loop(_loop_state: &mut LoopState) {
     // For latency computations, we measure the _start_ time from
     // before we start reading the DataRefs (since that also
     // contributes to latency.)
@@ -19,114 +50,512 @@ pub fn flight_loop(_loop_state: &mut LoopState) {
     // N.B. Also this is the start time of our part fo the flight loop, so
     //      subtracting these values from different flight loops gives us how
     //      long it takes an _integer number of flight loops_ to complete (in theory).
-    let new_start_time = SystemTime::now();
+    let new_start_time = clock::now();
 
     // `PLUGIN` is a global created by `xplane_plugin!`
     // It *should* be safe to take an exclusive reference,
     // since X-Plane does not call us concurrently.
     let plugin : &mut FFSim = unsafe { &mut *PLUGIN };
 
-    // Read from triple buffer and update controls
-    let control = *plugin.incoming.read();
-    plugin.rudder.set(control.rudder);
-    plugin.left_aileron.set(control.left_aileron);
-    plugin.right_aileron.set(control.right_aileron);
-    plugin.elevator1.set(control.elevator);
-    plugin.elevator2.set(control.elevator);
+    if RESTART_LATENCY.swap(false, Ordering::SeqCst) {
+        plugin.restart_latency_experiment();
+    }
+
+    // The sim's time step, i.e. how long since the physics engine last ran.
+    // While a (batch) latency experiment is recording, these add up to the
+    // physics engine's average rate over it.
+    let dt = loop_state.since_last_flight_loop();
+    log_debug!("Flight loop dt {}us", as_nanos(dt) / 1000);
+    let recording = !plugin.config.latency_stream && plugin.num_latencies >= 0
+        && plugin.num_latencies < plugin.latencies.len() as isize;
+    if recording {
+        plugin.loop_count += 1;
+        plugin.loop_time += dt;
+    }
+    if TOGGLE_OVERRIDE.swap(false, Ordering::SeqCst) {
+        plugin.toggle_override();
+    }
+    if TOGGLE_SURFACES.swap(false, Ordering::SeqCst) {
+        plugin.toggle_surfaces();
+    }
+    if TOGGLE_THROTTLE.swap(false, Ordering::SeqCst) {
+        plugin.toggle_throttle();
+    }
+    if TOGGLE_PAUSE_TX.swap(false, Ordering::SeqCst) {
+        plugin.toggle_pause_tx();
+    }
+
+    // Read from the receive thread and update controls (unless we're just watching)
+    let control = plugin.incoming.read();
+
+    // Watchdog: if the controller has gone quiet (cable yanked, firmware crashed),
+    // don't leave the surfaces frozen wherever it last put them. Instead go back
+    // to neutral surfaces and idle throttle until it comes back.
+    let alive = match control.received {
+        Some(received) => received.elapsed() <= plugin.config.watchdog_timeout(),
+        None => false,
+    };
+    if alive != plugin.controller_alive {
+        if alive {
+            log_info!("Receiving control data");
+        } else {
+            log_warn!("No control data for {}ms, returning controls to neutral",
+                      plugin.config.watchdog_ms);
+        }
+        plugin.controller_alive = alive;
+    }
+    let command = if alive { control } else { BufferedControlData::new() };
+
+    // Until the controller has sent us something there's nothing to apply, so
+    // leave the controls wherever the sim had them rather than snapping them
+    // to neutral. (`time` is 0 for data that was never written.)
+    if !plugin.have_valid_control && control.time != 0 {
+        plugin.have_valid_control = true;
+    }
+
+    let write_controls = plugin.in_control && plugin.have_valid_control;
+    if write_controls && plugin.controls_surfaces {
+        // A frame can pass the checksum and still ask for something silly,
+        // so keep everything within the aircraft's limits.
+        // Trim goes on afterwards; running out of travel because of it isn't
+        // the controller's fault, so that's clamped without complaint.
+        // Inversion is of the controller's command, so trim stays in the sim's terms.
+        let max = plugin.config.max_deflection_deg;
+        let trims = plugin.config.trims;
+        let invert = plugin.config.inversions;
+        let trimmed = |name, value, inverted, trim|
+            surface_command(name, value, max, inverted, trim);
+        let rudder = trimmed("rudder", command.rudder, invert.rudder, trims.rudder);
+        let left_aileron = trimmed("left aileron", command.left_aileron, invert.ailerons,
+                                   trims.left_aileron);
+        let right_aileron = trimmed("right aileron", command.right_aileron, invert.ailerons,
+                                    trims.right_aileron);
+        let left_elevator = trimmed("left elevator", command.left_elevator, invert.elevators,
+                                    trims.left_elevator);
+        let right_elevator = trimmed("right elevator", command.right_elevator, invert.elevators,
+                                     trims.right_elevator);
+
+        // Real surfaces can't jump from one end of their travel to the other,
+        // so (if configured) limit how far each can move over the sim's time
+        // step. That's `dt` rather than the wall-clock time since we were
+        // last called, which runs on while the sim is paused.
+        let mut surfaces = [rudder, left_aileron, right_aileron, left_elevator, right_elevator];
+        let slew_rate = plugin.config.slew_rate_deg_s;
+        if let Some(previous) = plugin.last_surfaces.filter(|_| slew_rate > 0.0) {
+            slew_surfaces(&mut surfaces, &previous, slew_rate, dt);
+        }
+        plugin.last_surfaces = Some(surfaces);
+
+        set_control_surface(&mut plugin.rudder, surfaces[0]);
+        set_control_surface(&mut plugin.left_aileron, surfaces[1]);
+        set_control_surface(&mut plugin.right_aileron, surfaces[2]);
+        set_linked_surfaces(&mut plugin.left_aileron_extra, surfaces[1]);
+        set_linked_surfaces(&mut plugin.right_aileron_extra, surfaces[2]);
+        set_control_surface(&mut plugin.elevator1, surfaces[3]);
+        set_control_surface(&mut plugin.elevator2, surfaces[4]);
+
+        let flaps = clamp_control("flaps", command.flaps, 0.0, 1.0);
+        let spoilers = clamp_control("spoilers", command.spoilers, 0.0, 1.0);
+        set_control_surface(&mut plugin.flaps, flaps);
+        set_control_surface(&mut plugin.spoilers, spoilers);
+
+        // Brakes and steering do nothing in the air, so leave them be until we land
+        if plugin.on_ground.get() != 0 {
+            let brake = clamp_control("brake", command.brake, 0.0, 1.0);
+            let max_steering = plugin.config.max_steering_deg;
+            let nosewheel = clamp_control("nosewheel", command.nosewheel, -max_steering, max_steering);
+
+            plugin.left_brake.set(brake);
+            plugin.right_brake.set(brake);
+            plugin.tire_steer.set(&[nosewheel]);
+        }
+    } else {
+        // Whoever has the surfaces now will have moved them by the time we
+        // get them back, so there's nothing to limit against
+        plugin.last_surfaces = None;
+    }
 
-    // If the time is set to UNIX_EPOCH, it means we read uninitialized data
-    // from the triple buffer---ignore it.
+    // At this point the data in `control` is written out to the
+    // sim, so we measure the _end_ time here.
     //
-    // If the time is one we measured just previously, that means we have multiple
-    // inputs from the controller for the same output---we care about the _first_
-    // response to the output, so ignore it.
-    if (control.time != UNIX_EPOCH) && (control.time != plugin.last_time) {
-        // At this point the data in `control` is written out to the
-        // sim, so we measure the _end_ time here.
-        //
-        // N.B. This is the end time for the packet we sent _last time_.
-        match control.time.elapsed() {
-            Ok(dur) => {
-                // num_latencies >= NUM_LATENCY_MEASUREMENTS means we have concluded the experiment
+    // N.B. This is the end time for the packet we sent _last time_.
+    if let Some(sample) = latency_sample(control.time, plugin.last_time) {
+        match sample {
+            Some(dur) => {
+                if plugin.config.latency_stream {
+                    // Streaming mode: the experiment never ends, and once the
+                    // warm-up is over each sample goes straight to the file
+                    if plugin.num_latencies >= 0 {
+                        if plugin.latency_stream.is_none() {
+                            let run_id = SystemTime::now().duration_since(UNIX_EPOCH)
+                                .map(|dur| dur.as_secs()).unwrap_or(0);
+                            let dir = output_dir(&plugin.config.latency_dir);
+                            plugin.latency_stream = Some(LatencyStream::start(&dir, run_id,
+                                                                              plugin.config.log_gzip));
+                        }
+                        if let Some(ref mut stream) = plugin.latency_stream {
+                            stream.record(dur);
+                        }
+                    } else {
+                        plugin.num_latencies += 1;
+                    }
+                    plugin.last_time = control.time;
+                }
+
+                // num_latencies >= latencies.len() means we have concluded the experiment
                 // already. We don't do this check earlier b/c we want the loop
                 // to take the same amount of time regardless of if the experiment is
                 // running. (Optimizing compiler might have other ideas though.)
-                if plugin.num_latencies < NUM_LATENCY_MEASUREMENTS as isize {
+                else if plugin.num_latencies < plugin.latencies.len() as isize {
                     if plugin.num_latencies >= 0 {
                         plugin.latencies[plugin.num_latencies as usize] = dur;
-
-                        // Start measuring the total time of the experiment; this will be used
-                        // to determine the average refresh rate of the physics engine during
-                        // the test.
-                        if plugin.num_latencies == 0 {
-                            plugin.time_start = new_start_time;
-                        }
-                        plugin.cycle_count += 1;
+                        plugin.latency_dts[plugin.num_latencies as usize] = dt;
                     }
                     plugin.num_latencies += 1;
                     plugin.last_time = control.time;
 
-                    if plugin.num_latencies == NUM_LATENCY_MEASUREMENTS as isize {
+                    if plugin.num_latencies == plugin.latencies.len() as isize {
                         // End of experiment. Spawn a new thread to write data
-                        // to a file.
-                        let latencies = plugin.latencies;
-                        let time_start = plugin.time_start;
-                        let cycles = plugin.cycle_count;
+                        // to a file. Each run gets its own files so re-running
+                        // the experiment doesn't overwrite the previous results.
+                        let latencies = plugin.latencies.clone();
+                        let dts = plugin.latency_dts.clone();
+                        let run_id = SystemTime::now().duration_since(UNIX_EPOCH)
+                            .map(|dur| dur.as_secs()).unwrap_or(0);
+                        let latency_dir = plugin.config.latency_dir.clone();
+                        // (None if the experiment somehow took no time at all)
+                        let loop_secs = plugin.loop_time.as_secs() as f64
+                            + plugin.loop_time.subsec_nanos() as f64 / 1e9;
+                        let refresh_hz = Some(plugin.loop_count as f64 / loop_secs)
+                            .filter(|_| loop_secs > 0.0);
+                        let bucket_us = plugin.config.latency_bucket_us;
+                        let gzip = plugin.config.log_gzip;
+                        let writing = WriteInProgress::new();
                         thread::spawn(move|| {
-                            match File::create("latencies.csv") {
-                                Ok(mut out) => {
-                                    out.write_all("latencies,refresh\n".as_bytes())
-                                        .unwrap();
-                                    for i in 0 .. latencies.len() {
-                                        out.write_all(format!("{}",
-                                                              latencies[i].as_secs() * 1_000_000_000
-                                                                  + latencies[i].subsec_nanos() as u64)
-                                            .as_bytes()).unwrap();
-
-                                        // write physics engine refresh rate into first row
-                                        if i == 0 {
-                                            let time_diff
-                                                = new_start_time.duration_since(time_start)
-                                                .unwrap();
-
-                                            let time_diff_ns = (time_diff.as_secs()
-                                                * 1_000_000_000 + time_diff.subsec_nanos() as u64)
-                                                as f64;
-
-                                            let adjusted_cycles = (cycles * 1_000_000_000)
-                                                as f64;
-
-                                            out.write_all(format!(",{}",
-                                                                  adjusted_cycles/time_diff_ns)
-                                                .as_bytes()).unwrap();
-                                        }
-                                        out.write_all("\n".as_bytes()).unwrap();
-
-                                    }
-                                    println!("[FFSim] Successfully wrote latencies");
-                                },
+                            let _writing = writing;
+                            let dir = output_dir(&latency_dir);
+                            let latencies_path = dir.join(format!("latencies-{}.csv", run_id));
+                            let written_path = log_path(&latencies_path, gzip);
+                            let stats_path = dir.join(format!("stats-{}.csv", run_id));
+                            let histogram_path = dir.join(format!("histogram-{}.csv", run_id));
+
+                            // Nothing would see a panic in this thread, so log any
+                            // failure and give up on the run instead
+                            match write_latencies(&latencies_path, &latencies[..], &dts[..], refresh_hz, gzip) {
+                                Ok(_) => log_info!("Successfully wrote latencies to {}",
+                                                   written_path.display()),
                                 Err(e) => {
-                                    println!("[FFSim] Couldn't open file for writing latencies: {:?}", e);
+                                    log_error!("Couldn't write latencies to {}: {:?}",
+                                               written_path.display(), e);
+                                    return;
                                 }
                             }
+
+                            match LatencyStats::compute(&latencies[..]) {
+                                Some(stats) => match stats.write_csv(&stats_path) {
+                                    Ok(_) => log_info!("Latency stats (ns): {:?}", stats),
+                                    Err(e) => log_error!("Couldn't write latency stats to {}: {:?}",
+                                                         stats_path.display(), e),
+                                },
+                                None => (),
+                            }
+
+                            if let Err(e) = write_histogram(&histogram_path,
+                                                            &histogram(&latencies[..], bucket_us)) {
+                                log_error!("Couldn't write latency histogram to {}: {:?}",
+                                           histogram_path.display(), e);
+                            }
                         });
                     }
                 }
             },
-            Err(e) => {
-                println!("[FFSim] Did the clock change under us? e={:?}", e);
+            None => {
+                // The clock is monotonic, so a timestamp from the future can only
+                // have been garbled (or be from before the plugin was reloaded).
+                // Throw this sample away, but remember the frame as seen so its
+                // repeats don't land here too; the experiment carries on with the
+                // next frame.
+                log_warn!("Timestamp {} from the future, discarding a latency sample",
+                          control.time);
+                plugin.last_time = control.time;
             }
         }
     }
 
     // Throttle is a bit trickier b/c it's an array,
-    // but we only have one engine so we only set the
-    // first element.
-    let mut throttle_buf = [0.0; 8];
-    throttle_buf[0] = control.throttle;
-    plugin.throttle.set(&mut throttle_buf);
+    // but we only set the first NUM_ENGINES elements (any other engines are
+    // kept at idle). The array is as long as the aircraft has engines, which
+    // needn't be NUM_ENGINES, so we size everything by it.
+    if write_controls && plugin.controls_throttle {
+        let reverse = plugin.config.throttle_reverse > 0.0;
+        let engines = plugin.throttle.len();
+        let (mut throttle_buf, prop_mode_buf) = throttle_arrays(&command.throttle, engines, reverse);
+        plugin.throttle.set(&mut throttle_buf);
+
+        if reverse {
+            if let Some(ref mut prop_mode) = plugin.prop_mode {
+                let len = NUM_ENGINES.min(engines).min(prop_mode.len());
+                prop_mode.set(&prop_mode_buf[.. len]);
+            }
+        }
+    }
 
     // Write flight data into triple buffer
     let flight_data = plugin.get_data(new_start_time);
     plugin.outgoing.write(flight_data);
-}
\ No newline at end of file
+
+    update_menu(plugin);
+    update_stats(plugin);
+
+    // Otherwise X-Plane calls us again next frame
+    if let Some(interval) = plugin.config.flight_loop_interval() {
+        loop_state.call_after(interval);
+    }
+}
+
+// The latency of control data stamped `time`, if it's a response we should
+// count. None if it isn't:
+//
+// If the time is set to 0, it means we read uninitialized data
+// from the triple buffer---ignore it.
+//
+// If the time is `last_time`, the one we measured just previously, that means
+// we have multiple inputs from the controller for the same output---we care
+// about the _first_ response to the output, so ignore it.
+//
+// Some(None) if it should count but the timestamp is from the future (see
+// `clock::since`), so there's no latency to record.
+fn latency_sample(time: u64, last_time: u64) -> Option<Option<Duration>> {
+    if time == 0 || time == last_time {
+        return None;
+    }
+    Some(clock::since(time))
+}
+
+// Copies the receive thread's counts into our datarefs. (They're i32, so
+// they'd wrap after a couple of billion frames; that's a year at 50Hz.)
+fn update_stats(plugin: &mut FFSim) {
+    let count = |counter: &AtomicUsize| counter.load(Ordering::Relaxed) as i32;
+    let stats = &plugin.link_stats;
+
+    plugin.stats_frames.set(count(&stats.frames));
+    plugin.stats_valid_frames.set(count(&stats.valid_frames));
+    plugin.stats_bad_headers.set(count(&stats.bad_headers));
+    plugin.stats_crc_errors.set(count(&stats.crc_errors));
+}
+
+// The menu can only be changed from the main thread, i.e. here
+fn update_menu(plugin: &mut FFSim) {
+    let state = plugin.link.state();
+    let tx_paused = plugin.tx_paused.load(Ordering::SeqCst);
+
+    if plugin.config.uses_serial_port() {
+        let serial_port = plugin.serial_port.lock().unwrap().clone();
+        plugin.menu.update(state, tx_paused, Some(&serial_port));
+    } else {
+        plugin.menu.update(state, tx_paused, None);
+    }
+}
+
+// What to write to the throttle and prop mode arrays of an aircraft with
+// `engines` engines: our commands for the first NUM_ENGINES (or as many as
+// there are), idle for the rest. The sim takes reverse thrust (a negative
+// command, if `reverse` is allowed at all) as a positive throttle with the
+// engine in reverse mode (3, where 1 is normal).
+fn throttle_arrays(command: &[f32; NUM_ENGINES], engines: usize, reverse: bool) -> (Vec<f32>, Vec<i32>) {
+    let min_throttle = if reverse { -1.0 } else { 0.0 };
+
+    let mut throttle_buf = vec![0.0; engines];
+    let mut prop_mode_buf = vec![1; engines];
+    for i in 0 .. NUM_ENGINES.min(engines) {
+        let throttle = clamp_control("throttle", command[i], min_throttle, 1.0);
+        throttle_buf[i] = throttle.abs();
+        if throttle < 0.0 {
+            prop_mode_buf[i] = 3;
+        }
+    }
+    (throttle_buf, prop_mode_buf)
+}
+
+// `value` the other way round if `inverted`
+fn apply_sign(value: f32, inverted: bool) -> f32 {
+    if inverted { -value } else { value }
+}
+
+// What to set a surface to for the controller's `value`, all within ±`max`. The
+// command is flipped (if `inverted`) before `trim` is added, not after.
+fn surface_command(name: &str, value: f32, max: f32, inverted: bool, trim: f32) -> f32 {
+    (apply_sign(clamp_control(name, value, -max, max), inverted) + trim).max(-max).min(max)
+}
+
+// Moves from `previous` towards `target`, but by no more than `max_step`
+fn slew_limit(previous: f32, target: f32, max_step: f32) -> f32 {
+    target.max(previous - max_step).min(previous + max_step)
+}
+
+// Moves each of `surfaces` from its `previous` position at no more than
+// `rate` degrees per second over `dt`
+fn slew_surfaces(surfaces: &mut [f32], previous: &[f32], rate: f32, dt: Duration) {
+    let max_step = rate * (dt.as_secs() as f32 + dt.subsec_nanos() as f32 / 1e9);
+    for (surface, previous) in surfaces.iter_mut().zip(previous.iter()) {
+        *surface = slew_limit(*previous, *surface, max_step);
+    }
+}
+
+// Control surfaces the aircraft doesn't have are silently skipped
+fn set_control_surface(surface: &mut Option<DataRef<f32, ReadWrite>>, value: f32) {
+    if let Some(ref mut dataref) = *surface {
+        dataref.set(value);
+    }
+}
+
+// Moves each of `surfaces` to `value` times its scale
+fn set_linked_surfaces(surfaces: &mut [(DataRef<f32, ReadWrite>, f32)], value: f32) {
+    for &mut (ref mut dataref, scale) in surfaces.iter_mut() {
+        dataref.set(value * scale);
+    }
+}
+
+// Limits `value` to [min, max], complaining if it had to since that
+// probably means the controller has a bug.
+fn clamp_control(name: &str, value: f32, min: f32, max: f32) -> f32 {
+    if value >= min && value <= max {
+        return value;
+    }
+
+    let clamped = if value > max {
+        max
+    } else {
+        min // also catches NaN
+    };
+    log_warn!("Clamped {} from {} to {}", name, value, clamped);
+    clamped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn clamp_leaves_values_in_range_alone() {
+        assert_eq!(clamp_control("rudder", 3.5, -15.0, 15.0), 3.5);
+        assert_eq!(clamp_control("rudder", -15.0, -15.0, 15.0), -15.0);
+        assert_eq!(clamp_control("flaps", 1.0, 0.0, 1.0), 1.0);
+    }
+
+    #[test]
+    fn clamp_pins_values_out_of_range() {
+        assert_eq!(clamp_control("rudder", 40.0, -15.0, 15.0), 15.0);
+        assert_eq!(clamp_control("rudder", -40.0, -15.0, 15.0), -15.0);
+        assert_eq!(clamp_control("throttle", ::std::f32::INFINITY, 0.0, 1.0), 1.0);
+        assert_eq!(clamp_control("throttle", ::std::f32::NEG_INFINITY, 0.0, 1.0), 0.0);
+    }
+
+    #[test]
+    fn clamp_turns_nan_into_the_minimum() {
+        assert_eq!(clamp_control("throttle", ::std::f32::NAN, 0.0, 1.0), 0.0);
+        assert_eq!(clamp_control("rudder", ::std::f32::NAN, -15.0, 15.0), -15.0);
+    }
+
+    #[test]
+    fn only_first_responses_are_sampled() {
+        let stamp = clock::now();
+        // Never written, and repeats of the last one, don't count
+        assert_eq!(latency_sample(0, 0), None);
+        assert_eq!(latency_sample(stamp, stamp), None);
+
+        match latency_sample(stamp, stamp - 1) {
+            Some(Some(_)) => (),
+            other => panic!("expected a latency, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn timestamps_from_the_future_count_without_a_latency() {
+        let future = clock::now() + 60_000_000_000;
+        assert_eq!(latency_sample(future, 0), Some(None));
+        // and the next repeat of that frame is ignored like any other
+        assert_eq!(latency_sample(future, future), None);
+    }
+
+    #[test]
+    fn throttle_arrays_fit_the_aircraft() {
+        let command = [0.75; NUM_ENGINES];
+
+        // More engines than we command: the rest idle
+        let (throttle, prop_mode) = throttle_arrays(&command, NUM_ENGINES + 2, false);
+        assert_eq!(throttle.len(), NUM_ENGINES + 2);
+        assert!(throttle[.. NUM_ENGINES].iter().all(|&val| val == 0.75));
+        assert!(throttle[NUM_ENGINES ..].iter().all(|&val| val == 0.0));
+        assert!(prop_mode.iter().all(|&mode| mode == 1));
+
+        // None at all (a glider): nothing to write
+        let (throttle, prop_mode) = throttle_arrays(&command, 0, false);
+        assert!(throttle.is_empty() && prop_mode.is_empty());
+    }
+
+    #[test]
+    fn reverse_thrust_is_a_positive_throttle_in_reverse_mode() {
+        let command = [-0.5; NUM_ENGINES];
+
+        let (throttle, prop_mode) = throttle_arrays(&command, NUM_ENGINES, true);
+        assert!(throttle.iter().all(|&val| val == 0.5));
+        assert!(prop_mode.iter().all(|&mode| mode == 3));
+
+        // Not allowed: clamped to idle, in normal mode
+        let (throttle, prop_mode) = throttle_arrays(&command, NUM_ENGINES, false);
+        assert!(throttle.iter().all(|&val| val == 0.0));
+        assert!(prop_mode.iter().all(|&mode| mode == 1));
+    }
+
+    #[test]
+    fn slew_reaches_nearby_targets() {
+        assert_eq!(slew_limit(0.0, 0.5, 1.0), 0.5);
+        assert_eq!(slew_limit(2.0, 1.0, 1.0), 1.0);
+        assert_eq!(slew_limit(3.0, 3.0, 0.0), 3.0);
+    }
+
+    #[test]
+    fn slew_stops_short_of_distant_targets() {
+        assert_eq!(slew_limit(0.0, 20.0, 1.5), 1.5);
+        assert_eq!(slew_limit(0.0, -20.0, 1.5), -1.5);
+        assert_eq!(slew_limit(-10.0, 10.0, 4.0), -6.0);
+        // With no time gone by, nothing moves
+        assert_eq!(slew_limit(5.0, -5.0, 0.0), 5.0);
+    }
+
+    #[test]
+    fn slew_is_limited_by_the_sim_time_step() {
+        // However long the sim sat paused, one 20 ms step only gets 20 ms
+        // worth of travel at 50 degrees per second
+        let mut surfaces = [20.0, -20.0, 0.5, 0.0, -1.0];
+        slew_surfaces(&mut surfaces, &[0.0; 5], 50.0, Duration::from_millis(20));
+        assert_eq!(surfaces, [1.0, -1.0, 0.5, 0.0, -1.0]);
+    }
+
+    #[test]
+    fn inverting_flips_the_sign() {
+        assert_eq!(apply_sign(2.5, false), 2.5);
+        assert_eq!(apply_sign(2.5, true), -2.5);
+        assert_eq!(apply_sign(-2.5, true), 2.5);
+    }
+
+    #[test]
+    fn surface_commands() {
+        assert_eq!(surface_command("rudder", 10.0, 20.0, false, 0.0), 10.0);
+        assert_eq!(surface_command("rudder", 10.0, 20.0, true, 0.0), -10.0);
+        // Trim is in the sim's terms, so inverting doesn't flip it
+        assert_eq!(surface_command("rudder", 10.0, 20.0, false, 2.0), 12.0);
+        assert_eq!(surface_command("rudder", 10.0, 20.0, true, 2.0), -8.0);
+    }
+
+    #[test]
+    fn surface_commands_stay_within_the_limit() {
+        // The command's clamped before inverting and trimming ...
+        assert_eq!(surface_command("rudder", 30.0, 20.0, true, 5.0), -15.0);
+        // ... and trim can't take it past the limit either
+        assert_eq!(surface_command("rudder", 18.0, 20.0, false, 5.0), 20.0);
+        assert_eq!(surface_command("rudder", 18.0, 20.0, true, -5.0), -20.0);
+        assert_eq!(surface_command("rudder", ::std::f32::NAN, 20.0, false, 0.0), -20.0);
+    }
+}
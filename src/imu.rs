@@ -0,0 +1,92 @@
+// Sensitivities of the sensors whose output `FlightData` imitates. These depend
+// on the full-scale range the FPGA configures each chip for, so they need to
+// match what it does or all the telemetry will be mis-scaled.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct ImuScales {
+    // lsm6dsm gyro, milli-dps per LSB
+    pub gyro_mdps_per_lsb: f32,
+    // lsm6dsm accelerometer, milli-g per LSB
+    pub accel_mg_per_lsb: f32,
+    // lis3mdl magnetometer, LSB per gauss
+    pub mag_lsb_per_gauss: f32,
+}
+
+// (full scale, sensitivity) pairs from the datasheets
+const GYRO_SCALES: [(u32, f32); 5] = [(125, 4.375), (250, 8.75), (500, 17.5), (1000, 35.0), (2000, 70.0)];
+const ACCEL_SCALES: [(u32, f32); 4] = [(2, 0.061), (4, 0.122), (8, 0.244), (16, 0.488)];
+const MAG_SCALES: [(u32, f32); 4] = [(4, 6842.0), (8, 3421.0), (12, 2281.0), (16, 1711.0)];
+
+// What the FPGA has always used: ±2000 dps, ±8g and ±4 gauss
+pub const DEFAULT_GYRO_RANGE_DPS: u32 = 2000;
+pub const DEFAULT_ACCEL_RANGE_G: u32 = 8;
+pub const DEFAULT_MAG_RANGE_GAUSS: u32 = 4;
+
+impl ImuScales {
+    // None unless each range is one the chip actually supports
+    pub fn from_ranges(gyro_dps: u32, accel_g: u32, mag_gauss: u32) -> Option<Self> {
+        Some(ImuScales {
+            gyro_mdps_per_lsb: lookup(&GYRO_SCALES, gyro_dps)?,
+            accel_mg_per_lsb: lookup(&ACCEL_SCALES, accel_g)?,
+            mag_lsb_per_gauss: lookup(&MAG_SCALES, mag_gauss)?,
+        })
+    }
+
+    pub fn is_gyro_range(dps: u32) -> bool {
+        lookup(&GYRO_SCALES, dps).is_some()
+    }
+
+    pub fn is_accel_range(g: u32) -> bool {
+        lookup(&ACCEL_SCALES, g).is_some()
+    }
+
+    pub fn is_mag_range(gauss: u32) -> bool {
+        lookup(&MAG_SCALES, gauss).is_some()
+    }
+}
+
+impl Default for ImuScales {
+    fn default() -> Self {
+        ImuScales::from_ranges(DEFAULT_GYRO_RANGE_DPS, DEFAULT_ACCEL_RANGE_G, DEFAULT_MAG_RANGE_GAUSS)
+            .unwrap()
+    }
+}
+
+fn lookup(table: &[(u32, f32)], full_scale: u32) -> Option<f32> {
+    table.iter().find(|entry| entry.0 == full_scale).map(|entry| entry.1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn defaults_are_what_the_fpga_has_always_used() {
+        assert_eq!(ImuScales::default(), ImuScales {
+            gyro_mdps_per_lsb: 70.0,
+            accel_mg_per_lsb: 0.244,
+            mag_lsb_per_gauss: 6842.0,
+        });
+    }
+
+    #[test]
+    fn each_range_has_its_datasheet_sensitivity() {
+        let scales = ImuScales::from_ranges(125, 16, 12).unwrap();
+        assert_eq!(scales.gyro_mdps_per_lsb, 4.375);
+        assert_eq!(scales.accel_mg_per_lsb, 0.488);
+        assert_eq!(scales.mag_lsb_per_gauss, 2281.0);
+    }
+
+    #[test]
+    fn unsupported_ranges_are_refused() {
+        assert_eq!(ImuScales::from_ranges(300, 8, 4), None);
+        assert_eq!(ImuScales::from_ranges(2000, 3, 4), None);
+        assert_eq!(ImuScales::from_ranges(2000, 8, 0), None);
+
+        assert!(ImuScales::is_gyro_range(250));
+        assert!(!ImuScales::is_gyro_range(0));
+        assert!(ImuScales::is_accel_range(2));
+        assert!(!ImuScales::is_accel_range(32));
+        assert!(ImuScales::is_mag_range(16));
+        assert!(!ImuScales::is_mag_range(2));
+    }
+}
@@ -0,0 +1,343 @@
+use std::fs::File;
+use std::io;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender, TrySendError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use super::log_file::{LogWriter, log_path};
+
+// Samples waiting to be written by a `LatencyStream`
+const STREAM_QUEUE_LEN: usize = 1024;
+
+// Threads currently writing latency files. They're never joined, so that the
+// flight loop doesn't wait on the disk, but the plugin's `stop` waits (briefly)
+// for this to reach zero so X-Plane quitting doesn't leave a file cut short.
+static WRITES_IN_PROGRESS: AtomicUsize = AtomicUsize::new(0);
+
+// Counts as a write in progress for as long as it's alive. Make one before
+// spawning the thread that does the writing and move it in, so there's no
+// moment at which the thread exists but `wait_for_writes` can't see it.
+pub struct WriteInProgress;
+
+impl WriteInProgress {
+    pub fn new() -> Self {
+        WRITES_IN_PROGRESS.fetch_add(1, Ordering::SeqCst);
+        WriteInProgress
+    }
+}
+
+impl Drop for WriteInProgress {
+    fn drop(&mut self) {
+        WRITES_IN_PROGRESS.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+// Waits up to `timeout` for every `WriteInProgress` to finish; false if some
+// are still going
+pub fn wait_for_writes(timeout: Duration) -> bool {
+    let deadline = Instant::now() + timeout;
+    while WRITES_IN_PROGRESS.load(Ordering::SeqCst) > 0 {
+        if Instant::now() >= deadline {
+            return false;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    true
+}
+
+// Summary of one latency experiment, all in nanoseconds
+#[derive(Copy, Clone, Debug)]
+pub struct LatencyStats {
+    pub min: u64,
+    pub mean: u64,
+    pub median: u64,
+    pub p90: u64,
+    pub p95: u64,
+    pub p99: u64,
+    pub max: u64,
+}
+
+impl LatencyStats {
+    // Returns None if there are no samples to summarize
+    pub fn compute(latencies: &[Duration]) -> Option<Self> {
+        if latencies.is_empty() {
+            return None;
+        }
+
+        let mut sorted: Vec<u64> = latencies.iter().map(|dur| as_nanos(*dur)).collect();
+        sorted.sort();
+
+        let len = sorted.len();
+        let median = if len % 2 == 0 {
+            (sorted[len / 2 - 1] + sorted[len / 2]) / 2
+        } else {
+            sorted[len / 2]
+        };
+
+        // nearest-rank percentile
+        let percentile = |pct: usize| sorted[(len * pct + 99) / 100 - 1]; // ceil(pct% of len), 1-based
+
+        Some(LatencyStats {
+            min: sorted[0],
+            mean: sorted.iter().sum::<u64>() / len as u64,
+            median,
+            p90: percentile(90),
+            p95: percentile(95),
+            p99: percentile(99),
+            max: sorted[len - 1],
+        })
+    }
+
+    pub fn write_csv(&self, path: &Path) -> io::Result<()> {
+        let mut out = File::create(path)?;
+        out.write_all("min,mean,median,p90,p95,p99,max\n".as_bytes())?;
+        out.write_all(format!("{},{},{},{},{},{},{}\n",
+                              self.min, self.mean, self.median, self.p90, self.p95,
+                              self.p99, self.max)
+            .as_bytes())?;
+        Ok(())
+    }
+}
+
+// The raw samples in nanoseconds, one per row, each with the sim's time step
+// (dt, also nanoseconds) when it was taken, and the physics engine's average
+// refresh rate (Hz) over the whole experiment alongside the first. The file
+// (`path`, plus ".gz" if `gzip`) is synced to disk before this returns.
+pub fn write_latencies(path: &Path, latencies: &[Duration], dts: &[Duration],
+                       refresh_hz: Option<f64>, gzip: bool) -> io::Result<()> {
+    let mut out = LogWriter::create(path, gzip)?;
+    out.write_all(format_latencies(latencies, dts, refresh_hz).as_bytes())?;
+    out.finish()
+}
+
+// The contents of the file `write_latencies` writes
+pub fn format_latencies(latencies: &[Duration], dts: &[Duration], refresh_hz: Option<f64>) -> String {
+    let mut csv = String::from("latencies,dt,refresh\n");
+    for (i, (latency, dt)) in latencies.iter().zip(dts.iter()).enumerate() {
+        csv.push_str(&format!("{},{}", as_nanos(*latency), as_nanos(*dt)));
+        if let (0, Some(hz)) = (i, refresh_hz) {
+            csv.push_str(&format!(",{}", hz));
+        }
+        csv.push('\n');
+    }
+    csv
+}
+
+// Streaming mode (FFSIM_LATENCY_STREAM): each sample is appended to
+// `latencies-<run_id>.csv` as it's measured, for as long as the experiment
+// runs, instead of being collected and written at the end. The file is
+// written from a thread of its own, since we're called from the flight loop.
+pub struct LatencyStream {
+    queue: Option<SyncSender<Duration>>,
+    dropped: u64,
+}
+
+impl LatencyStream {
+    pub fn start(dir: &Path, run_id: u64, gzip: bool) -> Self {
+        let path = dir.join(format!("latencies-{}.csv", run_id));
+        log_info!("Streaming latencies to {}", log_path(&path, gzip).display());
+
+        let (queue, samples) = sync_channel(STREAM_QUEUE_LEN);
+        let writing = WriteInProgress::new();
+        thread::spawn(move || {
+            let _writing = writing;
+            if let Err(e) = stream_latencies(&path, gzip, samples) {
+                log_error!("Couldn't write latencies to {}, giving up on them: {:?}",
+                           log_path(&path, gzip).display(), e);
+            }
+        });
+
+        LatencyStream {
+            queue: Some(queue),
+            dropped: 0,
+        }
+    }
+
+    // Never blocks; if the writer is behind, the sample is dropped
+    pub fn record(&mut self, latency: Duration) {
+        let queue = match self.queue {
+            Some(ref queue) => queue,
+            None => return,
+        };
+
+        match queue.try_send(latency) {
+            Ok(_) => (),
+            Err(TrySendError::Full(_)) => {
+                if self.dropped == 0 {
+                    log_warn!("Latency stream can't keep up, dropping samples");
+                }
+                self.dropped += 1;
+            }
+            // The writer has already given up (and said why)
+            Err(TrySendError::Disconnected(_)) => self.queue = None,
+        }
+    }
+}
+
+// Dropping a `LatencyStream` closes the queue, and the writer finishes off
+// what's in it by itself; we don't wait for it, so as not to hold up the
+// flight loop. (`stop` does, see `wait_for_writes`.)
+impl Drop for LatencyStream {
+    fn drop(&mut self) {
+        if self.dropped > 0 {
+            log_warn!("Latency stream dropped {} samples", self.dropped);
+        }
+    }
+}
+
+fn stream_latencies(path: &Path, gzip: bool, samples: Receiver<Duration>) -> io::Result<()> {
+    let mut out = LogWriter::create(path, gzip)?;
+    out.write_all("latencies\n".as_bytes())?;
+
+    while let Ok(latency) = samples.recv() {
+        out.write_all(format!("{}\n", as_nanos(latency)).as_bytes())?;
+        // Write out whatever has piled up, then let the file catch up so it's
+        // never far behind the experiment
+        for latency in samples.try_iter() {
+            out.write_all(format!("{}\n", as_nanos(latency)).as_bytes())?;
+        }
+        out.flush()?;
+    }
+
+    out.finish()
+}
+
+// Counts of samples per `bucket_us` wide bucket, as (start of the bucket in
+// microseconds, count). Runs from the fastest sample's bucket to the slowest's,
+// including any empty buckets in between, so it reads as a bar chart.
+pub fn histogram(samples: &[Duration], bucket_us: u64) -> Vec<(u64, usize)> {
+    let bucket_us = bucket_us.max(1);
+    let bucket_of = |dur: &Duration| as_nanos(*dur) / 1000 / bucket_us;
+
+    let first = match samples.iter().map(&bucket_of).min() {
+        Some(first) => first,
+        None => return Vec::new(),
+    };
+    let last = samples.iter().map(&bucket_of).max().unwrap_or(first);
+
+    let mut counts = vec![0usize; (last - first + 1) as usize];
+    for sample in samples {
+        counts[(bucket_of(sample) - first) as usize] += 1;
+    }
+
+    counts.into_iter().enumerate()
+        .map(|(i, count)| ((first + i as u64) * bucket_us, count))
+        .collect()
+}
+
+pub fn write_histogram(path: &Path, histogram: &[(u64, usize)]) -> io::Result<()> {
+    let mut out = File::create(path)?;
+    out.write_all("bucket_us,count\n".as_bytes())?;
+    for &(bucket, count) in histogram {
+        out.write_all(format!("{},{}\n", bucket, count).as_bytes())?;
+    }
+    Ok(())
+}
+
+// Where to put the results. Falls back to the working directory if the
+// configured one doesn't exist, rather than losing the whole experiment.
+pub fn output_dir(configured: &Option<PathBuf>) -> PathBuf {
+    match *configured {
+        Some(ref dir) if dir.is_dir() => dir.clone(),
+        Some(ref dir) => {
+            log_warn!("Latency output directory {:?} doesn't exist, using the working directory",
+                      dir);
+            PathBuf::from(".")
+        }
+        None => PathBuf::from("."),
+    }
+}
+
+pub fn as_nanos(dur: Duration) -> u64 {
+    dur.as_secs() * 1_000_000_000 + dur.subsec_nanos() as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn latencies_csv() {
+        let latencies = [Duration::from_micros(1500), Duration::new(2, 5)];
+        let dts = [Duration::from_millis(20), Duration::from_millis(19)];
+
+        let csv = format_latencies(&latencies, &dts, Some(50.0));
+        let rows: Vec<&str> = csv.lines().collect();
+        assert_eq!(rows, vec!["latencies,dt,refresh",
+                              "1500000,20000000,50",
+                              "2000000005,19000000"]);
+        assert!(csv.ends_with('\n'));
+    }
+
+    #[test]
+    fn latencies_csv_without_refresh_rate() {
+        let csv = format_latencies(&[Duration::from_nanos(7)], &[Duration::from_nanos(3)], None);
+        assert_eq!(csv, "latencies,dt,refresh\n7,3\n");
+    }
+
+    fn micros(values: &[u64]) -> Vec<Duration> {
+        values.iter().map(|&us| Duration::from_micros(us)).collect()
+    }
+
+    #[test]
+    fn stats_of_nothing() {
+        assert!(LatencyStats::compute(&[]).is_none());
+    }
+
+    #[test]
+    fn stats_of_one_sample() {
+        let stats = LatencyStats::compute(&micros(&[7])).unwrap();
+        for &value in &[stats.min, stats.mean, stats.median, stats.p90, stats.p95, stats.p99, stats.max] {
+            assert_eq!(value, 7000);
+        }
+    }
+
+    #[test]
+    fn nearest_rank_percentiles_of_100_samples() {
+        // 1 to 100 us, shuffled so the sort is exercised
+        let mut values: Vec<u64> = (1 ..= 100).collect();
+        values.reverse();
+        values.swap(3, 70);
+        let stats = LatencyStats::compute(&micros(&values)).unwrap();
+
+        assert_eq!(stats.min, 1000);
+        assert_eq!(stats.max, 100_000);
+        assert_eq!(stats.mean, 50_500);
+        assert_eq!(stats.median, 50_500);
+        assert_eq!(stats.p90, 90_000);
+        assert_eq!(stats.p95, 95_000);
+        assert_eq!(stats.p99, 99_000);
+    }
+
+    #[test]
+    fn nearest_rank_rounds_up() {
+        // The 90th percentile of 3 samples is the 3rd (ceil(2.7)), not the 2nd
+        let stats = LatencyStats::compute(&micros(&[1, 2, 3])).unwrap();
+        assert_eq!(stats.median, 2000);
+        assert_eq!(stats.p90, 3000);
+    }
+
+    #[test]
+    fn histogram_of_nothing() {
+        assert!(histogram(&[], 100).is_empty());
+    }
+
+    #[test]
+    fn histogram_of_one_sample() {
+        assert_eq!(histogram(&micros(&[250]), 100), vec![(200, 1)]);
+    }
+
+    #[test]
+    fn histogram_includes_empty_buckets() {
+        assert_eq!(histogram(&micros(&[120, 150, 199, 420]), 100),
+                   vec![(100, 3), (200, 0), (300, 0), (400, 1)]);
+    }
+
+    #[test]
+    fn zero_bucket_width_means_1us() {
+        assert_eq!(histogram(&micros(&[3, 5, 5]), 0), vec![(3, 1), (4, 0), (5, 2)]);
+    }
+}
@@ -2,37 +2,85 @@
 extern crate xplm;
 use xplm::plugin::{Plugin, PluginInfo};
 
-use xplm::data::borrowed::{DataRef, FindError};
+use xplm::data::borrowed::DataRef;
+use xplm::data::owned::OwnedData;
 use xplm::data::{ReadOnly, ReadWrite, DataRead, DataReadWrite, ArrayRead};
+use xplm::command::{OwnedCommand, CommandHandler};
 use xplm::flight_loop::FlightLoop;
-use triple_buffer::{TripleBuffer, Input, Output};
+use triple_buffer::{TripleBuffer, Input};
 use std::thread;
-use std::sync::atomic::{AtomicBool, Ordering, ATOMIC_BOOL_INIT};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, Mutex};
-use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::time::{Duration, SystemTime};
 
+#[macro_use]
+mod log;
+
+mod aircraft;
 mod buffered_control_data;
 mod buffered_flight_data;
+mod calibration;
+mod clock;
 mod control_data;
+mod control_queue;
 mod flight_data;
 mod quaternion;
 mod comm;
+mod config;
+mod crc;
+mod error;
 mod flight_loop;
+mod imu;
+mod latency;
+mod link;
+mod log_file;
+mod loopback;
+mod menu;
+mod nmea;
+mod replay;
+mod telemetry_log;
+mod wire;
 
 use self::buffered_control_data::BufferedControlData;
 use self::buffered_flight_data::BufferedFlightData;
 use self::control_data::ControlData;
 use self::flight_data::FlightData;
 use self::quaternion::Quaternion;
+use self::aircraft::{ControlDatarefs, LinkedSurface, BUILTIN_PROFILES};
+use self::calibration::Calibration;
+use self::comm::{LinkStats, StopSignal};
+use self::control_queue::{control_channel, ControlReceiver};
+use self::config::Config;
+use self::error::StartError;
 use self::flight_loop::flight_loop;
+use self::latency::LatencyStream;
+use self::link::SharedLink;
+use self::menu::StatusMenu;
+use self::replay::Replay;
+use self::wire::PROTOCOL_VERSION;
 
 extern crate triple_buffer;
 extern crate serial;
-
-pub static STOP_THREADS: AtomicBool = ATOMIC_BOOL_INIT;
-
+#[cfg(feature = "gzip")]
+extern crate flate2;
+
+// Set by the `ffsim/latency/start` command, picked up by the flight loop
+pub static RESTART_LATENCY: AtomicBool = AtomicBool::new(false);
+// Set by the `ffsim/toggle_override` command, picked up by the flight loop
+pub static TOGGLE_OVERRIDE: AtomicBool = AtomicBool::new(false);
+// Likewise `ffsim/toggle_surfaces` and `ffsim/toggle_throttle`
+pub static TOGGLE_SURFACES: AtomicBool = AtomicBool::new(false);
+pub static TOGGLE_THROTTLE: AtomicBool = AtomicBool::new(false);
+// ... and `ffsim/pause_tx` (or the menu item of the same name)
+pub static TOGGLE_PAUSE_TX: AtomicBool = AtomicBool::new(false);
+
+// Number of engines whose throttles are controlled. Changing this changes the
+// layout of `ControlData`, so the FPGA side needs to be updated to match.
+pub const NUM_ENGINES: usize = 1;
+
+// Default number of samples per latency experiment; can be changed at runtime
+// through the `ffsim/latency/samples` dataref.
 pub const NUM_LATENCY_MEASUREMENTS: usize = 100;
-const SACRIFICE_LATENCY_MEASUREMENTS: usize = 100; // initially the latency is very erratic
 
 pub struct FFSim {
     // overrides all flight control, i.e. throttle, control surfaces etc.
@@ -41,15 +89,34 @@ pub struct FFSim {
     override_control_surfaces: DataRef<bool, ReadWrite>,
     // overrides only the throttle
     override_throttles: DataRef<bool, ReadWrite>,
-
-    // control surfaces
-    rudder: DataRef<f32, ReadWrite>, // XXX: Only the "left rudder" seems to have an effect on the plane
-    left_aileron: DataRef<f32, ReadWrite>,
-    right_aileron: DataRef<f32, ReadWrite>,
-    elevator1: DataRef<f32, ReadWrite>, // XXX: The elevators can be controlled independently ..
-    elevator2: DataRef<f32, ReadWrite>,
+    // overrides the wheel brakes and nosewheel steering
+    override_toe_brakes: DataRef<bool, ReadWrite>,
+    override_wheel_steer: DataRef<bool, ReadWrite>,
+
+    // control surfaces; None if the aircraft doesn't have them
+    rudder: Option<DataRef<f32, ReadWrite>>, // XXX: Only the "left rudder" seems to have an effect on the plane
+    left_aileron: Option<DataRef<f32, ReadWrite>>,
+    right_aileron: Option<DataRef<f32, ReadWrite>>,
+    elevator1: Option<DataRef<f32, ReadWrite>>, // left; the elevators are controlled independently
+    elevator2: Option<DataRef<f32, ReadWrite>>, // right
+    // more ailerons, moved with the ones above, and how much by
+    left_aileron_extra: Vec<(DataRef<f32, ReadWrite>, f32)>,
+    right_aileron_extra: Vec<(DataRef<f32, ReadWrite>, f32)>,
+
+    // flap and speedbrake handles, [0, 1]; the sim moves the surfaces to match
+    flaps: Option<DataRef<f32, ReadWrite>>,
+    spoilers: Option<DataRef<f32, ReadWrite>>,
+
+    // ground handling
+    left_brake: DataRef<f32, ReadWrite>,  // [0, 1]
+    right_brake: DataRef<f32, ReadWrite>, // ...
+    tire_steer: DataRef<[f32], ReadWrite>, // degrees, one per gear; the nosewheel is first
+    on_ground: DataRef<i32, ReadOnly>,    // non-zero if any wheel is on the ground
 
     throttle: DataRef<[f32], ReadWrite>,
+    // 1 for normal thrust, 3 for reverse; only touched if a reverse region is
+    // configured (and None if the aircraft has no reversers to speak of)
+    prop_mode: Option<DataRef<[i32], ReadWrite>>,
 
     // flight controller inputs
     roll_rate: DataRef<f32, ReadOnly>,  // degrees/second
@@ -64,40 +131,94 @@ pub struct FFSim {
     local_ay: DataRef<f32, ReadOnly>,
     local_az: DataRef<f32, ReadOnly>,
     plane_orientation_quaternion: DataRef<[f32], ReadOnly>, // XXX: Remember to negate non-scalar parts
+    // What we sent last time, if `config.quaternion_smoothing` is in use
+    smoothed_quaternion: Option<Quaternion>,
+    // Whether the last read of it came up short, see `get_data`
+    short_quaternion: bool,
 
     latitude: DataRef<f64, ReadOnly>,  // degrees
     longitude: DataRef<f64, ReadOnly>, // ...
+    elevation: DataRef<f64, ReadOnly>, // metres above MSL
+    vh_ind: DataRef<f32, ReadOnly>,    // metres/second, vertical speed (up is positive)
+    groundspeed: DataRef<f32, ReadOnly>, // metres/second
+    hpath: DataRef<f32, ReadOnly>,       // degrees true, direction of travel over the ground
+    wind_speed: DataRef<f32, ReadOnly>,     // knots, at the aircraft
+    wind_direction: DataRef<f32, ReadOnly>, // degrees magnetic, where the wind comes from
 
     indicated_airspeed: DataRef<f32, ReadOnly>, // knot indicated airspeed
+    true_airspeed: DataRef<f32, ReadOnly>,      // metres/second
+    alpha: DataRef<f32, ReadOnly>, // degrees, angle of attack
+    beta: DataRef<f32, ReadOnly>,  // degrees, sideslip
     barometer_inhg: DataRef<f32, ReadOnly>,
 
     temperature_ambient_c: DataRef<f32, ReadOnly>, // temp outside the aircraft
     //temperature_le_c: DataRef<f32, ReadOnly>,      // temp at the leading edge of the wing
-    air_density: DataRef<f32, ReadOnly>, // kg / m^3
+    air_density: DataRef<f32, ReadOnly>,       // kg / m^3, at sea level
+    local_air_density: DataRef<f32, ReadOnly>, // kg / m^3, at the aircraft
 
     // Buffers for bidirectional communication
-    incoming: Output<BufferedControlData>,
+    incoming: ControlReceiver,
     outgoing: Input<BufferedFlightData>,
 
+    // False once the watchdog has given up on the controller (or before we've heard from it)
+    controller_alive: bool,
+    // Whether we've ever heard from the controller; we don't touch the controls until then
+    have_valid_control: bool,
+    // Whether the controller is flying the aircraft (true) or the user is. Starts
+    // out false in passive mode, and is flipped by `ffsim/toggle_override`.
+    in_control: bool,
+    _toggle_override: OwnedCommand,
+    // Which of the controls the controller has while it's `in_control`: the
+    // control surfaces (including flaps, spoilers, brakes and steering) and
+    // the throttle. Both by default, but either can be left to the user.
+    controls_surfaces: bool,
+    controls_throttle: bool,
+    _toggle_surfaces: OwnedCommand,
+    _toggle_throttle: OwnedCommand,
+    // Set while the send thread is holding back telemetry (but staying
+    // connected), e.g. to test the controller's watchdog
+    tx_paused: Arc<AtomicBool>,
+    _pause_tx: OwnedCommand,
+    // Rudder, ailerons and elevators as last set, for `config.slew_rate_deg_s`.
+    // None when we haven't been setting them.
+    last_surfaces: Option<[f32; 5]>,
+
     fl: FlightLoop,
-    ser: Arc<Mutex<Option<serial::SystemPort>>>,
+    link: Arc<SharedLink>,
+    serial_port: Arc<Mutex<String>>, // may differ from `config` if changed through the menu
+    stop: Arc<StopSignal>,           // shuts down the comm threads
+    threads: Vec<thread::JoinHandle<()>>, // ... which are joined in `stop()`
+    config: Config,
+    menu: StatusMenu,
+
+    // Link quality, counted by the receive thread and published as
+    // ffsim/stats/... datarefs each flight loop
+    link_stats: Arc<LinkStats>,
+    stats_frames: OwnedData<i32>,
+    stats_valid_frames: OwnedData<i32>,
+    stats_bad_headers: OwnedData<i32>,
+    stats_crc_errors: OwnedData<i32>,
 
     // latency measurement
-    latencies: [Duration; NUM_LATENCY_MEASUREMENTS],
+    latencies: Vec<Duration>,
+    // Samples taken so far; starts at minus the warm-up count (see
+    // `Config::latency_warmup`), so it reaches 0 as recording begins
     num_latencies: isize,
-    last_time: SystemTime,
-
-    // physics engine rate update measurement
-    time_start: SystemTime,
-    cycle_count: usize,
+    latency_samples: OwnedData<i32, ReadWrite>, // samples to take next time the experiment is started
+    _latency_start: OwnedCommand, // re-arms the experiment
+    latency_stream: Option<LatencyStream>, // in streaming mode, once the warm-up is over
+    last_time: u64,
+
+    // physics engine rate update measurement: the sim's time step when each
+    // latency sample was taken, and the number and total length of the
+    // steps over the whole experiment
+    latency_dts: Vec<Duration>,
+    loop_count: usize,
+    loop_time: Duration,
 }
 
 impl FFSim {
-    pub fn get_data(&self, time: SystemTime) -> BufferedFlightData {
-        // Throttle: we are only interested in first value
-        let mut throttle_buf: [f32; 4] = [0.0; 4];
-        self.throttle.get(&mut throttle_buf);
-
+    pub fn get_data(&mut self, time: u64) -> BufferedFlightData {
         let mut ret = BufferedFlightData {
             roll_rate: self.roll_rate.get(),
             pitch_rate: self.pitch_rate.get(),
@@ -111,49 +232,356 @@ impl FFSim {
             plane_orientation_quaternion: [0.0; 4],
             latitude: self.latitude.get(),
             longitude: self.longitude.get(),
+            elevation: self.elevation.get(),
+            vertical_speed: self.vh_ind.get(),
+            groundspeed: self.groundspeed.get(),
+            track: self.hpath.get(),
+            wind_speed: self.wind_speed.get(),
+            wind_direction: self.wind_direction.get(),
             indicated_airspeed: self.indicated_airspeed.get(),
+            true_airspeed: self.true_airspeed.get(),
+            alpha: self.alpha.get(),
+            beta: self.beta.get(),
             barometer_inhg: self.barometer_inhg.get(),
             ambient_temp: self.temperature_ambient_c.get(),
             air_density: self.air_density.get(),
+            local_air_density: self.local_air_density.get(),
+            actual_rudder: self.rudder.as_ref().map(|dataref| dataref.get()),
+            actual_left_aileron: self.left_aileron.as_ref().map(|dataref| dataref.get()),
+            actual_right_aileron: self.right_aileron.as_ref().map(|dataref| dataref.get()),
+            actual_left_elevator: self.elevator1.as_ref().map(|dataref| dataref.get()),
+            actual_right_elevator: self.elevator2.as_ref().map(|dataref| dataref.get()),
             time,
+            utc: SystemTime::now(),
         };
 
-        self.plane_orientation_quaternion.get(&mut ret.plane_orientation_quaternion);
+        // `q` should always have 4 elements, but if it ever comes up short, fly
+        // level (the identity) rather than on whatever was left in the array
+        let mut quaternion = [0f32; 4];
+        let read = self.plane_orientation_quaternion.get(&mut quaternion);
+        let full = orientation(quaternion, read);
+        let short_quaternion = full.is_none();
+        ret.plane_orientation_quaternion = full.unwrap_or([1.0, 0.0, 0.0, 0.0]);
+        // (only logged when it changes, since we're called every frame)
+        if short_quaternion && !self.short_quaternion {
+            log_warn!("Orientation quaternion only has {} elements, sending the identity", read);
+        } else if !short_quaternion && self.short_quaternion {
+            log_info!("Orientation quaternion is back");
+        }
+        self.short_quaternion = short_quaternion;
+
+        // The sim's orientation jitters a little from frame to frame, which shows
+        // up as noise in the accelerometer readings. Smoothing it is optional,
+        // since it also makes the orientation lag behind.
+        let smoothing = self.config.quaternion_smoothing;
+        if smoothing < 1f32 {
+            let smoothed = smooth_orientation(self.smoothed_quaternion,
+                                              ret.plane_orientation_quaternion, smoothing);
+            self.smoothed_quaternion = Some(smoothed);
+            ret.plane_orientation_quaternion = smoothed.as_array();
+        }
 
         ret
     }
 }
 
+impl FFSim {
+    // Whether the sim should listen to us (true) or the user (false), for
+    // whichever controls are ours (see `controls_surfaces`)
+    fn set_overrides(&mut self, enabled: bool) {
+        let surfaces = enabled && self.controls_surfaces;
+        let throttle = enabled && self.controls_throttle;
+
+        //self.override_flightcontrol.set(enabled);
+        self.override_control_surfaces.set(surfaces);
+        self.override_throttles.set(throttle);
+        self.override_toe_brakes.set(surfaces);
+        self.override_wheel_steer.set(surfaces);
+    }
+
+    // Hands control of the aircraft to the controller if the user has it, and
+    // vice versa.
+    pub fn toggle_override(&mut self) {
+        self.in_control = !self.in_control;
+        self.set_overrides(self.in_control);
+        if self.in_control {
+            log_info!("Controller has taken control of the aircraft");
+        } else {
+            log_info!("Handing control of the aircraft back to the user");
+        }
+    }
+
+    // Hands just the control surfaces, or just the throttle, back and forth in
+    // the same way. Takes effect straight away if the controller is in
+    // control, or when it next takes control otherwise.
+    pub fn toggle_surfaces(&mut self) {
+        self.controls_surfaces = !self.controls_surfaces;
+        self.set_overrides(self.in_control);
+        log_info!("Control surfaces are now flown by the {}",
+                  if self.controls_surfaces { "controller" } else { "user" });
+    }
+
+    pub fn toggle_throttle(&mut self) {
+        self.controls_throttle = !self.controls_throttle;
+        self.set_overrides(self.in_control);
+        log_info!("Throttle is now set by the {}",
+                  if self.controls_throttle { "controller" } else { "user" });
+    }
+
+    // Stops or restarts the flight data going to the controller. The link
+    // stays up and we carry on listening to the controller.
+    pub fn toggle_pause_tx(&mut self) {
+        let paused = !self.tx_paused.load(Ordering::SeqCst);
+        self.tx_paused.store(paused, Ordering::SeqCst);
+        if paused {
+            log_info!("Pausing telemetry");
+        } else {
+            log_info!("Resuming telemetry");
+        }
+    }
+
+    // Reads every dataref once and logs any that give NaN, infinity or something
+    // physically implausible, so an airframe we can't handle shows up in the log
+    // straight away rather than as garbage telemetry. Control surfaces that
+    // weren't found were already logged by `find_control_surface`.
+    fn self_test(&self) {
+        // (name, value, plausible range)
+        let mut readings: Vec<(&str, f64, f64, f64)> = Vec::new();
+        let mut read = |name, value: f64, min: f64, max: f64| readings.push((name, value, min, max));
+
+        let surfaces = [("rudder", &self.rudder), ("left_aileron", &self.left_aileron),
+                        ("right_aileron", &self.right_aileron), ("elevator1", &self.elevator1),
+                        ("elevator2", &self.elevator2), ("flaps", &self.flaps),
+                        ("spoilers", &self.spoilers)];
+        for &(name, surface) in surfaces.iter() {
+            if let Some(ref dataref) = *surface {
+                read(name, dataref.get() as f64, -180.0, 180.0);
+            }
+        }
+        for &(name, extras) in [("left_aileron_extra", &self.left_aileron_extra),
+                                ("right_aileron_extra", &self.right_aileron_extra)].iter() {
+            for &(ref dataref, _) in extras.iter() {
+                read(name, dataref.get() as f64, -180.0, 180.0);
+            }
+        }
+        read("left_brake", self.left_brake.get() as f64, 0.0, 1.0);
+        read("right_brake", self.right_brake.get() as f64, 0.0, 1.0);
+
+        let mut tire_steer = vec![0f32; self.tire_steer.len()];
+        self.tire_steer.get(&mut tire_steer);
+        for val in tire_steer {
+            read("tire_steer", val as f64, -180.0, 180.0);
+        }
+        let mut throttle = vec![0f32; self.throttle.len()];
+        self.throttle.get(&mut throttle);
+        for val in throttle {
+            read("throttle", val as f64, -1.0, 1.5);
+        }
+        let mut quaternion = [0f32; 4];
+        self.plane_orientation_quaternion.get(&mut quaternion);
+        for val in quaternion.iter() {
+            read("plane_orientation_quaternion", *val as f64, -1.01, 1.01);
+        }
+
+        // Same limits as `BufferedFlightData::sanitized`, where it has them
+        read("roll_rate", self.roll_rate.get() as f64, -1e4, 1e4);
+        read("pitch_rate", self.pitch_rate.get() as f64, -1e4, 1e4);
+        read("yaw_rate", self.yaw_rate.get() as f64, -1e4, 1e4);
+        read("true_theta", self.true_theta.get() as f64, -90.0, 90.0);
+        read("true_phi", self.true_phi.get() as f64, -180.0, 180.0);
+        read("mag_psi", self.mag_psi.get() as f64, -360.0, 360.0);
+        read("local_ax", self.local_ax.get() as f64, -1e3, 1e3);
+        read("local_ay", self.local_ay.get() as f64, -1e3, 1e3);
+        read("local_az", self.local_az.get() as f64, -1e3, 1e3);
+        read("latitude", self.latitude.get(), -90.0, 90.0);
+        read("longitude", self.longitude.get(), -180.0, 180.0);
+        read("elevation", self.elevation.get(), -1e6, 1e6);
+        read("vh_ind", self.vh_ind.get() as f64, -500.0, 500.0);
+        read("groundspeed", self.groundspeed.get() as f64, 0.0, 1000.0);
+        read("hpath", self.hpath.get() as f64, -360.0, 360.0);
+        read("wind_speed", self.wind_speed.get() as f64, 0.0, 500.0);
+        read("wind_direction", self.wind_direction.get() as f64, -360.0, 360.0);
+        read("indicated_airspeed", self.indicated_airspeed.get() as f64, 0.0, 2000.0);
+        read("true_airspeed", self.true_airspeed.get() as f64, 0.0, 1000.0);
+        read("alpha", self.alpha.get() as f64, -180.0, 180.0);
+        read("beta", self.beta.get() as f64, -180.0, 180.0);
+        read("barometer_inhg", self.barometer_inhg.get() as f64, 7.5, 32.5);
+        read("temperature_ambient_c", self.temperature_ambient_c.get() as f64, -100.0, 100.0);
+        read("air_density", self.air_density.get() as f64, 0.0, 2.0);
+        read("local_air_density", self.local_air_density.get() as f64, 0.0, 2.0);
+
+        let bad: Vec<String> = readings.iter()
+            .filter(|&&(_, value, min, max)| !value.is_finite() || value < min || value > max)
+            .map(|&(name, value, _, _)| format!("{} = {}", name, value))
+            .collect();
+
+        if bad.is_empty() {
+            log_info!("Self test: {}/{} dataref readings OK", readings.len(), readings.len());
+        } else {
+            log_warn!("Self test: {}/{} dataref readings OK, implausible: {}",
+                      readings.len() - bad.len(), readings.len(), bad.join(", "));
+        }
+    }
+
+    // Throws away any measurements taken so far and starts a new latency
+    // experiment (including the warm-up period).
+    pub fn restart_latency_experiment(&mut self) {
+        let samples = self.latency_samples.get().max(1) as usize;
+        log_info!("Starting latency experiment with {} samples", samples);
+
+        self.latencies = vec![Duration::from_millis(0); samples];
+        self.latency_dts = vec![Duration::from_millis(0); samples];
+        self.num_latencies = - (self.config.latency_warmup as isize);
+        // (a streamed run starts a new file after its warm-up)
+        self.latency_stream = None;
+        self.last_time = 0;
+        self.loop_count = 0;
+        self.loop_time = Duration::from_millis(0);
+    }
+}
+
+fn find_control_surface(name: &str) -> Option<DataRef<f32, ReadWrite>> {
+    match DataRef::find(name).and_then(|dataref| dataref.writeable()) {
+        Ok(dataref) => Some(dataref),
+        Err(e) => {
+            log_warn!("Control surface {} not available ({:?}), it won't be controlled", name, e);
+            None
+        }
+    }
+}
+
+// What X-Plane's plugin admin shows for us, with the versions to quote in a
+// bug report
+fn description() -> String {
+    format!("Flight simulator integration for FPGA/HIL, version {} (protocol {})",
+            env!("CARGO_PKG_VERSION"), PROTOCOL_VERSION)
+}
+
+// `quaternion` as read from the sim, if `read` says it was all there
+fn orientation(quaternion: [f32; 4], read: usize) -> Option<[f32; 4]> {
+    if read == quaternion.len() {
+        Some(quaternion)
+    } else {
+        None
+    }
+}
+
+// Moves `smoothing` of the way from the orientation we sent last time (if
+// any) towards the sim's `current` one
+fn smooth_orientation(previous: Option<Quaternion>, current: [f32; 4], smoothing: f32) -> Quaternion {
+    let current = Quaternion::new(current).normalize();
+    match previous {
+        Some(previous) => Quaternion::slerp(previous, current, smoothing),
+        None => current,
+    }
+}
+
+// As `find_control_surface`, leaving out the ones that aren't there
+fn find_linked_surfaces(surfaces: &[LinkedSurface]) -> Vec<(DataRef<f32, ReadWrite>, f32)> {
+    surfaces.iter()
+        .filter_map(|surface| find_control_surface(&surface.dataref).map(|dataref| (dataref, surface.scale)))
+        .collect()
+}
+
+// Our commands just raise a flag for the flight loop to act on, since that's
+// where we can safely get at the plugin
+struct FlagHandler(&'static AtomicBool);
+
+impl CommandHandler for FlagHandler {
+    fn command_begin(&mut self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+    fn command_continue(&mut self) {}
+    fn command_end(&mut self) {}
+}
+
 impl Plugin for FFSim {
-    type StartErr = FindError;
+    type StartErr = StartError;
     fn start() -> Result<Self, Self::StartErr> {
         /* Initialize triple buffers */
-        let (incoming_send, incoming_recv)
-            = TripleBuffer::new(BufferedControlData::new()).split();
         let (outgoing_send, outgoing_recv)
             = TripleBuffer::new(BufferedFlightData::new()).split();
 
-        let ser: Arc<Mutex<Option<serial::SystemPort>>> = Arc::new(Mutex::new(None));
+        let link = Arc::new(SharedLink::new());
+        link.subscribe(|old, new| log_info!("Link {} (was {})", new, old));
+
+        let mut config = Config::from_env();
+        log::init(config.log_level, &config.log_file);
+        match config.tcp_addr {
+            _ if config.loopback => log_info!("Loopback mode: answering our own flight data"),
+            Some(ref addr) => log_info!("Using TCP connection to {}", addr),
+            None => log_info!("Using serial port {} at {} baud",
+                              config.serial_port, config.baud_rate),
+        }
+
+        // (a triple buffer too, unless configured otherwise)
+        let (incoming_send, incoming_recv) = control_channel(config.control_queue_len);
+        if config.control_queue_len > 0 {
+            log_info!("Queueing up to {} commands from the controller", config.control_queue_len);
+        }
+
+        if let Some(ref path) = config.calibration_file {
+            config.calibration = Calibration::load(path).map_err(StartError::Config)?;
+            log_info!("Using IMU calibration from {}", path.display());
+        }
+
+        let replay = match config.replay_file {
+            Some(ref path) => {
+                let replay = Replay::load(path).map_err(StartError::Config)?;
+                log_info!("Replaying {} frames of telemetry from {}", replay.len(), path.display());
+                Some(replay)
+            }
+            None => None,
+        };
+
+        let serial_port = Arc::new(Mutex::new(config.serial_port.clone()));
+        let menu = StatusMenu::new(&config, serial_port.clone(), link.clone())?;
+
+        let profile = config.profile.clone().ok_or_else(|| StartError::Config(
+            format!("unknown aircraft profile {:?}, expected one of {}",
+                    config.profile_name, BUILTIN_PROFILES.join(", "))))?;
+        if profile.engines != NUM_ENGINES {
+            return Err(StartError::Config(
+                format!("aircraft profile {} has {} engines, but this build controls {}",
+                        profile.name, profile.engines, NUM_ENGINES)));
+        }
+        log_info!("Using aircraft profile {}", profile.name);
+
+        let controls = ControlDatarefs::load(&config.datarefs_file, profile.controls)
+            .map_err(StartError::Config)?;
+
+        let latency_warmup = config.latency_warmup;
 
         /* Get handles to datarefs */
         let mut plugin = FFSim {
             //override_flightcontrol: DataRef::find("sim/operation/override/override_flightcontrol")?.writeable()?,
             override_control_surfaces: DataRef::find("sim/operation/override/override_control_surfaces")?.writeable()?,
             override_throttles: DataRef::find("sim/operation/override/override_throttles")?.writeable()?,
+            override_toe_brakes: DataRef::find("sim/operation/override/override_toe_brakes")?.writeable()?,
+            override_wheel_steer: DataRef::find("sim/operation/override/override_wheel_steer")?.writeable()?,
 
-            // XXX: These are based on the Cessna Skyhawk. For other planes you may need to
-            //      change which datarefs are used to move the control surfaces!
+            // See aircraft.rs for which datarefs these are by default.
             //
-            // Also while we're on the subject. A name like hstab1_elv1def means:
-            //  * The control surfaces is attached to the horizontal (h) stabilizer (stab)
-            //  * The control surface moves when the elevator (elv) command is sent from the yoke.
-            rudder: DataRef::find("sim/flightmodel/controls/vstab1_rud1def")?.writeable()?,
-            left_aileron: DataRef::find("sim/flightmodel/controls/wing1l_ail1def")?.writeable()?,
-            right_aileron: DataRef::find("sim/flightmodel/controls/wing1r_ail1def")?.writeable()?,
-            elevator1: DataRef::find("sim/flightmodel/controls/hstab1_elv1def")?.writeable()?,
-            elevator2: DataRef::find("sim/flightmodel/controls/hstab2_elv1def")?.writeable()?,
+            // If any of these are missing we carry on without them, so that we can
+            // at least send telemetry from aircraft we don't know how to fly.
+            rudder: find_control_surface(&controls.rudder),
+            left_aileron: find_control_surface(&controls.left_aileron),
+            right_aileron: find_control_surface(&controls.right_aileron),
+            elevator1: find_control_surface(&controls.elevator1),
+            elevator2: find_control_surface(&controls.elevator2),
+            left_aileron_extra: find_linked_surfaces(&controls.left_aileron_extra),
+            right_aileron_extra: find_linked_surfaces(&controls.right_aileron_extra),
+            flaps: find_control_surface("sim/flightmodel/controls/flaprqst"),
+            spoilers: find_control_surface("sim/flightmodel/controls/sbrkrqst"),
+
+            left_brake: DataRef::find("sim/flightmodel/controls/l_brake_add")?.writeable()?,
+            right_brake: DataRef::find("sim/flightmodel/controls/r_brake_add")?.writeable()?,
+            tire_steer: DataRef::find("sim/flightmodel/parts/tire_steer_cmd")?.writeable()?,
+            on_ground: DataRef::find("sim/flightmodel/failures/onground_any")?,
 
             throttle: DataRef::find("sim/flightmodel/engine/ENGN_thro_use")?.writeable()?,
+            prop_mode: DataRef::find("sim/flightmodel/engine/ENGN_propmode")
+                .and_then(|dataref| dataref.writeable()).ok(),
 
             // append "rad" to the end of the names to get these in radians
             roll_rate: DataRef::find("sim/flightmodel/position/P")?,
@@ -168,49 +596,124 @@ impl Plugin for FFSim {
             local_ay: DataRef::find("sim/flightmodel/position/local_ay")?,
             local_az: DataRef::find("sim/flightmodel/position/local_az")?,
             plane_orientation_quaternion: DataRef::find("sim/flightmodel/position/q")?,
+            smoothed_quaternion: None,
+            short_quaternion: false,
 
             latitude: DataRef::find("sim/flightmodel/position/latitude")?,
             longitude: DataRef::find("sim/flightmodel/position/longitude")?,
+            elevation: DataRef::find("sim/flightmodel/position/elevation")?,
+            vh_ind: DataRef::find("sim/flightmodel/position/vh_ind")?,
+            groundspeed: DataRef::find("sim/flightmodel/position/groundspeed")?,
+            hpath: DataRef::find("sim/flightmodel/position/hpath")?,
+            // (sim/weather/wind_* are per layer; these are what the aircraft is actually in)
+            wind_speed: DataRef::find("sim/cockpit2/gauges/indicators/wind_speed_kts")?,
+            wind_direction: DataRef::find("sim/cockpit2/gauges/indicators/wind_heading_deg_mag")?,
 
             indicated_airspeed: DataRef::find("sim/flightmodel/position/indicated_airspeed")?, // XXX: Can have a "2" at the end?
+            true_airspeed: DataRef::find("sim/flightmodel/position/true_airspeed")?,
+            alpha: DataRef::find("sim/flightmodel/position/alpha")?,
+            beta: DataRef::find("sim/flightmodel/position/beta")?,
             barometer_inhg: DataRef::find("sim/weather/barometer_current_inhg")?,
             temperature_ambient_c: DataRef::find("sim/weather/temperature_ambient_c")?,
             //temperature_le_c: DataRef::find("sim/weather/temperature_le_c")?,
             air_density: DataRef::find("sim/physics/rho_sea_level")?,
+            local_air_density: DataRef::find("sim/weather/rho")?,
 
             incoming: incoming_recv,
             outgoing: outgoing_send,
 
+            controller_alive: false,
+            have_valid_control: false,
+            in_control: !config.passive,
+            _toggle_override: OwnedCommand::new("ffsim/toggle_override",
+                                                "Toggle whether FFSim or the user flies the aircraft",
+                                                FlagHandler(&TOGGLE_OVERRIDE))?,
+            controls_surfaces: config.override_surfaces,
+            controls_throttle: config.override_throttle,
+            _toggle_surfaces: OwnedCommand::new("ffsim/toggle_surfaces",
+                                                "Toggle whether FFSim or the user moves the control surfaces",
+                                                FlagHandler(&TOGGLE_SURFACES))?,
+            _toggle_throttle: OwnedCommand::new("ffsim/toggle_throttle",
+                                                "Toggle whether FFSim or the user sets the throttle",
+                                                FlagHandler(&TOGGLE_THROTTLE))?,
+            tx_paused: Arc::new(AtomicBool::new(false)),
+            _pause_tx: OwnedCommand::new("ffsim/pause_tx",
+                                         "Pause or resume sending telemetry to the controller",
+                                         FlagHandler(&TOGGLE_PAUSE_TX))?,
+            last_surfaces: None,
+
             /* Read control inputs and write flight data to the buffers every flight cycle */
             fl: FlightLoop::new(flight_loop),
 
-            ser: ser.clone(),
-
-            latencies: [Duration::from_millis(0); NUM_LATENCY_MEASUREMENTS],
-            num_latencies: - (SACRIFICE_LATENCY_MEASUREMENTS as isize),
-            last_time: UNIX_EPOCH,
-
-            time_start: UNIX_EPOCH,
-            cycle_count: 0,
+            link: link.clone(),
+            serial_port: serial_port.clone(),
+            stop: Arc::new(StopSignal::new()),
+            threads: Vec::new(),
+            config,
+            menu,
+
+            link_stats: Arc::new(LinkStats::new()),
+            stats_frames: OwnedData::create("ffsim/stats/frames")?,
+            stats_valid_frames: OwnedData::create("ffsim/stats/valid_frames")?,
+            stats_bad_headers: OwnedData::create("ffsim/stats/bad_headers")?,
+            stats_crc_errors: OwnedData::create("ffsim/stats/crc_errors")?,
+
+            latencies: vec![Duration::from_millis(0); NUM_LATENCY_MEASUREMENTS],
+            num_latencies: - (latency_warmup as isize),
+            latency_samples: OwnedData::create("ffsim/latency/samples")?,
+            _latency_start: OwnedCommand::new("ffsim/latency/start",
+                                              "Start a new FFSim latency measurement",
+                                              FlagHandler(&RESTART_LATENCY))?,
+            latency_stream: None,
+            last_time: 0,
+
+            latency_dts: vec![Duration::from_millis(0); NUM_LATENCY_MEASUREMENTS],
+            loop_count: 0,
+            loop_time: Duration::from_millis(0),
         };
 
-        //plugin.override_flightcontrol.set(true);
-        plugin.override_control_surfaces.set(true);
-        plugin.override_throttles.set(true);
+        plugin.latency_samples.set(NUM_LATENCY_MEASUREMENTS as i32);
+        RESTART_LATENCY.store(false, Ordering::SeqCst);
+        TOGGLE_OVERRIDE.store(false, Ordering::SeqCst);
+        TOGGLE_SURFACES.store(false, Ordering::SeqCst);
+        TOGGLE_THROTTLE.store(false, Ordering::SeqCst);
+        TOGGLE_PAUSE_TX.store(false, Ordering::SeqCst);
+
+        if plugin.throttle.len() < NUM_ENGINES {
+            log_warn!("The aircraft only has {} throttles, ignoring commands for the rest",
+                      plugin.throttle.len());
+        }
+        if plugin.config.throttle_reverse > 0.0 && plugin.prop_mode.is_none() {
+            log_warn!("Can't find the engine mode dataref, reverse thrust commands will be forward thrust");
+        }
 
-        STOP_THREADS.store(false, Ordering::SeqCst);
+        if plugin.in_control {
+            plugin.set_overrides(true);
+        } else {
+            log_info!("Passive mode: not taking control of the aircraft");
+        }
 
         /* Thread to send flight data to controller */
-        let ser_tmp1 = ser.clone();
-        thread::spawn(move|| comm::send_flight_data_thread(outgoing_recv, ser_tmp1));
+        let link_tmp1 = link.clone();
+        let serial_port_tmp1 = serial_port.clone();
+        let stop_tmp1 = plugin.stop.clone();
+        let paused_tmp1 = plugin.tx_paused.clone();
+        let config_tmp1 = plugin.config.clone();
+        plugin.threads.push(thread::spawn(move|| comm::send_flight_data_thread(
+            outgoing_recv, replay, link_tmp1, serial_port_tmp1, paused_tmp1, stop_tmp1, config_tmp1)));
 
         /* Thread to receive controller inputs */
-        let ser_tmp2 = ser.clone();
-        thread::spawn(move|| comm::recv_control_data_thread(incoming_send, ser_tmp2));
+        let link_tmp2 = link.clone();
+        let stop_tmp2 = plugin.stop.clone();
+        let stats_tmp2 = plugin.link_stats.clone();
+        let config_tmp2 = plugin.config.clone();
+        plugin.threads.push(thread::spawn(move|| comm::recv_control_data_thread(
+            incoming_send, link_tmp2, stats_tmp2, stop_tmp2, config_tmp2)));
 
         plugin.fl.schedule_immediate();
 
-        println!("[FFSim] Plugin loaded");
+        plugin.self_test();
+        log_info!("Plugin loaded");
         Ok(plugin)
     }
 
@@ -218,40 +721,110 @@ impl Plugin for FFSim {
         PluginInfo {
             name: "FFSim".into(),
             signature: "au.edu.anu.ffsim".into(),
-            description: "Flight simulator integration for FPGA/HIL".into(),
+            description: description(),
         }
     }
 
     fn enable(&mut self) {
         self.fl.schedule_immediate();
 
-        //plugin.override_flightcontrol.set(true);
-        self.override_control_surfaces.set(true);
-        self.override_throttles.set(true);
+        if self.in_control {
+            self.set_overrides(true);
+        }
     }
     
     fn disable(&mut self) {
         self.fl.deactivate();
 
-        //self.override_flightcontrol.set(false);
-        self.override_control_surfaces.set(false);
-        self.override_throttles.set(false);
+        self.set_overrides(false);
     }
     
     fn stop(&mut self) {
         self.fl.deactivate();
 
-        //self.override_flightcontrol.set(false);
-        self.override_control_surfaces.set(false);
-        self.override_throttles.set(false);
+        self.set_overrides(false);
 
-        STOP_THREADS.store(true, Ordering::SeqCst);
+        self.stop.stop();
 
-        match self.ser.lock().unwrap().as_mut() {
-            Some(port) => port.close(),
-            None => (),
-        };
+        self.link.disconnect();
+
+        // Make sure they've let go of the port before we're reloaded, or the new
+        // instance would find it busy. Neither thread blocks for long: reads
+        // time out, and sleeps are cut short by `StopSignal`.
+        for handle in self.threads.drain(..) {
+            if handle.join().is_err() {
+                log_error!("A communication thread panicked");
+            }
+        }
+
+        // Give any latency files being written a moment to finish, in case
+        // X-Plane is quitting. (Closes the stream's queue, if there is one.)
+        self.latency_stream = None;
+        if !latency::wait_for_writes(Duration::from_secs(2)) {
+            log_warn!("Latency files still being written, they may be incomplete");
+        }
     }
 }
 
 xplane_plugin!(FFSim);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn yaw(degrees: f32) -> [f32; 4] {
+        Quaternion::from_euler(0f32, 0f32, degrees.to_radians()).as_array()
+    }
+
+    fn yaw_of(q: Quaternion) -> f32 {
+        q.to_euler().2.to_degrees()
+    }
+
+    #[test]
+    fn first_orientation_is_sent_as_is() {
+        let q = smooth_orientation(None, [2f32, 0f32, 0f32, 0f32], 0.1f32);
+        assert_eq!(q.as_array(), [1f32, 0f32, 0f32, 0f32]);
+    }
+
+    #[test]
+    fn smoothing_closes_part_of_the_gap_each_frame() {
+        let mut sent = smooth_orientation(None, yaw(0f32), 0.5f32);
+        sent = smooth_orientation(Some(sent), yaw(80f32), 0.5f32);
+        assert!((yaw_of(sent) - 40f32).abs() < 0.01, "{}", yaw_of(sent));
+        sent = smooth_orientation(Some(sent), yaw(80f32), 0.5f32);
+        assert!((yaw_of(sent) - 60f32).abs() < 0.01, "{}", yaw_of(sent));
+    }
+
+    #[test]
+    fn no_smoothing_follows_the_sim() {
+        let sent = smooth_orientation(Some(Quaternion::new(yaw(0f32))), yaw(80f32), 1f32);
+        assert!((yaw_of(sent) - 80f32).abs() < 0.01);
+    }
+
+    #[test]
+    fn description_gives_both_versions() {
+        let description = description();
+        assert!(description.contains(&format!("version {}", env!("CARGO_PKG_VERSION"))),
+                "{}", description);
+        assert!(description.contains(&format!("(protocol {})", PROTOCOL_VERSION)), "{}", description);
+    }
+
+    #[test]
+    fn short_quaternion_is_refused() {
+        let q = [0.5f32, 0.5f32, 0.5f32, 0.5f32];
+        assert_eq!(orientation(q, 4), Some(q));
+        assert_eq!(orientation(q, 3), None);
+        assert_eq!(orientation(q, 0), None);
+    }
+
+    #[test]
+    fn commands_raise_their_flag() {
+        static FLAG: AtomicBool = AtomicBool::new(false);
+        let mut handler = FlagHandler(&FLAG);
+        handler.command_continue();
+        handler.command_end();
+        assert!(!FLAG.load(Ordering::SeqCst));
+        handler.command_begin();
+        assert!(FLAG.load(Ordering::SeqCst));
+    }
+}
@@ -0,0 +1,371 @@
+use std::fmt;
+use std::io;
+use std::io::{Read, Write};
+use std::mem;
+use std::net::{Shutdown, TcpStream};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+use serial;
+use serial::SerialPort;
+
+use super::config::Config;
+use super::loopback::Loopback;
+
+// The byte stream between us and the controller. The framing and checksums
+// in comm.rs don't care what carries the bytes, so anything that can be read,
+// written and shared between the send and receive threads will do.
+//
+// The send thread writes through one handle while the receive thread sits in a
+// blocking read on another, with no lock between them. That's fine for a serial
+// port or a socket, which are full duplex (both handles are the same file
+// descriptor or socket underneath), and `Loopback` only holds its own lock for
+// as long as it takes to copy bytes. Anything that can't read and write at the
+// same time would have to open two independent connections in `try_clone`.
+pub trait Link: Read + Write + Send {
+    // Another handle to the same connection, so that one thread can send
+    // while the other receives
+    fn try_clone(&self) -> io::Result<Box<dyn Link>>;
+
+    // Closes the connection for every handle to it
+    fn close(&mut self);
+}
+
+impl Link for serial::SystemPort {
+    fn try_clone(&self) -> io::Result<Box<dyn Link>> {
+        Ok(Box::new(self.clone()))
+    }
+
+    fn close(&mut self) {
+        serial::SystemPort::close(self);
+    }
+}
+
+impl Link for TcpStream {
+    fn try_clone(&self) -> io::Result<Box<dyn Link>> {
+        let stream = TcpStream::try_clone(self)?;
+        Ok(Box::new(stream))
+    }
+
+    fn close(&mut self) {
+        // Fails if the other end already hung up, which is fine by us
+        let _ = self.shutdown(Shutdown::Both);
+    }
+}
+
+// Whether we're talking to the controller, as far as the comm threads know
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum ConnectionState {
+    // Before the first connection attempt, and after `SharedLink::disconnect`
+    Disconnected,
+    Connected,
+    // The link failed, or we couldn't open it; the send thread keeps trying
+    Reconnecting,
+}
+
+impl fmt::Display for ConnectionState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match *self {
+            ConnectionState::Disconnected => "disconnected",
+            ConnectionState::Connected => "connected",
+            ConnectionState::Reconnecting => "reconnecting",
+        };
+        f.write_str(name)
+    }
+}
+
+// Called with the old and new state whenever the state changes. These run on
+// whichever thread made the change, with the link locked, so they mustn't
+// block or touch the `SharedLink` themselves.
+pub type StateObserver = Box<dyn Fn(ConnectionState, ConnectionState) + Send>;
+
+// The link as shared between the comm threads, the menu and `stop()`. The send
+// thread connects it and anyone may drop it. The comm threads each keep a
+// handle of their own (see `LinkHandle`) and only come back here when the link
+// has changed, rather than locking this and cloning the link every frame.
+pub struct SharedLink {
+    link: Mutex<Option<Box<dyn Link>>>,
+    // Bumped (with `link` locked) whenever the link is replaced or dropped
+    generation: AtomicUsize,
+    // Kept apart from `link` so the menu needn't wait on its lock
+    state: Mutex<ConnectionState>,
+    observers: Mutex<Vec<StateObserver>>,
+}
+
+impl SharedLink {
+    pub fn new() -> Self {
+        SharedLink {
+            link: Mutex::new(None),
+            generation: AtomicUsize::new(0),
+            state: Mutex::new(ConnectionState::Disconnected),
+            observers: Mutex::new(Vec::new()),
+        }
+    }
+
+    // Has `observer` told about every change of state from now on
+    pub fn subscribe<F>(&self, observer: F)
+        where F: Fn(ConnectionState, ConnectionState) + Send + 'static {
+        self.observers.lock().unwrap().push(Box::new(observer));
+    }
+
+    fn transition(&self, new: ConnectionState) {
+        let old = mem::replace(&mut *self.state.lock().unwrap(), new);
+        if old != new {
+            for observer in self.observers.lock().unwrap().iter() {
+                observer(old, new);
+            }
+        }
+    }
+
+    // Makes `link` the current link, closing any previous one
+    pub fn set(&self, link: Box<dyn Link>) {
+        let mut guard = self.link.lock().unwrap();
+        if let Some(mut old) = guard.replace(link) {
+            old.close();
+        }
+        self.generation.fetch_add(1, Ordering::SeqCst);
+        self.transition(ConnectionState::Connected);
+    }
+
+    // For the send thread to call when it couldn't open the link
+    pub fn connect_failed(&self) {
+        let _guard = self.link.lock().unwrap();
+        self.transition(ConnectionState::Reconnecting);
+    }
+
+    // Closes and drops the current link, if there is one
+    pub fn disconnect(&self) {
+        let mut guard = self.link.lock().unwrap();
+        if let Some(mut old) = guard.take() {
+            old.close();
+            self.generation.fetch_add(1, Ordering::SeqCst);
+            self.transition(ConnectionState::Disconnected);
+        }
+    }
+
+    // As `disconnect`, but only if the link is still the one from `generation`.
+    // A thread whose handle has failed mustn't drop a link that's since replaced it.
+    // Unlike `disconnect` this is a failure, so we're left reconnecting.
+    fn disconnect_if(&self, generation: usize) {
+        let mut guard = self.link.lock().unwrap();
+        if self.generation.load(Ordering::SeqCst) != generation {
+            return;
+        }
+        if let Some(mut old) = guard.take() {
+            old.close();
+            self.generation.fetch_add(1, Ordering::SeqCst);
+            self.transition(ConnectionState::Reconnecting);
+        }
+    }
+
+    pub fn state(&self) -> ConnectionState {
+        *self.state.lock().unwrap()
+    }
+}
+
+// One comm thread's own handle to the current `SharedLink`
+pub struct LinkHandle {
+    link: Option<Box<dyn Link>>,
+    // The `SharedLink` generation `link` was cloned from
+    generation: usize,
+}
+
+impl LinkHandle {
+    pub fn new() -> Self {
+        LinkHandle {
+            link: None,
+            generation: 0,
+        }
+    }
+
+    // Our handle to the current link, if it's up. This only locks `shared` when
+    // the link has changed since we last looked.
+    pub fn get(&mut self, shared: &SharedLink) -> Option<&mut Box<dyn Link>> {
+        if shared.generation.load(Ordering::SeqCst) != self.generation {
+            let guard = shared.link.lock().unwrap();
+            self.generation = shared.generation.load(Ordering::SeqCst);
+            self.link = match *guard {
+                Some(ref link) => link.try_clone().ok(),
+                None => None,
+            };
+        }
+        self.link.as_mut()
+    }
+
+    // Call when a read or write on our handle fails. Closes the link (for the
+    // other thread too) so the send thread reconnects.
+    pub fn failed(&mut self, shared: &SharedLink) {
+        if let Some(mut link) = self.link.take() {
+            link.close();
+        }
+        shared.disconnect_if(self.generation);
+    }
+}
+
+// Opens whichever link the config asks for. The serial port is passed separately
+// since it can be changed from the menu after we've started.
+pub fn connect(config: &Config, serial_port: &str) -> io::Result<Box<dyn Link>> {
+    if config.loopback {
+        return Ok(Box::new(Loopback::new(config.wire_endian)));
+    }
+
+    match config.tcp_addr {
+        Some(ref addr) => {
+            let stream = tcp_connect(addr, config.read_timeout())?;
+            Ok(Box::new(stream))
+        }
+        None => {
+            let port = ser_connect(serial_port, config.baud_rate, config.read_timeout())?;
+            Ok(Box::new(port))
+        }
+    }
+}
+
+// Reads give up with `TimedOut` after `timeout`, so the receive thread gets a
+// chance to notice that it should stop.
+fn ser_connect(serial_port: &str, baud_rate: usize, timeout: Duration) -> io::Result<serial::SystemPort> {
+    let mut ser = serial::open(serial_port)?;
+
+    // Loosely based on the example in
+    // https://github.com/dcuddeback/serial-rs/tree/master/serial
+    ser.reconfigure(&|settings| {
+        settings.set_baud_rate(serial::BaudOther(baud_rate))?;
+        settings.set_char_size(serial::Bits8);
+        settings.set_parity(serial::ParityNone);
+        settings.set_stop_bits(serial::Stop1);
+        settings.set_flow_control(serial::FlowNone);
+        Ok(())
+    })?;
+
+    ser.set_timeout(timeout)?;
+
+    Ok(ser)
+}
+
+// `addr` is anything `TcpStream::connect` understands, e.g. "192.168.1.10:5000"
+fn tcp_connect(addr: &str, timeout: Duration) -> io::Result<TcpStream> {
+    let stream = TcpStream::connect(addr)?;
+
+    // Frames are small and latency matters far more than throughput
+    stream.set_nodelay(true)?;
+    // Without a timeout the receive thread could block forever on a quiet
+    // connection and never notice that it should stop
+    stream.set_read_timeout(Some(timeout))?;
+
+    Ok(stream)
+}
+
+// Serial ports that could plausibly be the FPGA, for the menu. The serial crate
+// can't list ports, so we look for USB serial devices in /dev ...
+#[cfg(unix)]
+pub fn available_serial_ports() -> Vec<String> {
+    let prefixes = ["ttyUSB", "ttyACM", "cu.usbserial", "cu.usbmodem"];
+    let mut ports: Vec<String> = match ::std::fs::read_dir("/dev") {
+        Ok(entries) => entries
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.file_name().to_string_lossy().into_owned())
+            .filter(|name| prefixes.iter().any(|prefix| name.starts_with(prefix)))
+            .map(|name| format!("/dev/{}", name))
+            .collect(),
+        Err(_) => Vec::new(),
+    };
+    ports.sort();
+    ports
+}
+
+// ... or on Windows, try opening each COM port in turn. (Ports that are already
+// in use, including by us, won't show up.)
+#[cfg(windows)]
+pub fn available_serial_ports() -> Vec<String> {
+    (1 .. 33).map(|n| format!("COM{}", n))
+        .filter(|name| serial::open(name.as_str()).is_ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use super::super::wire::WireEndian;
+
+    fn loopback() -> Box<dyn Link> {
+        Box::new(Loopback::new(WireEndian::Little))
+    }
+
+    type Changes = Arc<Mutex<Vec<(ConnectionState, ConnectionState)>>>;
+
+    // The link, and every (old, new) its observer has been told about
+    fn observed() -> (SharedLink, Changes) {
+        let shared = SharedLink::new();
+        let changes = Arc::new(Mutex::new(Vec::new()));
+        let seen = changes.clone();
+        shared.subscribe(move |old, new| seen.lock().unwrap().push((old, new)));
+        (shared, changes)
+    }
+
+    #[test]
+    fn state_follows_the_link() {
+        use self::ConnectionState::*;
+        let (shared, changes) = observed();
+        assert_eq!(shared.state(), Disconnected);
+
+        shared.connect_failed();
+        assert_eq!(shared.state(), Reconnecting);
+        shared.set(loopback());
+        assert_eq!(shared.state(), Connected);
+        shared.disconnect();
+        assert_eq!(shared.state(), Disconnected);
+
+        assert_eq!(*changes.lock().unwrap(),
+                   vec![(Disconnected, Reconnecting), (Reconnecting, Connected),
+                        (Connected, Disconnected)]);
+    }
+
+    #[test]
+    fn observers_only_hear_about_changes() {
+        let (shared, changes) = observed();
+        // Nothing to drop
+        shared.disconnect();
+        shared.connect_failed();
+        shared.connect_failed();
+        // Replacing a link leaves us connected
+        shared.set(loopback());
+        shared.set(loopback());
+        assert_eq!(changes.lock().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn failed_handle_leaves_us_reconnecting() {
+        let shared = SharedLink::new();
+        let mut handle = LinkHandle::new();
+        assert!(handle.get(&shared).is_none());
+
+        shared.set(loopback());
+        assert!(handle.get(&shared).is_some());
+        handle.failed(&shared);
+        assert_eq!(shared.state(), ConnectionState::Reconnecting);
+        assert!(handle.get(&shared).is_none());
+        assert!(LinkHandle::new().get(&shared).is_none());
+    }
+
+    #[test]
+    fn stale_handle_leaves_a_newer_link_alone() {
+        let shared = SharedLink::new();
+        let mut stale = LinkHandle::new();
+        shared.set(loopback());
+        assert!(stale.get(&shared).is_some());
+
+        // Reconnected before the stale handle noticed its link had failed
+        shared.set(loopback());
+        stale.failed(&shared);
+        assert_eq!(shared.state(), ConnectionState::Connected);
+        assert!(LinkHandle::new().get(&shared).is_some());
+    }
+
+    #[test]
+    fn state_names() {
+        assert_eq!(ConnectionState::Disconnected.to_string(), "disconnected");
+        assert_eq!(ConnectionState::Connected.to_string(), "connected");
+        assert_eq!(ConnectionState::Reconnecting.to_string(), "reconnecting");
+    }
+}
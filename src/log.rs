@@ -0,0 +1,157 @@
+// Leveled logging. Everything goes to stdout (which is where X-Plane's own
+// console output goes) and, if configured, is appended to a log file as well.
+//
+// We don't use XPLMDebugString since it may only be called from the main
+// thread, and most of our messages come from the communication threads.
+
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Mutex, MutexGuard};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+// Most to least severe; a message is logged if it is at least as severe as
+// the configured level.
+#[derive(Copy, Clone, Debug, PartialEq, PartialOrd)]
+pub enum Level {
+    Error,
+    Warn,
+    Info,
+    Debug, // per-frame detail, e.g. hex dumps of bad frames
+}
+
+impl Level {
+    fn from_usize(val: usize) -> Self {
+        match val {
+            0 => Level::Error,
+            1 => Level::Warn,
+            2 => Level::Info,
+            _ => Level::Debug,
+        }
+    }
+}
+
+impl fmt::Display for Level {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match *self {
+            Level::Error => "error",
+            Level::Warn => "warn",
+            Level::Info => "info",
+            Level::Debug => "debug",
+        };
+        f.write_str(name)
+    }
+}
+
+impl FromStr for Level {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, ()> {
+        match s.to_lowercase().as_str() {
+            "error" => Ok(Level::Error),
+            "warn" | "warning" => Ok(Level::Warn),
+            "info" => Ok(Level::Info),
+            "debug" => Ok(Level::Debug),
+            _ => Err(()),
+        }
+    }
+}
+
+// Until `init` is called we log at Info, i.e. not including debug messages
+static LEVEL: AtomicUsize = AtomicUsize::new(Level::Info as usize);
+
+// The log file, if there is one. Empty until `init` opens it.
+static FILE: Mutex<Option<File>> = Mutex::new(None);
+
+// A poisoned lock just means someone panicked mid-write; carry on regardless
+fn log_file() -> MutexGuard<'static, Option<File>> {
+    match FILE.lock() {
+        Ok(guard) => guard,
+        Err(poisoned) => poisoned.into_inner(),
+    }
+}
+
+// Sets the level and (re)opens the log file. Called when the plugin starts,
+// so messages from before that (e.g. about the config itself) only go to stdout.
+pub fn init(level: Level, path: &Option<PathBuf>) {
+    LEVEL.store(level as usize, Ordering::SeqCst);
+
+    let file = match *path {
+        Some(ref path) => open(path),
+        None => None,
+    };
+    *log_file() = file;
+}
+
+fn open(path: &Path) -> Option<File> {
+    match OpenOptions::new().create(true).append(true).open(path) {
+        Ok(file) => Some(file),
+        Err(e) => {
+            println!("[FFSim] Couldn't open log file {}, logging to stdout only: {:?}",
+                     path.display(), e);
+            None
+        }
+    }
+}
+
+pub fn enabled(level: Level) -> bool {
+    level <= Level::from_usize(LEVEL.load(Ordering::Relaxed))
+}
+
+// Use the `log_*!` macros rather than calling this directly
+pub fn log(level: Level, args: fmt::Arguments) {
+    if !enabled(level) {
+        return;
+    }
+
+    let line = match level {
+        Level::Error | Level::Warn => format!("[FFSim] {}: {}", level, args),
+        Level::Info | Level::Debug => format!("[FFSim] {}", args),
+    };
+    println!("{}", line);
+
+    if let Some(ref mut file) = *log_file() {
+        // Nowhere to report a failure to log, so ignore it
+        let _ = writeln!(file, "{}", line);
+    }
+}
+
+macro_rules! log_error {
+    ($($arg:tt)*) => ($crate::log::log($crate::log::Level::Error, format_args!($($arg)*)))
+}
+
+macro_rules! log_warn {
+    ($($arg:tt)*) => ($crate::log::log($crate::log::Level::Warn, format_args!($($arg)*)))
+}
+
+macro_rules! log_info {
+    ($($arg:tt)*) => ($crate::log::log($crate::log::Level::Info, format_args!($($arg)*)))
+}
+
+macro_rules! log_debug {
+    ($($arg:tt)*) => ($crate::log::log($crate::log::Level::Debug, format_args!($($arg)*)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn levels_parse_and_round_trip() {
+        for &level in &[Level::Error, Level::Warn, Level::Info, Level::Debug] {
+            assert_eq!(level.to_string().parse::<Level>(), Ok(level));
+            assert_eq!(Level::from_usize(level as usize), level);
+        }
+        assert_eq!("WARNING".parse::<Level>(), Ok(Level::Warn));
+        assert!("verbose".parse::<Level>().is_err());
+    }
+
+    #[test]
+    fn more_severe_levels_sort_first() {
+        assert!(Level::Error < Level::Warn);
+        assert!(Level::Warn < Level::Info);
+        assert!(Level::Info < Level::Debug);
+    }
+}
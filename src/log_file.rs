@@ -0,0 +1,99 @@
+use std::fs::File;
+use std::io;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+
+#[cfg(feature = "gzip")]
+use flate2::Compression;
+#[cfg(feature = "gzip")]
+use flate2::read::GzDecoder;
+#[cfg(feature = "gzip")]
+use flate2::write::GzEncoder;
+
+// Whether this build can compress the telemetry and latency logs
+// (FFSIM_LOG_GZIP). It's behind the `gzip` feature so that builds without it
+// don't pull in a compression library.
+pub const GZIP_AVAILABLE: bool = cfg!(feature = "gzip");
+
+// A CSV file written by one of the logging threads, gzipped if asked for (and
+// built with the `gzip` feature). Call `finish` when done with it; just
+// dropping it leaves anything still buffered unwritten.
+pub enum LogWriter {
+    Plain(BufWriter<File>),
+    #[cfg(feature = "gzip")]
+    Gzip(GzEncoder<BufWriter<File>>),
+}
+
+impl LogWriter {
+    // Creates the file at `log_path(path, gzip)`
+    pub fn create(path: &Path, gzip: bool) -> io::Result<Self> {
+        let file = BufWriter::new(File::create(log_path(path, gzip))?);
+
+        #[cfg(feature = "gzip")]
+        {
+            if gzip {
+                return Ok(LogWriter::Gzip(GzEncoder::new(file, Compression::default())));
+            }
+        }
+        Ok(LogWriter::Plain(file))
+    }
+
+    // Writes out everything (including the end of the gzip stream) and syncs
+    // the file to disk, so it's complete even if X-Plane quits straight after
+    pub fn finish(self) -> io::Result<()> {
+        let mut file = match self {
+            LogWriter::Plain(file) => file,
+            #[cfg(feature = "gzip")]
+            LogWriter::Gzip(encoder) => encoder.finish()?,
+        };
+        file.flush()?;
+        file.get_ref().sync_all()
+    }
+}
+
+impl Write for LogWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match *self {
+            LogWriter::Plain(ref mut file) => file.write(buf),
+            #[cfg(feature = "gzip")]
+            LogWriter::Gzip(ref mut encoder) => encoder.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match *self {
+            LogWriter::Plain(ref mut file) => file.flush(),
+            #[cfg(feature = "gzip")]
+            LogWriter::Gzip(ref mut encoder) => encoder.flush(),
+        }
+    }
+}
+
+// `path` with ".gz" on the end if it's to be gzipped
+pub fn log_path(path: &Path, gzip: bool) -> PathBuf {
+    if gzip && GZIP_AVAILABLE {
+        let mut name = path.as_os_str().to_owned();
+        name.push(".gz");
+        PathBuf::from(name)
+    } else {
+        path.to_path_buf()
+    }
+}
+
+// Opens a log for reading, decompressing it if its name ends in ".gz"
+pub fn open(path: &Path) -> io::Result<Box<dyn Read>> {
+    let file = BufReader::new(File::open(path)?);
+    let gzipped = path.extension().map_or(false, |ext| ext == "gz");
+
+    #[cfg(feature = "gzip")]
+    {
+        if gzipped {
+            return Ok(Box::new(GzDecoder::new(file)));
+        }
+    }
+    if gzipped {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                  "gzipped, but built without the gzip feature"));
+    }
+    Ok(Box::new(file))
+}
@@ -0,0 +1,183 @@
+use std::collections::VecDeque;
+use std::io;
+use std::io::{Read, Write};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use super::ControlData;
+use super::flight_data::FLIGHT_DATA_SIZE;
+use super::link::Link;
+use super::wire::{WireEndian, WireReader, SYNC, TIMESTAMP_SIZE};
+
+// Where the timestamp sits in a FlightData frame: just before the checksum
+const FLIGHT_DATA_TIME: usize = FLIGHT_DATA_SIZE - 4 - TIMESTAMP_SIZE;
+// ... and the sequence number: after SYNC, the version and a reserved byte
+const FLIGHT_DATA_SEQUENCE: usize = 6;
+
+// Stands in for the FPGA when there isn't one (FFSIM_LOOPBACK=1). Every
+// FlightData frame we're sent is answered with a ControlData frame carrying
+// neutral controls and the same timestamp, so the framing, checksums, watchdog
+// and latency measurement all get exercised without any hardware.
+#[derive(Clone)]
+pub struct Loopback {
+    state: Arc<Mutex<State>>,
+    endian: WireEndian,
+}
+
+struct State {
+    // What we've been sent that doesn't make up a whole frame yet
+    from_plugin: Vec<u8>,
+    // Replies waiting to be read
+    to_plugin: VecDeque<u8>,
+    closed: bool,
+}
+
+impl Loopback {
+    pub fn new(endian: WireEndian) -> Self {
+        Loopback {
+            state: Arc::new(Mutex::new(State {
+                from_plugin: Vec::new(),
+                to_plugin: VecDeque::new(),
+                closed: false,
+            })),
+            endian,
+        }
+    }
+}
+
+fn closed_error() -> io::Error {
+    io::Error::new(io::ErrorKind::BrokenPipe, "loopback closed")
+}
+
+impl Read for Loopback {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = {
+            let mut state = self.state.lock().unwrap();
+            if state.closed {
+                return Err(closed_error());
+            }
+
+            let len = buf.len().min(state.to_plugin.len());
+            for (dest, byte) in buf.iter_mut().zip(state.to_plugin.drain(.. len)) {
+                *dest = byte;
+            }
+            len
+        };
+
+        if len == 0 {
+            // Behave like a serial port with a read timeout rather than spinning
+            thread::sleep(Duration::from_millis(10));
+            return Err(io::Error::new(io::ErrorKind::TimedOut, "nothing to read"));
+        }
+        Ok(len)
+    }
+}
+
+impl Write for Loopback {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut state = self.state.lock().unwrap();
+        if state.closed {
+            return Err(closed_error());
+        }
+
+        state.from_plugin.extend_from_slice(buf);
+        while state.from_plugin.len() >= FLIGHT_DATA_SIZE {
+            let frame: Vec<u8> = state.from_plugin.drain(.. FLIGHT_DATA_SIZE).collect();
+            if frame[.. 4] != SYNC {
+                // We only ever get whole frames from the send thread, so this
+                // means a bug on our side rather than line noise
+                log_warn!("Loopback: FlightData frame doesn't start with SYNC, dropping it");
+                continue;
+            }
+
+            let mut time = [0u8; TIMESTAMP_SIZE];
+            time.copy_from_slice(&frame[FLIGHT_DATA_TIME .. FLIGHT_DATA_TIME + TIMESTAMP_SIZE]);
+            // (one reply per frame, so its sequence number will do for ours)
+            let sequence = WireReader::new(&frame[FLIGHT_DATA_SEQUENCE ..], self.endian).u16();
+            let reply = ControlData::neutral(sequence, time, self.endian);
+            state.to_plugin.extend(reply.to_bytes(self.endian).iter());
+        }
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Link for Loopback {
+    fn try_clone(&self) -> io::Result<Box<dyn Link>> {
+        Ok(Box::new(self.clone()))
+    }
+
+    fn close(&mut self) {
+        self.state.lock().unwrap().closed = true;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::BufferedFlightData;
+    use super::super::config::Config;
+    use super::super::control_data::CONTROL_DATA_SIZE;
+    use super::super::flight_data::{FlightData, Saturation};
+    use super::super::wire::encode_timestamp;
+
+    fn flight_data(sequence: u16, time: u64, endian: WireEndian) -> [u8; FLIGHT_DATA_SIZE] {
+        let mut config = Config::for_test();
+        config.wire_endian = endian;
+        let mut bfd = BufferedFlightData::new();
+        bfd.time = time;
+        FlightData::new(bfd, sequence, &config, &mut Saturation::new()).to_bytes(endian)
+    }
+
+    fn reply(link: &mut Loopback, endian: WireEndian) -> ControlData {
+        let mut buf = [0u8; CONTROL_DATA_SIZE];
+        link.read_exact(&mut buf).unwrap();
+        let cd = ControlData::from_bytes(&buf, endian);
+        assert!(cd.verify(endian));
+        cd
+    }
+
+    #[test]
+    fn replies_echo_the_sequence_number_and_time() {
+        for &endian in &[WireEndian::Little, WireEndian::Big] {
+            let mut link = Loopback::new(endian);
+            link.write_all(&flight_data(0xFFFF, 1234, endian)).unwrap();
+            link.write_all(&flight_data(0, 5678, endian)).unwrap();
+
+            let first = reply(&mut link, endian);
+            assert_eq!(first.sequence, 0xFFFF);
+            assert_eq!(first.time, encode_timestamp(1234));
+            let second = reply(&mut link, endian);
+            assert_eq!(second.sequence, 0);
+            assert_eq!(second.time, encode_timestamp(5678));
+        }
+    }
+
+    #[test]
+    fn frames_can_arrive_in_pieces() {
+        let endian = WireEndian::Little;
+        let mut link = Loopback::new(endian);
+        let frame = flight_data(7, 99, endian);
+
+        link.write_all(&frame[.. 10]).unwrap();
+        let mut buf = [0u8; 1];
+        assert_eq!(link.read(&mut buf).unwrap_err().kind(), io::ErrorKind::TimedOut);
+
+        link.write_all(&frame[10 ..]).unwrap();
+        assert_eq!(reply(&mut link, endian).sequence, 7);
+    }
+
+    #[test]
+    fn closed_loopback_refuses_everything() {
+        let mut link = Loopback::new(WireEndian::Little);
+        let mut other = link.clone();
+        link.close();
+        assert_eq!(other.write(b"SYNC").unwrap_err().kind(), io::ErrorKind::BrokenPipe);
+        assert_eq!(other.read(&mut [0u8; 4]).unwrap_err().kind(), io::ErrorKind::BrokenPipe);
+    }
+}
@@ -0,0 +1,147 @@
+use std::ffi::NulError;
+use std::rc::Rc;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::Ordering;
+use xplm::menu::{Menu, ActionItem, MenuClickHandler, Separator};
+
+use super::TOGGLE_PAUSE_TX;
+use super::config::Config;
+use super::link;
+use super::link::{ConnectionState, SharedLink};
+
+// The FFSim submenu of X-Plane's Plugins menu. Shows whether we're connected
+// to the controller and over what, lets the user pick another serial port, and
+// pause the telemetry.
+//
+// Like the rest of the X-Plane API this may only be touched from the main
+// thread, so the flight loop keeps it up to date (see `update`).
+pub struct StatusMenu {
+    _menu: Menu,
+    status: Rc<ActionItem>,
+    port: Rc<ActionItem>,
+    pause: Rc<ActionItem>,
+
+    // What the items currently say, so we only rename them when it changes
+    shown_state: Option<ConnectionState>,
+    shown_paused: bool,
+    shown_port: String,
+    baud_rate: usize,
+}
+
+impl StatusMenu {
+    // `serial_port` and `link` are shared with the communication threads.
+    // Picking a port from the menu changes `serial_port` and drops the current
+    // link, so the send thread reconnects using the new port.
+    pub fn new(config: &Config, serial_port: Arc<Mutex<String>>,
+               link: Arc<SharedLink>) -> Result<Self, NulError> {
+        let menu = Menu::new("FFSim")?;
+
+        let status = Rc::new(ActionItem::new("Not connected", Inert)?);
+        menu.add_child(status.clone());
+
+        let port = match config.tcp_addr {
+            _ if config.loopback => Rc::new(ActionItem::new("Loopback", Inert)?),
+            Some(ref addr) => Rc::new(ActionItem::new(format!("TCP {}", addr), Inert)?),
+            None => Rc::new(ActionItem::new(
+                port_label(&config.serial_port, config.baud_rate), Inert)?),
+        };
+        menu.add_child(port.clone());
+
+        let pause = Rc::new(ActionItem::new("Pause telemetry", PauseTx)?);
+        menu.add_child(pause.clone());
+
+        // Switching serial ports means nothing over TCP (or loopback)
+        if config.uses_serial_port() {
+            menu.add_child(Separator);
+
+            let ports = Rc::new(Menu::new("Serial port")?);
+            let mut names = link::available_serial_ports();
+            if !names.contains(&config.serial_port) {
+                names.insert(0, config.serial_port.clone());
+            }
+            for name in names {
+                let handler = SelectPort {
+                    name: name.clone(),
+                    serial_port: serial_port.clone(),
+                    link: link.clone(),
+                };
+                ports.add_child(Rc::new(ActionItem::new(name, handler)?));
+            }
+            menu.add_child(ports);
+        }
+
+        menu.add_to_plugins_menu();
+
+        Ok(StatusMenu {
+            _menu: menu,
+            status,
+            port,
+            pause,
+            shown_state: None,
+            shown_paused: false,
+            shown_port: config.serial_port.clone(),
+            baud_rate: config.baud_rate,
+        })
+    }
+
+    // Call from the main thread with the current state of the link. `serial_port`
+    // is None unless we're using one, since nothing else can be changed.
+    pub fn update(&mut self, state: ConnectionState, tx_paused: bool, serial_port: Option<&str>) {
+        if self.shown_state != Some(state) {
+            let label = match state {
+                ConnectionState::Connected => "Connected",
+                ConnectionState::Disconnected => "Not connected",
+                ConnectionState::Reconnecting => "Not connected, retrying",
+            };
+            let _ = self.status.set_name(label);
+            self.shown_state = Some(state);
+        }
+
+        if self.shown_paused != tx_paused {
+            let label = if tx_paused { "Resume telemetry" } else { "Pause telemetry" };
+            let _ = self.pause.set_name(label);
+            self.shown_paused = tx_paused;
+        }
+
+        if let Some(serial_port) = serial_port {
+            if self.shown_port != serial_port {
+                let _ = self.port.set_name(&port_label(serial_port, self.baud_rate));
+                self.shown_port = serial_port.to_string();
+            }
+        }
+    }
+}
+
+fn port_label(serial_port: &str, baud_rate: usize) -> String {
+    format!("{} at {} baud", serial_port, baud_rate)
+}
+
+// For items that only display something
+struct Inert;
+
+impl MenuClickHandler for Inert {
+    fn item_clicked(&mut self, _item: &ActionItem) {}
+}
+
+// Same as the `ffsim/pause_tx` command
+struct PauseTx;
+
+impl MenuClickHandler for PauseTx {
+    fn item_clicked(&mut self, _item: &ActionItem) {
+        TOGGLE_PAUSE_TX.store(true, Ordering::SeqCst);
+    }
+}
+
+struct SelectPort {
+    name: String,
+    serial_port: Arc<Mutex<String>>,
+    link: Arc<SharedLink>,
+}
+
+impl MenuClickHandler for SelectPort {
+    fn item_clicked(&mut self, _item: &ActionItem) {
+        log_info!("Switching to serial port {}", self.name);
+        *self.serial_port.lock().unwrap() = self.name.clone();
+        self.link.disconnect();
+    }
+}
@@ -0,0 +1,391 @@
+use std::ops::BitXor;
+use std::time::{Duration, UNIX_EPOCH};
+
+use super::BufferedFlightData;
+use super::config::NmeaSentence;
+
+// Maximum length of a single NMEA sentence, including the leading $ and trailing CRLF
+pub const NMEA_MAX_LEN: usize = 82;
+
+// Room for every kind of sentence we know how to generate, back to back
+pub const NMEA_BUFFER_SIZE: usize = 3 * NMEA_MAX_LEN;
+
+const KNOTS_TO_MS: f64 = 0.5144447;
+
+// Talker ID we identify as unless configured otherwise: a plain GPS receiver,
+// which is what most parsers expect
+pub const DEFAULT_TALKER: &str = "GP";
+
+// Builds the requested sentences, each from `talker` (e.g. "GP"), and packs
+// them into the `gps` field of `FlightData`.
+pub fn build(sentences: &[NmeaSentence], talker: &str, bfd: &BufferedFlightData) -> [u8; NMEA_BUFFER_SIZE] {
+    let (lat, long) = (bfd.latitude, bfd.longitude);
+    // (If the clock is somehow before 1970, we claim it's midnight on 1/1/1970)
+    let time = bfd.utc.duration_since(UNIX_EPOCH).unwrap_or(Duration::from_millis(0));
+
+    let strings: Vec<String> = sentences.iter().map(|sentence| match *sentence {
+        NmeaSentence::Gll => gll(talker, lat, long, time),
+        NmeaSentence::Gga => gga(talker, lat, long, bfd.elevation, time),
+        NmeaSentence::Rmc => rmc(talker, lat, long, bfd.groundspeed as f64 / KNOTS_TO_MS,
+                                 bfd.track as f64, time),
+    }).collect();
+
+    to_buffer(&strings)
+}
+
+// Global position data only. As in all the sentences, `time` is the time of
+// the fix since the Unix epoch (i.e. in UTC), which is when the frame was built.
+pub fn gll(talker: &str, lat: f64, long: f64, time: Duration) -> String {
+    let mut res = header(talker, "GLL"); // Latitude/Longitude info
+
+    push_lat_long(&mut res, lat, long);
+
+    // time of fix, then status and mode indicator: valid, autonomous (see `gga`)
+    res.push_str(",");
+    res.push_str(utc_time(time).as_str());
+    res.push_str(",A,A");
+
+    finish(res)
+}
+
+// Fix data, which unlike GLL includes altitude (metres above MSL)
+pub fn gga(talker: &str, lat: f64, long: f64, alt: f64, time: Duration) -> String {
+    let mut res = header(talker, "GGA");
+
+    // time of fix
+    res.push_str(utc_time(time).as_str());
+    res.push_str(",");
+
+    push_lat_long(&mut res, lat, long);
+
+    // The sim always knows exactly where we are, so pretend we have a good
+    // GPS fix (quality 1) with a decent number of satellites and low HDOP.
+    res.push_str(",1,08,1.0,");
+
+    // altitude, then geoid separation (which we don't model)
+    res.push_str(format!("{:.1},M,0.0,M,", alt).as_str());
+
+    // age of differential data, differential station ID: both empty
+    res.push_str(",");
+
+    finish(res)
+}
+
+// Recommended minimum data: position, ground speed (knots) and true track
+// (degrees). Unlike the others this one carries the date as well as the time.
+pub fn rmc(talker: &str, lat: f64, long: f64, speed_knots: f64, track: f64, time: Duration) -> String {
+    let mut res = header(talker, "RMC");
+
+    res.push_str(utc_time(time).as_str());
+    // 'A' for a valid fix, see `gga`
+    res.push_str(",A,");
+
+    push_lat_long(&mut res, lat, long);
+
+    // NMEA wants the track in [0, 360)
+    let track = ((track % 360f64) + 360f64) % 360f64;
+    res.push_str(format!(",{:.1},{:.1},", speed_knots, track).as_str());
+
+    res.push_str(utc_date(time).as_str());
+
+    // magnetic variation and its direction (not modelled), then the mode
+    // indicator, which is 'A' (autonomous) for a normal fix
+    res.push_str(",,,A");
+
+    finish(res)
+}
+
+// Time of day as hhmmss.ss. Fractions of a second are truncated rather than
+// rounded so we can never print 60 seconds (or roll over into the next day).
+fn utc_time(since_epoch: Duration) -> String {
+    let secs_of_day = since_epoch.as_secs() % 86_400;
+    let hundredths = since_epoch.subsec_nanos() / 10_000_000;
+    format!("{:02}{:02}{:02}.{:02}",
+            secs_of_day / 3600, (secs_of_day / 60) % 60, secs_of_day % 60, hundredths)
+}
+
+// Date as ddmmyy
+fn utc_date(since_epoch: Duration) -> String {
+    let (year, month, day) = civil_from_days(since_epoch.as_secs() / 86_400);
+    format!("{:02}{:02}{:02}", day, month, year % 100)
+}
+
+// Converts days since 1970-01-01 to a (year, month, day) date in the proleptic
+// Gregorian calendar. This is Howard Hinnant's `civil_from_days`, see
+// http://howardhinnant.github.io/date_algorithms.html for how it works.
+fn civil_from_days(days: u64) -> (u64, u64, u64) {
+    let z = days + 719_468;
+    let era = z / 146_097;
+    let doe = z - era * 146_097; // day of era, [0, 146096]
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365; // year of era, [0, 399]
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100); // day of year (from March 1st), [0, 365]
+    let mp = (5 * doy + 2) / 153; // month, March = 0
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+// True for two capital letters, like "GP" or "GN"
+pub fn is_valid_talker(talker: &str) -> bool {
+    talker.len() == 2 && talker.chars().all(|c| c.is_ascii_uppercase())
+}
+
+fn header(talker: &str, kind: &str) -> String {
+    let mut res = String::new();
+    res.push_str("$");
+    res.push_str(talker);
+    res.push_str(kind);
+    res.push_str(",");
+    res
+}
+
+fn push_lat_long(res: &mut String, lat: f64, long: f64) {
+    // latitude, ddmm.mmmm
+    res.push_str(degrees_minutes(lat, 2).as_str());
+    res.push_str(",");
+    // sign according to ISO-6709 (hopefully)
+    if lat.is_sign_positive() {
+        res.push_str("N");
+    } else {
+        res.push_str("S");
+    }
+    res.push_str(",");
+
+    // longitude, dddmm.mmmm
+    res.push_str(degrees_minutes(long, 3).as_str());
+    res.push_str(",");
+    if long.is_sign_positive() {
+        res.push_str("E");
+    } else {
+        res.push_str("W");
+    }
+}
+
+// NMEA doesn't use decimal degrees: the magnitude of the angle is written as
+// whole degrees (zero-padded to `degree_digits`) immediately followed by
+// decimal minutes to 4dp, e.g. 35.5 -> "3530.0000". The sign is sent separately.
+fn degrees_minutes(angle: f64, degree_digits: usize) -> String {
+    let abs = angle.abs();
+    let mut degrees = abs.trunc();
+    let mut minutes = (abs - degrees) * 60f64;
+
+    // Rounding to 4dp could give us "60.0000" minutes; carry it into the degrees instead
+    if (minutes * 10_000f64).round() >= 600_000f64 {
+        degrees += 1f64;
+        minutes = 0f64;
+    }
+
+    format!("{:0width$}{:07.4}", degrees as u32, minutes, width = degree_digits)
+}
+
+// Appends the checksum and line terminator
+fn finish(mut res: String) -> String {
+    res.push_str("*");
+    let check: u8 = res[1 .. res.len()-1] // the $ and * aren't part of the checksum
+        .as_bytes().iter()
+        // checksum is XOR of all elements
+        .fold(0u8, |tot, val| tot.bitxor(*val));
+
+    res.push_str(format!("{:02X}", check).as_str()); // format as 2 hex digits
+
+    // CRLF indicates end of string
+    res.push_str("\r\n");
+
+    res
+}
+
+// Copies sentences into the fixed-size `gps` field, one after another. Any
+// sentence past the NMEA maximum of 82 characters is cut off rather than
+// panicking the send thread; the unused tail is zero-filled so the FPGA can
+// find the end.
+pub fn to_buffer(sentences: &[String]) -> [u8; NMEA_BUFFER_SIZE] {
+    let mut ret: [u8; NMEA_BUFFER_SIZE] = [0u8; NMEA_BUFFER_SIZE];
+    let mut cursor: usize = 0;
+
+    for sentence in sentences {
+        let bytes = sentence.as_bytes();
+        let len = bytes.len().min(NMEA_MAX_LEN).min(NMEA_BUFFER_SIZE - cursor);
+        if len < bytes.len() {
+            log_warn!("NMEA sentence too long ({} bytes), truncating", bytes.len());
+        }
+        ret[cursor .. cursor + len].copy_from_slice(&bytes[.. len]);
+        cursor += len;
+    }
+
+    ret
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 2021-03-14 12:34:56.78 UTC
+    fn time() -> Duration {
+        Duration::from_millis(1_615_725_296_780)
+    }
+
+    #[test]
+    fn checksums_match_published_examples() {
+        assert_eq!(finish("$GPGLL,4916.45,N,12311.12,W,225444,A".to_string()),
+                   "$GPGLL,4916.45,N,12311.12,W,225444,A*31\r\n");
+        assert_eq!(finish("$GPRMC,123519,A,4807.038,N,01131.000,E,022.4,084.4,230394,003.1,W".to_string()),
+                   "$GPRMC,123519,A,4807.038,N,01131.000,E,022.4,084.4,230394,003.1,W*6A\r\n");
+    }
+
+    #[test]
+    fn sentences() {
+        assert_eq!(gll("GP", 35.5, -0.5, time()),
+                   "$GPGLL,3530.0000,N,00030.0000,W,123456.78,A,A*75\r\n");
+        assert_eq!(gga("GN", 35.5, -0.5, 100.0, time()),
+                   "$GNGGA,123456.78,3530.0000,N,00030.0000,W,1,08,1.0,100.0,M,0.0,M,,*56\r\n");
+        // Track wrapped into [0, 360)
+        assert_eq!(rmc("GP", 35.5, -0.5, 10.0, -90.0, time()),
+                   "$GPRMC,123456.78,A,3530.0000,N,00030.0000,W,10.0,270.0,140321,,,A*73\r\n");
+    }
+
+    #[test]
+    fn hemispheres() {
+        let fields = |lat, long| -> Vec<String> {
+            gll("GP", lat, long, time()).split(',').map(String::from).collect()
+        };
+        assert_eq!(&fields(1.0, 1.0)[2 .. 5], ["N", "00100.0000", "E"]);
+        assert_eq!(&fields(-1.0, -1.0)[2 .. 5], ["S", "00100.0000", "W"]);
+    }
+
+    #[test]
+    fn degrees_and_minutes_are_zero_padded() {
+        assert_eq!(degrees_minutes(5.25, 2), "0515.0000");
+        assert_eq!(degrees_minutes(-7.5, 3), "00730.0000");
+        assert_eq!(degrees_minutes(0.0, 3), "00000.0000");
+        assert_eq!(degrees_minutes(179.0 + 1.0 / 60.0, 3), "17901.0000");
+        // Minutes that would round to 60 carry into the degrees
+        assert_eq!(degrees_minutes(35.999_999_9, 2), "3600.0000");
+    }
+
+    #[test]
+    fn minutes_are_rounded_to_4dp() {
+        // 0.3456789 degrees is 20.740734 minutes
+        assert_eq!(degrees_minutes(12.345_678_9, 2), "1220.7407");
+        assert_eq!(degrees_minutes(-12.345_678_9, 2), "1220.7407");
+        // 0.5 minutes short of a whole degree stays there
+        assert_eq!(degrees_minutes(12.0 - 0.5 / 60.0, 2), "1159.5000");
+    }
+
+    #[test]
+    fn largest_angles() {
+        assert_eq!(degrees_minutes(90.0, 2), "9000.0000");
+        assert_eq!(degrees_minutes(-180.0, 3), "18000.0000");
+        assert_eq!(degrees_minutes(179.999_999_9, 3), "18000.0000");
+    }
+
+    #[test]
+    fn time_and_date() {
+        assert_eq!(utc_time(Duration::from_millis(86_399_999)), "235959.99");
+        assert_eq!(utc_date(Duration::from_secs(0)), "010170");
+        // 2000 was a leap year
+        assert_eq!(civil_from_days(11_016), (2000, 2, 29));
+    }
+
+    #[test]
+    fn time_of_fix_is_truncated_not_rounded() {
+        assert_eq!(utc_time(Duration::from_millis(59_999)), "000059.99");
+        // Next day, same time
+        assert_eq!(utc_time(Duration::from_millis(86_400_000 + 3_723_450)), "010203.45");
+    }
+
+    #[test]
+    fn sentences_carry_the_frame_time() {
+        let mut bfd = BufferedFlightData::new();
+        bfd.utc = UNIX_EPOCH + time();
+        let buf = build(&[NmeaSentence::Gll, NmeaSentence::Gga, NmeaSentence::Rmc], "GP", &bfd);
+        let text = String::from_utf8_lossy(&buf);
+        let text = text.trim_end_matches('\0');
+
+        let sentences: Vec<&str> = text.split_terminator("\r\n").collect();
+        assert_eq!(sentences.len(), 3);
+        for sentence in sentences {
+            assert!(sentence.contains(",123456.78,"), "{}", sentence);
+        }
+        assert!(text.contains(",140321,"));
+    }
+
+    #[test]
+    fn time_before_1970_is_midnight() {
+        let mut bfd = BufferedFlightData::new();
+        bfd.utc = UNIX_EPOCH - Duration::from_secs(10);
+        let buf = build(&[NmeaSentence::Rmc], "GP", &bfd);
+        let text = String::from_utf8_lossy(&buf);
+        assert!(text.contains(",000000.00,"), "{}", text);
+        assert!(text.contains(",010170,"), "{}", text);
+    }
+
+    #[test]
+    fn longest_sentences_fit_the_buffer() {
+        let (lat, long) = (-89.999, -179.999);
+        let sentences = [gll("GP", lat, long, time()),
+                         gga("GP", lat, long, 99_999.9, time()),
+                         rmc("GP", lat, long, 999.9, 359.9, time())];
+        for sentence in sentences.iter() {
+            assert!(sentence.len() <= NMEA_MAX_LEN, "{:?}", sentence);
+        }
+
+        let buf = to_buffer(&sentences);
+        let total: usize = sentences.iter().map(String::len).sum();
+        assert!(total <= NMEA_BUFFER_SIZE);
+        assert_eq!(&buf[.. total], sentences.concat().as_bytes());
+        assert!(buf[total ..].iter().all(|&byte| byte == 0));
+    }
+
+    #[test]
+    fn overlong_sentences_are_truncated() {
+        let long = "x".repeat(100);
+        let buf = to_buffer(&[long.clone(), long.clone(), long.clone(), long]);
+        assert!(buf.iter().all(|&byte| byte == b'x'));
+    }
+
+    #[test]
+    fn overlong_sentence_is_cut_at_the_nmea_maximum() {
+        let long = format!("${}\r\n", "A".repeat(98));
+        let next = "$GPGLL*00\r\n".to_string();
+        let buf = to_buffer(&[long.clone(), next.clone()]);
+
+        // The first 82 bytes of the long one, then all of the next
+        assert_eq!(&buf[.. NMEA_MAX_LEN], &long.as_bytes()[.. NMEA_MAX_LEN]);
+        assert_eq!(&buf[NMEA_MAX_LEN .. NMEA_MAX_LEN + next.len()], next.as_bytes());
+        assert!(buf[NMEA_MAX_LEN + next.len() ..].iter().all(|&byte| byte == 0));
+    }
+
+    #[test]
+    fn talkers() {
+        assert!(is_valid_talker(DEFAULT_TALKER));
+        assert!(is_valid_talker("GN"));
+        assert!(!is_valid_talker("gp"));
+        assert!(!is_valid_talker("GPS"));
+        assert!(!is_valid_talker(""));
+    }
+
+    #[test]
+    fn every_sentence_comes_from_the_talker() {
+        let mut bfd = BufferedFlightData::new();
+        bfd.utc = UNIX_EPOCH + time();
+        let buf = build(&[NmeaSentence::Gll, NmeaSentence::Gga, NmeaSentence::Rmc], "GN", &bfd);
+        let text = String::from_utf8_lossy(&buf);
+
+        let sentences: Vec<&str> = text.trim_end_matches('\0').split_terminator("\r\n").collect();
+        assert_eq!(sentences.len(), 3);
+        for sentence in sentences {
+            assert!(sentence.starts_with("$GN"), "{}", sentence);
+            // The talker's covered by the checksum like everything else
+            let body = &sentence[.. sentence.len() - 3];
+            assert_eq!(finish(body.to_string()), format!("{}\r\n", sentence));
+        }
+    }
+
+    #[test]
+    fn talker_defaults_to_gps() {
+        assert_eq!(DEFAULT_TALKER, "GP");
+        let buf = build(&[NmeaSentence::Gll], DEFAULT_TALKER, &BufferedFlightData::new());
+        assert!(buf.starts_with(b"$GPGLL,"));
+    }
+}
@@ -1,4 +1,5 @@
-use std::ops::Mul;
+use std::f32::consts::PI;
+use std::ops::{Add, Mul};
 
 #[derive(Copy, Clone, Debug)]
 pub struct Quaternion {
@@ -12,6 +13,94 @@ impl Quaternion {
         }
     }
 
+    // Unit quaternion from aerospace Euler angles in radians; the inverse of `to_euler`.
+    pub fn from_euler(roll: f32, pitch: f32, yaw: f32) -> Self {
+        let (sr, cr) = (roll / 2f32).sin_cos();
+        let (sp, cp) = (pitch / 2f32).sin_cos();
+        let (sy, cy) = (yaw / 2f32).sin_cos();
+
+        Quaternion {
+            inner: [
+                cr*cp*cy + sr*sp*sy,
+                sr*cp*cy - cr*sp*sy,
+                cr*sp*cy + sr*cp*sy,
+                cr*cp*sy - sr*sp*cy
+            ],
+        }
+    }
+
+    // Scales to unit length. Rotating by a non-unit quaternion also scales
+    // the vector, so the sim's quaternion should go through this first.
+    // A zero quaternion has no sensible direction, so we give back the identity.
+    pub fn normalize(self) -> Self {
+        let norm = self.inner.iter().map(|x| x * x).sum::<f32>().sqrt();
+        if norm == 0f32 || !norm.is_finite() {
+            return Quaternion::new([1f32, 0f32, 0f32, 0f32]);
+        }
+
+        Quaternion {
+            inner: [self.inner[0] / norm, self.inner[1] / norm, self.inner[2] / norm, self.inner[3] / norm],
+        }
+    }
+
+    // (roll, pitch, yaw) in radians, using the usual aerospace ZYX (yaw, then pitch,
+    // then roll) sequence. Handy for cross-checking against true_phi/true_theta/mag_psi.
+    // At ±90° pitch roll and yaw become indistinguishable (gimbal lock), so we clamp
+    // pitch there rather than letting asin() return NaN.
+    #[allow(dead_code)] // only used when debugging
+    pub fn to_euler(self) -> (f32, f32, f32) {
+        let w = self.inner[0];
+        let x = self.inner[1];
+        let y = self.inner[2];
+        let z = self.inner[3];
+
+        let roll = (2f32 * (w*x + y*z)).atan2(1f32 - 2f32 * (x*x + y*y));
+
+        let sin_pitch = 2f32 * (w*y - z*x);
+        let pitch = if sin_pitch.abs() >= 1f32 {
+            (PI / 2f32).copysign(sin_pitch)
+        } else {
+            sin_pitch.asin()
+        };
+
+        let yaw = (2f32 * (w*z + x*y)).atan2(1f32 - 2f32 * (y*y + z*z));
+
+        (roll, pitch, yaw)
+    }
+
+    // Treating both as 4-vectors. For unit quaternions this is the cosine of
+    // half the angle between the rotations they represent.
+    pub fn dot(self, rhs: Self) -> f32 {
+        self.inner.iter().zip(rhs.inner.iter()).map(|(a, b)| a * b).sum()
+    }
+
+    pub fn as_array(self) -> [f32; 4] {
+        self.inner
+    }
+
+    // Spherical linear interpolation from `a` (t = 0) to `b` (t = 1) at a
+    // constant angular rate. Both should be unit quaternions.
+    pub fn slerp(a: Self, b: Self, t: f32) -> Self {
+        // q and -q are the same rotation, so flip `b` if that makes the way
+        // from `a` shorter; otherwise we'd go the long way round the sphere
+        let mut cos_theta = a.dot(b);
+        let b = if cos_theta < 0f32 {
+            cos_theta = - cos_theta;
+            b * -1f32
+        } else {
+            b
+        };
+
+        // Close enough that sin(theta) is nearly 0; a straight line is just as good
+        if cos_theta > 0.9995f32 {
+            return (a * (1f32 - t) + b * t).normalize();
+        }
+
+        let theta = cos_theta.acos();
+        let sin_theta = theta.sin();
+        a * (((1f32 - t) * theta).sin() / sin_theta) + b * ((t * theta).sin() / sin_theta)
+    }
+
     pub fn conj(self) -> Self {
         Quaternion {
             inner: [self.inner[0], - self.inner[1], - self.inner[2], - self.inner[3]],
@@ -47,4 +136,158 @@ impl Mul for Quaternion {
             ],
         }
     }
-}
\ No newline at end of file
+}
+
+// Scaling and adding quaternions componentwise doesn't keep them unit length,
+// so blends built from these (e.g. `q * 0.5 + r * 0.5`) want normalizing after.
+impl Mul<f32> for Quaternion {
+    type Output = Self;
+
+    fn mul(self, rhs: f32) -> Self::Output {
+        Quaternion {
+            inner: [self.inner[0] * rhs, self.inner[1] * rhs, self.inner[2] * rhs, self.inner[3] * rhs],
+        }
+    }
+}
+
+impl Add for Quaternion {
+    type Output = Self;
+
+    fn add(self, rhs: Self) -> Self::Output {
+        Quaternion {
+            inner: [
+                self.inner[0] + rhs.inner[0],
+                self.inner[1] + rhs.inner[1],
+                self.inner[2] + rhs.inner[2],
+                self.inner[3] + rhs.inner[3]
+            ],
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(got: Quaternion, expected: Quaternion) {
+        for (g, e) in got.inner.iter().zip(expected.inner.iter()) {
+            assert!((g - e).abs() < 1e-5, "expected {:?}, got {:?}", expected, got);
+        }
+    }
+
+    fn length(q: Quaternion) -> f32 {
+        q.inner.iter().map(|x| x * x).sum::<f32>().sqrt()
+    }
+
+    #[test]
+    fn normalize_scales_to_unit_length() {
+        let q = Quaternion::new([2f32, 0f32, 0f32, 2f32]).normalize();
+        let half = 0.5f32.sqrt();
+        assert_close(q, Quaternion::new([half, 0f32, 0f32, half]));
+        assert!((length(q) - 1f32).abs() < 1e-6);
+    }
+
+    #[test]
+    fn normalize_gives_the_identity_for_nothing_sensible() {
+        let identity = Quaternion::new([1f32, 0f32, 0f32, 0f32]);
+        assert_close(Quaternion::new([0f32; 4]).normalize(), identity);
+        assert_close(Quaternion::new([::std::f32::NAN, 0f32, 0f32, 0f32]).normalize(), identity);
+        assert_close(Quaternion::new([::std::f32::INFINITY, 1f32, 0f32, 0f32]).normalize(), identity);
+    }
+
+    #[test]
+    fn normalized_rotation_keeps_length() {
+        // 30 degrees of yaw, slightly off unit length as the sim's tends to be
+        let (sin, cos) = 15f32.to_radians().sin_cos();
+        let q = Quaternion::new([1.01f32 * cos, 0f32, 0f32, 1.01f32 * sin]).normalize();
+        let v = q.rotate([3f32, 4f32, 0f32]);
+        let len = (v[0] * v[0] + v[1] * v[1] + v[2] * v[2]).sqrt();
+        assert!((len - 5f32).abs() < 1e-4, "{:?}", v);
+    }
+
+    // `degrees` about the x (1), y (2) or z (3) axis
+    fn about(axis: usize, degrees: f32) -> Quaternion {
+        let (sin, cos) = (degrees.to_radians() / 2f32).sin_cos();
+        let mut inner = [cos, 0f32, 0f32, 0f32];
+        inner[axis] = sin;
+        Quaternion::new(inner)
+    }
+
+    // Yaw, then pitch, then roll
+    fn from_euler(roll: f32, pitch: f32, yaw: f32) -> Quaternion {
+        about(3, yaw) * about(2, pitch) * about(1, roll)
+    }
+
+    #[test]
+    fn euler_round_trip() {
+        for &(roll, pitch, yaw) in &[(0f32, 0f32, 0f32), (10f32, -20f32, 30f32),
+                                     (-170f32, 45f32, 135f32), (5f32, 80f32, -90f32)] {
+            let q = from_euler(roll, pitch, yaw);
+            let (r, p, y) = q.to_euler();
+            for &(got, expected) in &[(r, roll), (p, pitch), (y, yaw)] {
+                assert!((got.to_degrees() - expected).abs() < 1e-3,
+                        "({}, {}, {}) came back as ({}, {}, {})", roll, pitch, yaw,
+                        r.to_degrees(), p.to_degrees(), y.to_degrees());
+            }
+        }
+    }
+
+    #[test]
+    fn pitch_is_clamped_at_gimbal_lock() {
+        // Rounding can put sin(pitch) either side of 1 here; just below it,
+        // asin is steep enough that f32 only gets within a few hundredths
+        for &pitch in &[90f32, -90f32] {
+            let (roll, got, yaw) = from_euler(0f32, pitch, 0f32).to_euler();
+            assert!(!roll.is_nan() && !got.is_nan() && !yaw.is_nan());
+            assert!((got.to_degrees() - pitch).abs() < 0.05, "{}", got.to_degrees());
+        }
+        let (_, pitch, _) = Quaternion::new([0.5f32.sqrt(), 0f32, 0.5f32.sqrt() * 1.001f32, 0f32])
+            .to_euler();
+        assert_eq!(pitch, PI / 2f32);
+    }
+
+    #[test]
+    fn componentwise_scaling_adding_and_dot() {
+        let q = Quaternion::new([1f32, 2f32, 3f32, 4f32]);
+        let r = Quaternion::new([0.5f32, -1f32, 0f32, 2f32]);
+        assert_close(q * 2f32, Quaternion::new([2f32, 4f32, 6f32, 8f32]));
+        assert_close(q + r, Quaternion::new([1.5f32, 1f32, 3f32, 6f32]));
+        assert_eq!(q.dot(r), 0.5f32 - 2f32 + 0f32 + 8f32);
+        assert!((q.dot(q).sqrt() - length(q)).abs() < 1e-6);
+    }
+
+    fn yaw(degrees: f32) -> Quaternion {
+        Quaternion::from_euler(0f32, 0f32, degrees.to_radians())
+    }
+
+    #[test]
+    fn slerp_ends() {
+        let (a, b) = (yaw(0f32), yaw(90f32));
+        assert_close(Quaternion::slerp(a, b, 0f32), a);
+        assert_close(Quaternion::slerp(a, b, 1f32), b);
+    }
+
+    #[test]
+    fn slerp_halfway() {
+        assert_close(Quaternion::slerp(yaw(0f32), yaw(90f32), 0.5f32), yaw(45f32));
+        assert_close(Quaternion::slerp(yaw(10f32), yaw(130f32), 0.25f32), yaw(40f32));
+    }
+
+    #[test]
+    fn slerp_takes_the_short_way() {
+        // -b is the same rotation as b, but a straight blend towards it would
+        // swing through 270 degrees instead of 90
+        let (a, b) = (yaw(0f32), yaw(90f32));
+        let halfway = Quaternion::slerp(a, b * -1f32, 0.5f32);
+        assert_close(halfway, yaw(45f32));
+        assert!(halfway.dot(a) > 0f32);
+    }
+
+    #[test]
+    fn slerp_between_nearly_equal_rotations() {
+        let (a, b) = (yaw(0f32), yaw(0.01f32));
+        let halfway = Quaternion::slerp(a, b, 0.5f32);
+        assert_close(halfway, yaw(0.005f32));
+        assert!((halfway.dot(halfway) - 1f32).abs() < 1e-6);
+    }
+}
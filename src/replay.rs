@@ -0,0 +1,73 @@
+use std::io::Read;
+use std::path::Path;
+
+use super::BufferedFlightData;
+use super::clock;
+use super::log_file;
+use super::telemetry_log::parse_row;
+
+// Recorded telemetry to send to the controller in place of the sim's
+// (FFSIM_REPLAY), so it can be tested against the same flight over and over.
+// The file is a telemetry log as written by telemetry_log.rs, or anything else
+// with the same columns: a header row naming them, in any order, then one row
+// per frame.
+//
+// One row is sent per frame at the usual rate, going back to the first after
+// the last. Only the latency timestamp is fresh; everything else, including
+// the UTC time in the GPS sentences, is as recorded.
+pub struct Replay {
+    frames: Vec<BufferedFlightData>,
+    next: usize,
+}
+
+impl Replay {
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let mut contents = String::new();
+        log_file::open(path)
+            .and_then(|mut file| file.read_to_string(&mut contents))
+            .map_err(|e| format!("couldn't read {}: {}", path.display(), e))?;
+
+        Self::parse(&contents).map_err(|e| format!("{}: {}", path.display(), e))
+    }
+
+    pub fn parse(contents: &str) -> Result<Self, String> {
+        let mut lines = contents.lines();
+        let header = lines.next().ok_or("empty file".to_string())?;
+        let columns: Vec<&str> = header.trim_end().split(',').map(|column| column.trim()).collect();
+
+        let mut frames = Vec::new();
+        for (num, line) in lines.enumerate() {
+            if line.trim().is_empty() {
+                continue;
+            }
+            // (+2: lines count from 1, and the header was the first)
+            frames.push(parse_row(&columns, line).map_err(|e| format!("line {}: {}", num + 2, e))?);
+        }
+
+        if frames.is_empty() {
+            return Err("no frames to replay".to_string());
+        }
+
+        Ok(Replay {
+            frames,
+            next: 0,
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.frames.len()
+    }
+
+    pub fn next_frame(&mut self) -> BufferedFlightData {
+        if self.next == self.frames.len() {
+            log_info!("Reached the end of the replay, starting again");
+            self.next = 0;
+        }
+
+        let mut frame = self.frames[self.next];
+        self.next += 1;
+
+        frame.time = clock::now();
+        frame
+    }
+}
@@ -0,0 +1,305 @@
+use std::fs;
+use std::io;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender, TrySendError};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use super::BufferedFlightData;
+use super::log_file::{LogWriter, log_path};
+
+// Frames waiting to be written. If the disk can't keep up we drop frames
+// rather than hold up the send thread.
+const QUEUE_LEN: usize = 256;
+
+// Records each frame of telemetry we send to the controller as a row of CSV,
+// for post-flight analysis (FFSIM_TELEMETRY_LOG). The writing happens on a
+// thread of its own, so a slow disk can't hold up the link.
+//
+// Once a file reaches the size cap it's renamed to `<name>.old.csv`, replacing
+// any older one, and a fresh file is started; so a session never takes up more
+// than twice the cap. If gzipped (".csv.gz") the cap is on the uncompressed
+// size, so the files end up a good deal smaller than it.
+pub struct TelemetryLog {
+    queue: Option<SyncSender<BufferedFlightData>>,
+    writer: Option<thread::JoinHandle<()>>,
+    dropped: u64,
+}
+
+impl TelemetryLog {
+    pub fn start(dir: &Path, max_bytes: u64, gzip: bool) -> Self {
+        let run_id = SystemTime::now().duration_since(UNIX_EPOCH)
+            .map(|dur| dur.as_secs()).unwrap_or(0);
+        let path = dir.join(format!("telemetry-{}.csv", run_id));
+        log_info!("Logging telemetry to {}", log_path(&path, gzip).display());
+
+        let (queue, frames) = sync_channel(QUEUE_LEN);
+        let writer = thread::spawn(move || {
+            if let Err(e) = write_frames(&path, max_bytes, gzip, frames) {
+                log_error!("Couldn't write telemetry to {}, giving up on it: {:?}",
+                           log_path(&path, gzip).display(), e);
+            }
+        });
+
+        TelemetryLog {
+            queue: Some(queue),
+            writer: Some(writer),
+            dropped: 0,
+        }
+    }
+
+    // Never blocks; if the writer is behind, the frame is dropped
+    pub fn record(&mut self, frame: &BufferedFlightData) {
+        let queue = match self.queue {
+            Some(ref queue) => queue,
+            None => return,
+        };
+
+        match queue.try_send(*frame) {
+            Ok(_) => (),
+            Err(TrySendError::Full(_)) => {
+                if self.dropped == 0 {
+                    log_warn!("Telemetry log can't keep up, dropping frames");
+                }
+                self.dropped += 1;
+            }
+            // The writer has already given up (and said why)
+            Err(TrySendError::Disconnected(_)) => self.queue = None,
+        }
+    }
+}
+
+impl Drop for TelemetryLog {
+    // Lets the writer finish off what's queued and close the file
+    fn drop(&mut self) {
+        self.queue = None;
+        if let Some(writer) = self.writer.take() {
+            let _ = writer.join();
+        }
+        if self.dropped > 0 {
+            log_warn!("Telemetry log dropped {} frames", self.dropped);
+        }
+    }
+}
+
+// Runs until the `TelemetryLog` is dropped, or something goes wrong
+// `path` is without any ".gz"
+fn write_frames(path: &Path, max_bytes: u64, gzip: bool,
+                frames: Receiver<BufferedFlightData>) -> io::Result<()> {
+    let mut out = create(path, gzip)?;
+    let mut written: u64 = 0;
+
+    for frame in frames {
+        if written >= max_bytes {
+            out.finish()?;
+            fs::rename(log_path(path, gzip), log_path(&old_path(path), gzip))?;
+            out = create(path, gzip)?;
+            written = 0;
+        }
+
+        let row = row(&frame);
+        out.write_all(row.as_bytes())?;
+        written += row.len() as u64;
+    }
+
+    out.finish()
+}
+
+fn create(path: &Path, gzip: bool) -> io::Result<LogWriter> {
+    let mut out = LogWriter::create(path, gzip)?;
+    out.write_all(header().as_bytes())?;
+    Ok(out)
+}
+
+// telemetry-123.csv -> telemetry-123.old.csv
+fn old_path(path: &Path) -> PathBuf {
+    let stem = path.file_stem().map(|stem| stem.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    path.with_file_name(format!("{}.old.csv", stem))
+}
+
+// Generates `header`, `row` and `parse_row` from one list of fields, so the
+// columns can't get out of step with each other. The columns are named after
+// the fields of `BufferedFlightData`; `utc` (in seconds since 1970) comes
+// first and the quaternion, as four columns, last. Fields after the `;` are
+// Options, which are left empty when None.
+macro_rules! csv_columns {
+    ($($field:ident),*; $($optional:ident),*) => {
+        fn header() -> String {
+            let mut header = String::from("utc");
+            $(
+                header.push(',');
+                header.push_str(stringify!($field));
+            )*
+            $(
+                header.push(',');
+                header.push_str(stringify!($optional));
+            )*
+            for i in 0 .. 4 {
+                header.push_str(&format!(",plane_orientation_quaternion[{}]", i));
+            }
+            header.push('\n');
+            header
+        }
+
+        fn row(frame: &BufferedFlightData) -> String {
+            let utc = frame.utc.duration_since(UNIX_EPOCH)
+                .map(|dur| dur.as_secs() as f64 + dur.subsec_nanos() as f64 / 1e9)
+                .unwrap_or(0.0);
+
+            let mut row = format!("{:.6}", utc);
+            $(
+                row.push_str(&format!(",{}", frame.$field));
+            )*
+            $(
+                row.push(',');
+                if let Some(val) = frame.$optional {
+                    row.push_str(&val.to_string());
+                }
+            )*
+            for val in frame.plane_orientation_quaternion.iter() {
+                row.push_str(&format!(",{}", val));
+            }
+            row.push('\n');
+            row
+        }
+
+        // The inverse of `row`, for replaying a log (see replay.rs). `columns`
+        // is the log's header, split up; they needn't be in our order, but
+        // every one of ours has to be there.
+        pub fn parse_row(columns: &[&str], row: &str) -> Result<BufferedFlightData, String> {
+            let values: Vec<&str> = row.trim_end().split(',').collect();
+            if values.len() != columns.len() {
+                return Err(format!("expected {} columns, got {}", columns.len(), values.len()));
+            }
+            let value = |name: &str| -> Result<&str, String> {
+                columns.iter().position(|column| *column == name)
+                    .map(|i| values[i].trim())
+                    .ok_or(format!("no {} column", name))
+            };
+            fn parse<T: ::std::str::FromStr>(name: &str, raw: &str) -> Result<T, String> {
+                raw.parse::<T>().map_err(|_| format!("bad {} {:?}", name, raw))
+            }
+
+            let mut frame = BufferedFlightData::new();
+
+            let utc: f64 = parse("utc", value("utc")?)?;
+            if !(utc >= 0.0) {
+                return Err(format!("bad utc {:?}", utc));
+            }
+            frame.utc = UNIX_EPOCH + Duration::new(utc.trunc() as u64, (utc.fract() * 1e9) as u32);
+            $(
+                frame.$field = parse(stringify!($field), value(stringify!($field))?)?;
+            )*
+            $(
+                let raw = value(stringify!($optional))?;
+                frame.$optional = if raw.is_empty() {
+                    None
+                } else {
+                    Some(parse(stringify!($optional), raw)?)
+                };
+            )*
+            for i in 0 .. 4 {
+                let name = format!("plane_orientation_quaternion[{}]", i);
+                frame.plane_orientation_quaternion[i] = parse(&name, value(&name)?)?;
+            }
+
+            Ok(frame)
+        }
+    }
+}
+
+csv_columns!(roll_rate, pitch_rate, yaw_rate,
+             true_theta, true_phi, mag_psi,
+             local_ax, local_ay, local_az,
+             latitude, longitude, elevation, vertical_speed, groundspeed, track,
+             wind_speed, wind_direction,
+             indicated_airspeed, true_airspeed, alpha, beta, barometer_inhg,
+             ambient_temp, air_density, local_air_density, time;
+             actual_rudder, actual_left_aileron, actual_right_aileron,
+             actual_left_elevator, actual_right_elevator);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame() -> BufferedFlightData {
+        let mut frame = BufferedFlightData::new();
+        frame.utc = UNIX_EPOCH + Duration::new(1_600_000_000, 123_456_000);
+        frame.roll_rate = -1.25;
+        frame.latitude = -35.123_456_789;
+        frame.longitude = 149.1;
+        frame.barometer_inhg = 29.92;
+        frame.time = 42;
+        frame.actual_rudder = Some(0.5);
+        frame.actual_left_elevator = None;
+        frame.plane_orientation_quaternion = [0.5, -0.5, 0.5, -0.5];
+        frame
+    }
+
+    fn columns(header: &str) -> Vec<&str> {
+        header.trim_end().split(',').collect()
+    }
+
+    #[test]
+    fn rows_round_trip() {
+        let header = header();
+        let row = row(&frame());
+        let parsed = parse_row(&columns(&header), &row).unwrap();
+
+        // (to the microsecond, which is all the column holds)
+        let utc_error = match parsed.utc.duration_since(frame().utc) {
+            Ok(later) => later,
+            Err(earlier) => earlier.duration(),
+        };
+        assert!(utc_error < Duration::from_micros(1), "{:?}", utc_error);
+        assert_eq!(parsed.roll_rate, -1.25);
+        assert_eq!(parsed.latitude, -35.123_456_789);
+        assert_eq!(parsed.time, 42);
+        assert_eq!(parsed.actual_rudder, Some(0.5));
+        assert_eq!(parsed.actual_left_elevator, None);
+        assert_eq!(parsed.plane_orientation_quaternion, [0.5, -0.5, 0.5, -0.5]);
+        // and so every column comes back as it was
+        assert_eq!(super::row(&parsed), row);
+    }
+
+    #[test]
+    fn one_value_per_column() {
+        assert_eq!(columns(&header()).len(), row(&frame()).trim_end().split(',').count());
+    }
+
+    #[test]
+    fn columns_can_come_in_any_order() {
+        let header = header();
+        let row = row(&frame());
+        let mut pairs: Vec<(&str, &str)> = columns(&header).into_iter()
+            .zip(row.trim_end().split(','))
+            .collect();
+        pairs.reverse();
+
+        let columns: Vec<&str> = pairs.iter().map(|pair| pair.0).collect();
+        let values: Vec<&str> = pairs.iter().map(|pair| pair.1).collect();
+        let parsed = parse_row(&columns, &values.join(",")).unwrap();
+        assert_eq!(super::row(&parsed), row);
+    }
+
+    #[test]
+    fn bad_rows_are_rejected() {
+        let header = header();
+        let columns = columns(&header);
+        let row = row(&frame());
+
+        assert!(parse_row(&columns, "1,2,3").unwrap_err().starts_with("expected"));
+        assert!(parse_row(&columns[1 ..], &row[row.find(',').unwrap() + 1 ..])
+                .unwrap_err().contains("no utc column"));
+        let garbled = row.replacen("-1.25", "x", 1);
+        assert!(parse_row(&columns, &garbled).unwrap_err().contains("bad roll_rate"));
+    }
+
+    #[test]
+    fn old_file_name() {
+        assert_eq!(old_path(Path::new("/logs/telemetry-123.csv")),
+                   Path::new("/logs/telemetry-123.old.csv"));
+    }
+}
@@ -0,0 +1,256 @@
+// Helpers for (de)serializing the structs we send over the serial link.
+//
+// Multi-byte fields are little endian unless FFSIM_WIRE_ENDIAN says otherwise.
+// That's what the FPGA was built against back when we just transmuted the
+// structs on x86, so it's the default; big endian is for controllers built
+// the other way round.
+
+use std::fmt;
+use std::str::FromStr;
+
+// Every frame, in either direction, starts with this so the receiver can find
+// frame boundaries in the byte stream.
+pub const SYNC: [u8; 4] = *b"SYNC";
+
+// Sent at the start of every FlightData frame, so the FPGA can refuse to talk
+// to a plugin that doesn't speak the same protocol. Bump it whenever the layout
+// or meaning of either frame changes.
+pub const PROTOCOL_VERSION: u8 = 3;
+
+// Gap between the sequence number we got and the one we expected, i.e. how
+// many frames went missing in between. Counting wraps around, so a sequence
+// number "before" the expected one shows up as a gap of more than half the
+// range; that means the other end restarted, not that we lost 60000 frames.
+pub fn sequence_gap(expected: u16, got: u16) -> Option<u16> {
+    match got.wrapping_sub(expected) {
+        gap if gap < 0x8000 => Some(gap),
+        _ => None,
+    }
+}
+
+// Byte order of the multi-byte fields in both frames. Byte arrays (SYNC, the
+// NMEA buffer, timestamps) are the same either way.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum WireEndian {
+    Little,
+    Big,
+}
+
+impl Default for WireEndian {
+    fn default() -> Self {
+        WireEndian::Little
+    }
+}
+
+impl fmt::Display for WireEndian {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match *self {
+            WireEndian::Little => "little",
+            WireEndian::Big => "big",
+        };
+        f.write_str(name)
+    }
+}
+
+impl FromStr for WireEndian {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, ()> {
+        match s.to_lowercase().as_str() {
+            "little" | "le" => Ok(WireEndian::Little),
+            "big" | "be" => Ok(WireEndian::Big),
+            _ => Err(()),
+        }
+    }
+}
+
+pub const TIMESTAMP_SIZE: usize = 8;
+
+// Timestamps are a u64 count of nanoseconds from `clock::now`, with 0 meaning
+// there isn't one. Only we interpret them; the controller echoes them back, so
+// they're always little endian whatever the rest of the frame is.
+pub fn encode_timestamp(nanos: u64) -> [u8; TIMESTAMP_SIZE] {
+    nanos.to_le_bytes()
+}
+
+pub fn decode_timestamp(buf: &[u8; TIMESTAMP_SIZE]) -> u64 {
+    u64::from_le_bytes(*buf)
+}
+
+pub struct WireWriter<'a> {
+    buf: &'a mut [u8],
+    pos: usize,
+    endian: WireEndian,
+}
+
+impl<'a> WireWriter<'a> {
+    pub fn new(buf: &'a mut [u8], endian: WireEndian) -> Self {
+        WireWriter {
+            buf,
+            pos: 0,
+            endian,
+        }
+    }
+
+    // Number of bytes written so far
+    pub fn position(&self) -> usize {
+        self.pos
+    }
+
+    pub fn bytes(&mut self, bytes: &[u8]) {
+        self.buf[self.pos .. self.pos + bytes.len()].copy_from_slice(bytes);
+        self.pos += bytes.len();
+    }
+
+    pub fn u16(&mut self, val: u16) {
+        match self.endian {
+            WireEndian::Little => self.bytes(&val.to_le_bytes()),
+            WireEndian::Big => self.bytes(&val.to_be_bytes()),
+        }
+    }
+
+    pub fn i16(&mut self, val: i16) {
+        match self.endian {
+            WireEndian::Little => self.bytes(&val.to_le_bytes()),
+            WireEndian::Big => self.bytes(&val.to_be_bytes()),
+        }
+    }
+
+    pub fn u32(&mut self, val: u32) {
+        match self.endian {
+            WireEndian::Little => self.bytes(&val.to_le_bytes()),
+            WireEndian::Big => self.bytes(&val.to_be_bytes()),
+        }
+    }
+
+    pub fn i32(&mut self, val: i32) {
+        match self.endian {
+            WireEndian::Little => self.bytes(&val.to_le_bytes()),
+            WireEndian::Big => self.bytes(&val.to_be_bytes()),
+        }
+    }
+}
+
+pub struct WireReader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+    endian: WireEndian,
+}
+
+impl<'a> WireReader<'a> {
+    pub fn new(buf: &'a [u8], endian: WireEndian) -> Self {
+        WireReader {
+            buf,
+            pos: 0,
+            endian,
+        }
+    }
+
+    // Number of bytes read so far
+    pub fn position(&self) -> usize {
+        self.pos
+    }
+
+    // Fills `dest` with the next `dest.len()` bytes
+    pub fn bytes(&mut self, dest: &mut [u8]) {
+        dest.copy_from_slice(&self.buf[self.pos .. self.pos + dest.len()]);
+        self.pos += dest.len();
+    }
+
+    pub fn u16(&mut self) -> u16 {
+        let mut raw = [0u8; 2];
+        self.bytes(&mut raw);
+        match self.endian {
+            WireEndian::Little => u16::from_le_bytes(raw),
+            WireEndian::Big => u16::from_be_bytes(raw),
+        }
+    }
+
+    pub fn u32(&mut self) -> u32 {
+        let mut raw = [0u8; 4];
+        self.bytes(&mut raw);
+        match self.endian {
+            WireEndian::Little => u32::from_le_bytes(raw),
+            WireEndian::Big => u32::from_be_bytes(raw),
+        }
+    }
+}
+
+// Formats bytes as space separated hex pairs, e.g. "53 59 4e 43", for logging
+pub fn hex_dump(bytes: &[u8]) -> String {
+    let hex: Vec<String> = bytes.iter().map(|byte| format!("{:02x}", byte)).collect();
+    hex.join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn writer_and_reader_round_trip() {
+        for &endian in &[WireEndian::Little, WireEndian::Big] {
+            let mut buf = [0u8; 10];
+            {
+                let mut out = WireWriter::new(&mut buf, endian);
+                out.bytes(b"AB");
+                out.u16(0xBEEF);
+                out.i16(-2);
+                out.u32(0xDEAD_BEEF);
+                assert_eq!(out.position(), 10);
+            }
+
+            let mut input = WireReader::new(&buf, endian);
+            let mut ab = [0u8; 2];
+            input.bytes(&mut ab);
+            assert_eq!(&ab, b"AB");
+            assert_eq!(input.u16(), 0xBEEF);
+            assert_eq!(input.u16(), 0xFFFE);
+            assert_eq!(input.u32(), 0xDEAD_BEEF);
+            assert_eq!(input.position(), 10);
+        }
+    }
+
+    #[test]
+    fn byte_order() {
+        let mut little = [0u8; 10];
+        {
+            let mut out = WireWriter::new(&mut little, WireEndian::Little);
+            out.u16(0x0102);
+            out.u32(0x0304_0506);
+            out.i32(-0x0708_090a);
+        }
+        assert_eq!(little, [0x02, 0x01, 0x06, 0x05, 0x04, 0x03, 0xf6, 0xf6, 0xf7, 0xf8]);
+
+        let mut big = [0u8; 10];
+        {
+            let mut out = WireWriter::new(&mut big, WireEndian::Big);
+            out.u16(0x0102);
+            out.u32(0x0304_0506);
+            out.i32(-0x0708_090a);
+        }
+        assert_eq!(big, [0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0xf8, 0xf7, 0xf6, 0xf6]);
+    }
+
+    #[test]
+    fn timestamps_are_little_endian_either_way() {
+        let stamp = encode_timestamp(0x0102_0304_0506_0708);
+        assert_eq!(stamp, [8, 7, 6, 5, 4, 3, 2, 1]);
+        assert_eq!(decode_timestamp(&stamp), 0x0102_0304_0506_0708);
+    }
+
+    #[test]
+    fn sequence_gaps() {
+        assert_eq!(sequence_gap(5, 5), Some(0));
+        assert_eq!(sequence_gap(5, 8), Some(3));
+        assert_eq!(sequence_gap(0xFFFF, 1), Some(2));
+        // Went backwards: the other end restarted
+        assert_eq!(sequence_gap(100, 3), None);
+    }
+
+    #[test]
+    fn parse_endian() {
+        assert_eq!("little".parse::<WireEndian>(), Ok(WireEndian::Little));
+        assert_eq!("be".parse::<WireEndian>(), Ok(WireEndian::Big));
+        assert!("middle".parse::<WireEndian>().is_err());
+    }
+}